@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use config::{Config, File as ConfigFile};
+use serde::Deserialize;
+
+use crate::chain_params::{self, ChainParams, Network};
+use crate::db::codec::{self, Compression};
+use crate::db::options::{DbTuning, Profile, MB};
+use crate::scheduler::{self, Schedule};
+use crate::{IndexSettings, Role, DEFAULT_DUST_RELAY_FEE};
+
+const DEFAULT_MIN_FREE_DISK_MB: u64 = 2048;
+
+// config.toml as written; everything is optional here so that all problems
+// can be reported together by validate()
+#[derive(Deserialize)]
+struct RawConfig {
+    role: Option<String>,
+    #[serde(default)]
+    chain: RawChain,
+    #[serde(default)]
+    paths: RawPaths,
+    #[serde(default)]
+    stats: RawStats,
+    #[serde(default)]
+    journal: RawJournal,
+    #[serde(default)]
+    sync: RawSync,
+    #[serde(default)]
+    diagnostics: RawDiagnostics,
+    #[serde(default)]
+    db: RawDb,
+    #[serde(default)]
+    search: RawSearch,
+    // Task name -> cron expression
+    #[serde(default)]
+    schedule: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawChain {
+    network: Option<String>,
+    // Overrides of the network's preset, e.g. for a private test network
+    magic: Option<String>,
+    default_port: Option<u16>,
+    pubkey_address_prefix: Option<u8>,
+    script_address_prefix: Option<u8>,
+    staking_address_prefix: Option<u8>,
+    genesis_hash: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawPaths {
+    db_path: Option<PathBuf>,
+    blk_dir: Option<PathBuf>,
+    ldb_dir: Option<PathBuf>,
+    // Old name for ldb_dir
+    ldb_files_dir: Option<PathBuf>,
+    labels_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawStats {
+    dust_relay_fee: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawJournal {
+    #[serde(default)]
+    enabled: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct RawSync {
+    min_free_disk_mb: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawDiagnostics {
+    #[serde(default)]
+    tag_unspendable: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct RawSearch {
+    #[serde(default)]
+    text: bool,
+}
+
+// RocksDB profile plus optional overrides of its individual knobs
+#[derive(Deserialize, Default)]
+struct RawDb {
+    profile: Option<String>,
+    write_buffer_size_mb: Option<usize>,
+    max_write_buffer_number: Option<i32>,
+    max_background_jobs: Option<i32>,
+    max_open_files: Option<i32>,
+    block_cache_mb: Option<usize>,
+    // zstd dictionary compression for the transactions column family
+    zstd_dictionary_kb: Option<usize>,
+    // Column family -> compression applied to values before they are written
+    #[serde(default)]
+    compression: BTreeMap<String, String>,
+}
+
+// Validated configuration for the whole run
+pub struct AppConfig {
+    pub chain_params: ChainParams,
+    pub db_path: PathBuf,
+    pub db_tuning: DbTuning,
+    pub value_compression: HashMap<String, Compression>,
+    pub blk_dir: PathBuf,
+    pub labels_file: Option<PathBuf>,
+    pub journal_enabled: bool,
+    pub index: IndexSettings,
+    // Maintenance tasks run by `rustyblox schedule`
+    pub schedule: Vec<(String, Schedule)>,
+}
+
+// Read and validate a config file, returning every problem found
+pub fn load(path: &str) -> Result<AppConfig, Vec<String>> {
+    let raw = Config::builder()
+        .add_source(ConfigFile::with_name(path))
+        .build()
+        .and_then(|config| config.try_deserialize::<RawConfig>())
+        .map_err(|e| vec![e.to_string()])?;
+    validate(raw)
+}
+
+fn validate(raw: RawConfig) -> Result<AppConfig, Vec<String>> {
+    let mut errors = Vec::new();
+
+    let role = raw.role.as_deref().unwrap_or("full").parse().unwrap_or_else(|e| {
+        errors.push(e);
+        Role::Full
+    });
+    let network = raw.chain.network.as_deref().unwrap_or("mainnet").parse().unwrap_or_else(|e| {
+        errors.push(e);
+        Network::Mainnet
+    });
+
+    let chain_params = chain_params(network, &raw.chain, &mut errors);
+
+    let db_path = raw.paths.db_path.unwrap_or_else(|| {
+        errors.push("paths.db_path is required".to_string());
+        PathBuf::new()
+    });
+
+    let ldb_dir = match (raw.paths.ldb_dir, raw.paths.ldb_files_dir) {
+        (Some(dir), _) => Some(dir),
+        (None, Some(dir)) => {
+            eprintln!("Warning: paths.ldb_files_dir is deprecated, rename it to paths.ldb_dir");
+            Some(dir)
+        }
+        (None, None) => None,
+    };
+
+    // blk files and the block index are only read when syncing
+    let blk_dir = raw.paths.blk_dir.unwrap_or_default();
+    if role.syncs_blocks() {
+        require_dir(&mut errors, "paths.blk_dir", &blk_dir);
+        // Without a block index, heights follow the blocks' parent links
+        if let Some(ldb_dir) = &ldb_dir {
+            require_dir(&mut errors, "paths.ldb_dir", ldb_dir);
+        }
+    }
+
+    if let Some(labels_file) = &raw.paths.labels_file {
+        if !labels_file.is_file() {
+            errors.push(format!("paths.labels_file {} does not exist", labels_file.display()));
+        }
+    }
+
+    let dust_relay_fee = raw.stats.dust_relay_fee.unwrap_or(DEFAULT_DUST_RELAY_FEE);
+    if dust_relay_fee < 0 {
+        errors.push(format!("stats.dust_relay_fee must not be negative, got {}", dust_relay_fee));
+    }
+
+    let profile = raw.db.profile.as_deref().unwrap_or("balanced").parse().unwrap_or_else(|e| {
+        errors.push(e);
+        Profile::Balanced
+    });
+    let db_tuning = db_tuning(profile, &raw.db, &mut errors);
+
+    let mut value_compression = HashMap::new();
+    for (cf, name) in &raw.db.compression {
+        if !codec::COMPRESSIBLE_CFS.contains(&cf.as_str()) {
+            errors.push(format!("db.compression.{}: only {} can be compressed", cf, codec::COMPRESSIBLE_CFS.join(" and ")));
+            continue;
+        }
+        match name.parse() {
+            Ok(compression) => {
+                value_compression.insert(cf.clone(), compression);
+            }
+            Err(e) => errors.push(format!("db.compression.{}: {}", cf, e)),
+        }
+    }
+
+    let min_free_disk_mb = raw.sync.min_free_disk_mb.unwrap_or(DEFAULT_MIN_FREE_DISK_MB);
+
+    let mut schedule = Vec::new();
+    for (task, expression) in &raw.schedule {
+        if !scheduler::TASKS.contains(&task.as_str()) {
+            errors.push(format!("Unknown schedule task '{}', expected one of {}", task, scheduler::TASKS.join(", ")));
+            continue;
+        }
+        match expression.parse() {
+            Ok(parsed) => schedule.push((task.clone(), parsed)),
+            Err(e) => errors.push(format!("schedule.{}: {}", task, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(AppConfig {
+        chain_params,
+        db_path,
+        db_tuning,
+        value_compression,
+        blk_dir,
+        labels_file: raw.paths.labels_file,
+        journal_enabled: raw.journal.enabled,
+        index: IndexSettings {
+            role,
+            dust_relay_fee,
+            ldb_dir,
+            min_free_disk_bytes: min_free_disk_mb * MB as u64,
+            tag_unspendable: raw.diagnostics.tag_unspendable,
+            index_text: raw.search.text,
+        },
+        schedule,
+    })
+}
+
+// The network's preset with any [chain] overrides applied
+fn chain_params(network: Network, raw: &RawChain, errors: &mut Vec<String>) -> ChainParams {
+    let mut params = chain_params::params_for(network).clone();
+    if let Some(magic) = &raw.magic {
+        match hex::decode(magic) {
+            Ok(bytes) if bytes.len() == 4 => params.magic.copy_from_slice(&bytes),
+            _ => errors.push(format!("chain.magic must be 4 bytes of hex, got '{}'", magic)),
+        }
+    }
+    if let Some(port) = raw.default_port {
+        params.default_port = port;
+    }
+    if let Some(prefix) = raw.pubkey_address_prefix {
+        params.pubkey_address_prefix = prefix;
+    }
+    if let Some(prefix) = raw.script_address_prefix {
+        params.script_address_prefix = prefix;
+    }
+    if let Some(prefix) = raw.staking_address_prefix {
+        params.staking_address_prefix = prefix;
+    }
+    if let Some(hash) = &raw.genesis_hash {
+        match hex::decode(hash) {
+            // Loaded once per run, like the parameters it ends up in
+            Ok(bytes) if bytes.len() == 32 => {
+                params.genesis_hash = Box::leak(hash.to_lowercase().into_boxed_str());
+                // Another chain's genesis, so PIVX's merkle root no longer identifies it
+                params.genesis_merkle_root = None;
+            }
+            _ => errors.push(format!("chain.genesis_hash must be 32 bytes of hex, got '{}'", hash)),
+        }
+    }
+    params
+}
+
+fn db_tuning(profile: Profile, raw: &RawDb, errors: &mut Vec<String>) -> DbTuning {
+    let mut tuning = profile.tuning();
+    if let Some(mb) = raw.write_buffer_size_mb {
+        tuning.write_buffer_size = mb * MB;
+    }
+    if let Some(number) = raw.max_write_buffer_number {
+        tuning.max_write_buffer_number = number;
+    }
+    if let Some(jobs) = raw.max_background_jobs {
+        tuning.max_background_jobs = jobs;
+    }
+    if let Some(files) = raw.max_open_files {
+        tuning.max_open_files = files;
+    }
+    if let Some(mb) = raw.block_cache_mb {
+        tuning.block_cache_size = mb * MB;
+    }
+    if let Some(kb) = raw.zstd_dictionary_kb {
+        tuning.zstd_dictionary_size = kb * 1024;
+    }
+
+    if tuning.write_buffer_size == 0 {
+        errors.push("db.write_buffer_size_mb must be positive".to_string());
+    }
+    if tuning.max_write_buffer_number < 1 {
+        errors.push(format!("db.max_write_buffer_number must be at least 1, got {}", tuning.max_write_buffer_number));
+    }
+    if tuning.max_background_jobs < 1 {
+        errors.push(format!("db.max_background_jobs must be at least 1, got {}", tuning.max_background_jobs));
+    }
+    tuning
+}
+
+fn require_dir(errors: &mut Vec<String>, key: &str, dir: &Path) {
+    if dir.as_os_str().is_empty() {
+        errors.push(format!("{} is required", key));
+    } else if !dir.is_dir() {
+        errors.push(format!("{} {} is not a directory", key, dir.display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn parse(toml: &str) -> RawConfig {
+        Config::builder()
+            .add_source(ConfigFile::from_str(toml, FileFormat::Toml))
+            .build()
+            .and_then(|config| config.try_deserialize::<RawConfig>())
+            .unwrap()
+    }
+
+    // A valid config with `extra` appended; blk_dir only has to exist
+    fn config_with(extra: &str) -> String {
+        format!("[paths]\ndb_path = \"db\"\nblk_dir = {:?}\n{}", std::env::temp_dir(), extra)
+    }
+
+    #[test]
+    fn defaults() {
+        let config = validate(parse(&config_with(""))).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.index.role, Role::Full);
+        assert_eq!(config.index.dust_relay_fee, DEFAULT_DUST_RELAY_FEE);
+        assert_eq!(config.index.min_free_disk_bytes, 2048 * 1024 * 1024);
+        assert_eq!(config.chain_params.network, Network::Mainnet);
+        assert_eq!(config.db_tuning.write_buffer_size, Profile::Balanced.tuning().write_buffer_size);
+        assert!(config.schedule.is_empty());
+    }
+
+    #[test]
+    fn megabytes_are_mebibytes() {
+        let config = validate(parse(&config_with("
+            [sync]
+            min_free_disk_mb = 3
+            [db]
+            write_buffer_size_mb = 5
+            block_cache_mb = 7
+            zstd_dictionary_kb = 16
+        "))).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.index.min_free_disk_bytes, 3 * 1_048_576);
+        assert_eq!(config.db_tuning.write_buffer_size, 5 * 1_048_576);
+        assert_eq!(config.db_tuning.block_cache_size, 7 * 1_048_576);
+        assert_eq!(config.db_tuning.zstd_dictionary_size, 16 * 1024);
+    }
+
+    #[test]
+    fn overrides() {
+        // Top-level keys go before the first table
+        let toml = format!("role = \"analytics\"\n{}", config_with("
+            [chain]
+            network = \"testnet\"
+            magic = \"0a0b0c0d\"
+            pubkey_address_prefix = 30
+            [db]
+            compression = { transactions = \"zstd\", addr_index = \"lz4\" }
+            [schedule]
+            compact = \"30 3 * * 0\"
+        "));
+        let config = validate(parse(&toml)).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.index.role, Role::Analytics);
+        assert_eq!(config.chain_params.network, Network::Testnet);
+        assert_eq!(config.chain_params.magic, [0x0a, 0x0b, 0x0c, 0x0d]);
+        assert_eq!(config.chain_params.pubkey_address_prefix, 30);
+        assert_eq!(config.value_compression.get("transactions"), Some(&Compression::Zstd));
+        assert_eq!(config.value_compression.get("addr_index"), Some(&Compression::Lz4));
+        assert_eq!(config.schedule.len(), 1);
+    }
+
+    #[test]
+    fn every_bad_value_is_reported() {
+        let errors = match validate(parse("
+            role = \"archive\"
+            [chain]
+            network = \"moonnet\"
+            magic = \"0a0b\"
+            genesis_hash = \"abcd\"
+            [paths]
+            blk_dir = \"/nonexistent/blocks\"
+            labels_file = \"/nonexistent/labels.csv\"
+            [stats]
+            dust_relay_fee = -1
+            [db]
+            profile = \"fastest\"
+            write_buffer_size_mb = 0
+            max_write_buffer_number = 0
+            max_background_jobs = 0
+            compression = { blocks = \"zstd\", transactions = \"brotli\" }
+            [schedule]
+            reindex = \"* * * * *\"
+            sync = \"61 * * * *\"
+        ")) {
+            Ok(_) => panic!("invalid config accepted"),
+            Err(errors) => errors,
+        };
+        let expected = [
+            "archive", "moonnet", "chain.magic", "chain.genesis_hash", "paths.db_path is required",
+            "paths.blk_dir", "paths.labels_file", "stats.dust_relay_fee", "fastest",
+            "db.write_buffer_size_mb", "db.max_write_buffer_number", "db.max_background_jobs",
+            "db.compression.blocks", "db.compression.transactions", "reindex", "schedule.sync",
+        ];
+        for fragment in expected {
+            assert!(errors.iter().any(|e| e.contains(fragment)), "no error mentioning {} in {:?}", fragment, errors);
+        }
+        assert_eq!(errors.len(), expected.len(), "{:?}", errors);
+    }
+
+    #[test]
+    fn api_only_needs_no_blk_dir() {
+        let config = validate(parse("role = \"api-only\"\n[paths]\ndb_path = \"db\"\n")).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.index.role, Role::ApiOnly);
+    }
+}
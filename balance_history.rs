@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use rocksdb::{Direction, IteratorMode, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::block_commit::BlockCommit;
+use crate::parser::stream::decode_header;
+
+// An address's net movement within one block
+#[derive(Default, Serialize, Deserialize)]
+struct BlockDelta {
+    received: i64,
+    sent: i64,
+}
+
+// Totals over one time bucket; `balance` is the balance after the highest
+// block in it
+#[derive(Serialize, Debug)]
+pub struct BalanceBucket {
+    pub time: u64,
+    pub received: i64,
+    pub sent: i64,
+    pub balance: i64,
+}
+
+// 'd' + address length + address, shared by all of an address's entries
+fn address_prefix(address: &str) -> Vec<u8> {
+    let mut key = vec![b'd', address.len() as u8];
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+// 'd' + address length + address + height (big endian, so entries sort by height)
+// -> amounts received and sent in that block
+pub fn record(commit: &mut BlockCommit, address: &str, height: i32, received: i64, sent: i64) -> io::Result<()> {
+    let mut key = address_prefix(address);
+    key.extend_from_slice(&height.to_be_bytes());
+    let mut delta: BlockDelta = match commit.get_cf("addr_history", &key)? {
+        Some(data) => bincode::deserialize(&data).unwrap_or_default(),
+        None => BlockDelta::default(),
+    };
+    delta.received += received;
+    delta.sent += sent;
+
+    let data = bincode::serialize(&delta)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    commit.put_cf("addr_history", &key, &data);
+    Ok(())
+}
+
+// Received and sent over all of an address's entries
+pub fn totals(db: &DB, address: &str) -> io::Result<(i64, i64)> {
+    let cf_history = db.cf_handle("addr_history").expect("Address history column family not found");
+    let prefix = address_prefix(address);
+    let (mut received, mut sent) = (0, 0);
+    for item in db.iterator_cf(cf_history, IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 4 {
+            break;
+        }
+        let delta: BlockDelta = bincode::deserialize(&value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        received += delta.received;
+        sent += delta.sent;
+    }
+    Ok((received, sent))
+}
+
+// Balance history between two block times (inclusive, unix seconds), summed
+// into buckets of `group_by` seconds. Empty buckets are left out.
+pub fn query(db: &DB, address: &str, from: u64, to: u64, group_by: u64) -> io::Result<Vec<BalanceBucket>> {
+    let cf_history = db.cf_handle("addr_history").expect("Address history column family not found");
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let group_by = group_by.max(1);
+    let prefix = address_prefix(address);
+
+    // Keyed by bucket start, as block times can go backwards between blocks
+    let mut buckets: BTreeMap<u64, BalanceBucket> = BTreeMap::new();
+    let mut balance = 0;
+    for item in db.iterator_cf(cf_history, IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 4 {
+            break;
+        }
+        let delta: BlockDelta = bincode::deserialize(&value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // Entries before the range still count towards the balance
+        balance += delta.received - delta.sent;
+
+        let mut height_key = vec![b'h'];
+        height_key.extend_from_slice(&i32::from_be_bytes(key[prefix.len()..].try_into().unwrap()).to_le_bytes());
+        let time = match block_time(db, cf_blocks, &height_key)? {
+            Some(time) => time,
+            None => continue,
+        };
+        if time < from || time > to {
+            continue;
+        }
+
+        let bucket_time = time - time % group_by;
+        let bucket = buckets.entry(bucket_time)
+            .or_insert(BalanceBucket { time: bucket_time, received: 0, sent: 0, balance });
+        bucket.received += delta.received;
+        bucket.sent += delta.sent;
+        // Entries come in height order
+        bucket.balance = balance;
+    }
+    Ok(buckets.into_values().collect())
+}
+
+// Time of the block at a height, via 'h' + height -> hash and 'b' + hash -> header
+fn block_time(db: &DB, cf_blocks: &rocksdb::ColumnFamily, height_key: &[u8]) -> io::Result<Option<u64>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let hash = match db.get_cf(cf_blocks, height_key).map_err(to_io)? {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let mut key = vec![b'b'];
+    key.extend_from_slice(&hash);
+    match db.get_cf(cf_blocks, &key).map_err(to_io)? {
+        Some(header) => Ok(Some(decode_header(&header)?.0.n_time as u64)),
+        None => Ok(None),
+    }
+}
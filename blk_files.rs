@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use rocksdb::{IteratorMode, DB};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::block_commit::BlockCommit;
+
+// Bytes at the start of a blk file covered by its checksum. Enough to tell a
+// rewritten file (e.g. after a node reindex) from the one that was scanned.
+const CHECKSUM_LEN: u64 = 4096;
+
+// How far a blk file has been scanned, in blk_files keyed by file name
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanState {
+    // End of the last block indexed from the file
+    pub offset: u64,
+    pub last_block_hash: String,
+    // File size at the last scan; blk files are preallocated, so this
+    // usually doesn't change as blocks are appended
+    pub file_size: u64,
+    // SHA-256 of the first CHECKSUM_LEN bytes
+    pub checksum: String,
+    // The last scan read to the end of the file. Only informational: the node
+    // may append to it later, so a sync still resumes it from `offset`.
+    pub complete: bool,
+}
+
+fn file_key(file_path: &Path) -> Vec<u8> {
+    file_path.file_name().unwrap_or_default().to_string_lossy().as_bytes().to_vec()
+}
+
+pub fn checksum(file: &mut File) -> io::Result<String> {
+    let mut head = Vec::new();
+    file.take(CHECKSUM_LEN).read_to_end(&mut head)?;
+    Ok(hex::encode(Sha256::digest(&head)))
+}
+
+pub fn load(db: &DB, file_path: &Path) -> io::Result<Option<ScanState>> {
+    let cf_files = db.cf_handle("blk_files").expect("Blk files column family not found");
+    let value = db.get_cf(cf_files, file_key(file_path)).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(value.and_then(|value| bincode::deserialize(&value).ok()))
+}
+
+// Written in the block's commit, so the offset never runs ahead of the index
+pub fn record(commit: &mut BlockCommit, file_path: &Path, state: &ScanState) -> io::Result<()> {
+    let data = bincode::serialize(state)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    commit.put_cf("blk_files", &file_key(file_path), &data);
+    Ok(())
+}
+
+pub fn save(db: &DB, file_path: &Path, state: &ScanState) -> io::Result<()> {
+    let cf_files = db.cf_handle("blk_files").expect("Blk files column family not found");
+    let data = bincode::serialize(state)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    db.put_cf(cf_files, file_key(file_path), data).map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Every file's scan state, by file name
+pub fn all(db: &DB) -> io::Result<Vec<(String, ScanState)>> {
+    let cf_files = db.cf_handle("blk_files").expect("Blk files column family not found");
+    let mut states = Vec::new();
+    for item in db.iterator_cf(cf_files, IteratorMode::Start) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if let Ok(state) = bincode::deserialize(&value) {
+            states.push((String::from_utf8_lossy(&key).into_owned(), state));
+        }
+    }
+    Ok(states)
+}
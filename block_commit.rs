@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::io;
+
+use rocksdb::{WriteBatch, DB};
+
+use crate::db::codec;
+
+// Every write made while indexing one block, across all column families,
+// committed together in a single WriteBatch so a block is either fully
+// indexed or not at all. Reads go through the pending writes first, so later
+// transactions in the block see the outputs and index entries of earlier ones.
+// Values are compressed on the way out per the column family's policy.
+pub struct BlockCommit<'a> {
+    db: &'a DB,
+    batch: WriteBatch,
+    // (column family, key) -> value, None for a delete
+    pending: HashMap<(&'static str, Vec<u8>), Option<Vec<u8>>>,
+}
+
+impl<'a> BlockCommit<'a> {
+    pub fn new(db: &'a DB) -> Self {
+        BlockCommit {
+            db,
+            batch: WriteBatch::default(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn get_cf(&self, cf: &'static str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(&(cf, key.to_vec())) {
+            return Ok(value.clone());
+        }
+        let value = self.db.get_cf(handle(self.db, cf), key).map_err(to_io_error)?;
+        Ok(value.map(codec::decode))
+    }
+
+    pub fn put_cf(&mut self, cf: &'static str, key: &[u8], value: &[u8]) {
+        self.batch.put_cf(handle(self.db, cf), key, codec::encode(cf, value));
+        self.pending.insert((cf, key.to_vec()), Some(value.to_vec()));
+    }
+
+    pub fn delete_cf(&mut self, cf: &'static str, key: &[u8]) {
+        self.batch.delete_cf(handle(self.db, cf), key);
+        self.pending.insert((cf, key.to_vec()), None);
+    }
+
+    pub fn commit(self) -> io::Result<()> {
+        self.db.write(self.batch).map_err(to_io_error)
+    }
+}
+
+fn handle<'a>(db: &'a DB, cf: &str) -> &'a rocksdb::ColumnFamily {
+    db.cf_handle(cf).unwrap_or_else(|| panic!("{} column family not found", cf))
+}
+
+fn to_io_error(e: rocksdb::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor};
+use std::path::Path;
+
+use rocksdb::{ColumnFamilyDescriptor, Direction, IteratorMode, Options, Snapshot, WriteBatch, DB};
+use serde::Serialize;
+
+use crate::db::codec;
+use crate::parser::tx::{decode_transactions, hash_txid, DecodedTx};
+use crate::{address_keys, chain_params, BlockStats};
+
+// Experimental one-way export into Blockbook's RocksDB layout. Only the block
+// heights, the per-address transaction index and the raw transaction cache are
+// written; Blockbook's other column families (txAddresses, addressBalance,
+// blockTxs, internal state, ...) are not.
+
+const BLOCKBOOK_CFS: [&str; 3] = ["height", "addresses", "transactions"];
+const BATCH_ROWS: usize = 100_000;
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportCounts {
+    pub blocks: u64,
+    pub address_rows: u64,
+    pub transactions: u64,
+    // Shielded transactions keep no raw bytes here, so they are left out
+    pub shielded_skipped: u64,
+    // Addresses without a Blockbook descriptor (cold staker addresses)
+    pub addresses_skipped: u64,
+}
+
+// Write the export into a new database at `out`, reading `db` at one point in time
+pub fn export(db: &DB, out: &Path) -> io::Result<ExportCounts> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    if out.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", out.display())));
+    }
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let cfs = BLOCKBOOK_CFS.iter().map(|cf| ColumnFamilyDescriptor::new(*cf, Options::default()));
+    let target = DB::open_cf_descriptors(&options, out, cfs).map_err(to_io)?;
+
+    let snapshot = db.snapshot();
+    let mut counts = ExportCounts::default();
+    let tx_counts = export_addresses(db, &snapshot, &target, &mut counts)?;
+    export_heights(db, &snapshot, &target, &tx_counts, &mut counts)?;
+    Ok(counts)
+}
+
+// addresses: addrDesc + ^height (u32 BE, newest block first) -> for each
+// transaction, newest first: txid + its indexes as varints, outputs as
+// index << 1 and inputs as ^index << 1, the last one with the low bit set.
+// Raw transactions go to transactions along the way. Returns the number of
+// transactions per height, up to the last one that touches an address.
+fn export_addresses(db: &DB, snapshot: &Snapshot, target: &DB, counts: &mut ExportCounts) -> io::Result<HashMap<i32, u32>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_addr = db.cf_handle("addr_index").expect("Address_index column family not found");
+    let cf_addresses = target.cf_handle("addresses").expect("addresses column family not created");
+    let cf_transactions = target.cf_handle("transactions").expect("transactions column family not created");
+
+    let mut tx_counts: HashMap<i32, u32> = HashMap::new();
+    let mut batch = WriteBatch::default();
+    // Entries of one address at one height: (address, height) and (tx index, txid)
+    let mut group: Option<(String, i32)> = None;
+    let mut group_txs: Vec<(u32, Vec<u8>)> = Vec::new();
+    let flush = |group: &(String, i32), txs: &[(u32, Vec<u8>)], batch: &mut WriteBatch, counts: &mut ExportCounts| -> io::Result<()> {
+        let (address, height) = group;
+        let desc = match address_descriptor(address) {
+            Some(desc) => desc,
+            None => {
+                counts.addresses_skipped += 1;
+                return Ok(());
+            }
+        };
+        let block = match block_at(db, snapshot, *height)? {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        let spent = spent_at(db, snapshot, address, *height)?;
+
+        let mut tx_indexes = Vec::new();
+        for (_, txid) in txs {
+            let (tx, raw) = match load_transaction(db, snapshot, txid, block.version)? {
+                Some(loaded) => loaded,
+                None => {
+                    counts.shielded_skipped += 1;
+                    continue;
+                }
+            };
+            let indexes = address_indexes(&tx, address, &spent);
+            if indexes.is_empty() {
+                continue;
+            }
+            batch.put_cf(cf_transactions, txid, pack_tx(*height as u32, block.time, &raw));
+            counts.transactions += 1;
+            tx_indexes.push((txid.clone(), indexes));
+        }
+        if !tx_indexes.is_empty() {
+            batch.put_cf(cf_addresses, address_key(&desc, *height as u32), pack_tx_indexes(&tx_indexes));
+            counts.address_rows += 1;
+        }
+        Ok(())
+    };
+
+    for item in snapshot.iterator_cf(cf_addr, IteratorMode::From(b"h", Direction::Forward)) {
+        let (key, value) = item.map_err(to_io)?;
+        if key[0] != b'h' {
+            break;
+        }
+        // 'h' + address length + address + height + tx index (both big endian)
+        let len = key[1] as usize;
+        if key.len() != 2 + len + 8 {
+            continue;
+        }
+        let address = String::from_utf8_lossy(&key[2..2 + len]).into_owned();
+        let height = i32::from_be_bytes(key[2 + len..6 + len].try_into().unwrap());
+        let tx_index = u32::from_be_bytes(key[6 + len..].try_into().unwrap());
+        let known = tx_counts.entry(height).or_insert(0);
+        *known = (*known).max(tx_index + 1);
+
+        if group.as_ref() != Some(&(address.clone(), height)) {
+            if let Some(group) = &group {
+                flush(group, &group_txs, &mut batch, counts)?;
+            }
+            group = Some((address, height));
+            group_txs.clear();
+        }
+        group_txs.push((tx_index, codec::decode(value.to_vec())));
+
+        if batch.len() >= BATCH_ROWS {
+            target.write(std::mem::take(&mut batch)).map_err(to_io)?;
+        }
+    }
+    if let Some(group) = &group {
+        flush(group, &group_txs, &mut batch, counts)?;
+    }
+    target.write(batch).map_err(to_io)?;
+    Ok(tx_counts)
+}
+
+// height: height (u32 BE) -> block hash + time (u32 BE) + transaction count
+// and block size as varuints
+fn export_heights(db: &DB, snapshot: &Snapshot, target: &DB, tx_counts: &HashMap<i32, u32>, counts: &mut ExportCounts) -> io::Result<()> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let cf_stats = db.cf_handle("stats").expect("Stats column family not found");
+    let cf_height = target.cf_handle("height").expect("height column family not created");
+
+    let mut batch = WriteBatch::default();
+    for item in snapshot.iterator_cf(cf_blocks, IteratorMode::From(b"h", Direction::Forward)) {
+        let (key, hash) = item.map_err(to_io)?;
+        if key[0] != b'h' {
+            break;
+        }
+        // 'h' + block_height (LE) -> block_hash
+        if key.len() != 5 || hash.len() != 32 {
+            continue;
+        }
+        let height = i32::from_le_bytes(key[1..5].try_into().unwrap());
+        let block = match block_at(db, snapshot, height)? {
+            Some(block) => block,
+            None => continue,
+        };
+        let size = snapshot.get_cf(cf_stats, height.to_le_bytes()).map_err(to_io)?
+            .and_then(|data| BlockStats::decode(&data))
+            .map_or(0, |stats| stats.size);
+        let info = pack_block_info(&hash, block.time, tx_counts.get(&height).copied().unwrap_or(0), size);
+        batch.put_cf(cf_height, (height as u32).to_be_bytes(), info);
+        counts.blocks += 1;
+
+        if batch.len() >= BATCH_ROWS {
+            target.write(std::mem::take(&mut batch)).map_err(to_io)?;
+        }
+    }
+    target.write(batch).map_err(to_io)
+}
+
+struct BlockAt {
+    version: u32,
+    time: u32,
+}
+
+fn block_at(db: &DB, snapshot: &Snapshot, height: i32) -> io::Result<Option<BlockAt>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let mut key = vec![b'h'];
+    key.extend_from_slice(&height.to_le_bytes());
+    let hash = match snapshot.get_cf(cf_blocks, &key).map_err(to_io)? {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let mut key = vec![b'b'];
+    key.extend_from_slice(&hash);
+    Ok(snapshot.get_cf(cf_blocks, &key).map_err(to_io)?
+        .filter(|header| header.len() >= 80)
+        .map(|header| BlockAt {
+            version: u32::from_le_bytes(header[0..4].try_into().unwrap()),
+            time: u32::from_le_bytes(header[68..72].try_into().unwrap()),
+        }))
+}
+
+// Outputs `address` spent in the block at `height`, from its 'o' entries
+fn spent_at(db: &DB, snapshot: &Snapshot, address: &str, height: i32) -> io::Result<HashSet<(Vec<u8>, u32)>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_addr = db.cf_handle("addr_index").expect("Address_index column family not found");
+    let mut prefix = vec![b'o', address.len() as u8];
+    prefix.extend_from_slice(address.as_bytes());
+    prefix.extend_from_slice(&height.to_be_bytes());
+
+    let mut spent = HashSet::new();
+    for item in snapshot.iterator_cf(cf_addr, IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, _) = item.map_err(to_io)?;
+        // prefix + txid + vout (big endian)
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 36 {
+            break;
+        }
+        let vout = u32::from_be_bytes(key[prefix.len() + 32..].try_into().unwrap());
+        spent.insert((key[prefix.len()..prefix.len() + 32].to_vec(), vout));
+    }
+    Ok(spent)
+}
+
+// The transaction and its raw bytes, None when only its shielded data is stored
+fn load_transaction(db: &DB, snapshot: &Snapshot, txid: &[u8], block_version: u32) -> io::Result<Option<(DecodedTx, Vec<u8>)>> {
+    let cf_transactions = db.cf_handle("transactions").expect("Transactions column family not found");
+    let mut key = vec![b't'];
+    key.extend_from_slice(txid);
+    let raw = match snapshot.get_cf(cf_transactions, &key).map_err(|e| io::Error::other(e.to_string()))? {
+        Some(value) => codec::decode(value),
+        None => return Ok(None),
+    };
+    if hash_txid(&raw)? != txid {
+        return Ok(None);
+    }
+    let mut block = vec![1u8];
+    block.extend_from_slice(&raw);
+    let tx = decode_transactions(&mut Cursor::new(block), block_version)?.pop().flatten();
+    Ok(tx.map(|tx| (tx, raw)))
+}
+
+// Output indexes of `tx` paying `address`, then the inputs spending its outputs as ^index
+fn address_indexes(tx: &DecodedTx, address: &str, spent: &HashSet<(Vec<u8>, u32)>) -> Vec<i32> {
+    let mut indexes: Vec<i32> = tx.address_types.iter().enumerate()
+        .filter(|(_, address_type)| address_keys(address_type).iter().any(|key| key == address))
+        .map(|(index, _)| index as i32)
+        .collect();
+    for tx_in in &tx.transaction.inputs {
+        let spends_address = tx_in.prevout.as_ref()
+            .and_then(|prevout| hex::decode(&prevout.hash).ok().map(|txid| (txid, prevout.n)))
+            .is_some_and(|outpoint| spent.contains(&outpoint));
+        if spends_address {
+            indexes.push(!(tx_in.index as i32));
+        }
+    }
+    indexes
+}
+
+// Blockbook's address descriptor, the output script of a P2PKH or P2SH address
+fn address_descriptor(address: &str) -> Option<Vec<u8>> {
+    let params = chain_params::params();
+    let data = bs58::decode(address).into_vec().ok()?;
+    if data.len() != 25 {
+        return None;
+    }
+    let hash = &data[1..21];
+    if data[0] == params.pubkey_address_prefix {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(hash);
+        script.extend_from_slice(&[0x88, 0xac]);
+        Some(script)
+    } else if data[0] == params.script_address_prefix {
+        let mut script = vec![0xa9, 0x14];
+        script.extend_from_slice(hash);
+        script.push(0x87);
+        Some(script)
+    } else {
+        None
+    }
+}
+
+fn address_key(desc: &[u8], height: u32) -> Vec<u8> {
+    let mut key = desc.to_vec();
+    key.extend_from_slice(&(!height).to_be_bytes());
+    key
+}
+
+// `txs` in block order
+fn pack_tx_indexes(txs: &[(Vec<u8>, Vec<i32>)]) -> Vec<u8> {
+    let mut value = Vec::new();
+    for (txid, indexes) in txs.iter().rev() {
+        value.extend_from_slice(txid);
+        for (i, index) in indexes.iter().enumerate() {
+            let last = if i == indexes.len() - 1 { 1 } else { 0 };
+            put_varint(&mut value, ((index << 1) | last) as i64);
+        }
+    }
+    value
+}
+
+// Block hash as explorers show it, time, transaction count and size
+fn pack_block_info(hash: &[u8], time: u32, txs: u32, size: u32) -> Vec<u8> {
+    let mut value: Vec<u8> = hash.iter().rev().cloned().collect();
+    value.extend_from_slice(&time.to_be_bytes());
+    put_varuint(&mut value, txs as u64);
+    put_varuint(&mut value, size as u64);
+    value
+}
+
+// transactions: txid -> height (u32 BE) + block time (varint) + raw transaction
+fn pack_tx(height: u32, time: u32, raw: &[u8]) -> Vec<u8> {
+    let mut value = height.to_be_bytes().to_vec();
+    put_varint(&mut value, time as i64);
+    value.extend_from_slice(raw);
+    value
+}
+
+// Blockbook's vlq varuint: base 128, most significant group first, each group
+// but the last one less than its value (Bitcoin Core's VARINT)
+fn put_varuint(buf: &mut Vec<u8>, mut n: u64) {
+    let mut groups = vec![(n & 0x7f) as u8];
+    while n > 0x7f {
+        n = (n >> 7) - 1;
+        groups.push((n & 0x7f) as u8 | 0x80);
+    }
+    buf.extend(groups.iter().rev());
+}
+
+// Signed values are zigzag encoded first
+fn put_varint(buf: &mut Vec<u8>, n: i64) {
+    let zigzag = if n < 0 { !((n as u64) << 1) } else { (n as u64) << 1 };
+    put_varuint(buf, zigzag);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::address::hash_address;
+
+    fn varuint(n: u64) -> String {
+        let mut buf = Vec::new();
+        put_varuint(&mut buf, n);
+        hex::encode(buf)
+    }
+
+    // Bitcoin Core's serialize_tests varint_bitpatterns
+    #[test]
+    fn varuint_bit_patterns() {
+        let cases = [
+            (0, "00"), (0x7f, "7f"), (0x80, "8000"), (0x1234, "a334"), (0xffff, "82fe7f"),
+            (0x123456, "c7e756"), (0x80123456, "86ffc7e756"), (0xffffffff, "8efefefe7f"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(varuint(n), expected, "{:#x}", n);
+        }
+    }
+
+    #[test]
+    fn varint_zigzag() {
+        for (n, expected) in [(0, "00"), (-1, "01"), (1, "02"), (-2, "03"), (64, "8000")] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, n);
+            assert_eq!(hex::encode(buf), expected, "{}", n);
+        }
+    }
+
+    #[test]
+    fn tx_indexes_newest_first_with_last_flag() {
+        let older = vec![0xaa; 32];
+        let newer = vec![0xbb; 32];
+        // Output 0 and input 0 of the older transaction, output 2 of the newer
+        let packed = pack_tx_indexes(&[(older.clone(), vec![0, !0]), (newer.clone(), vec![2])]);
+        let mut expected = newer;
+        expected.push(0x0a); // 2 << 1 | 1, zigzagged
+        expected.extend_from_slice(&older);
+        expected.extend_from_slice(&[0x00, 0x01]); // 0 << 1, then ^0 << 1 | 1
+        assert_eq!(packed, expected);
+    }
+
+    #[test]
+    fn address_keys_sort_newest_first() {
+        let desc = [0x76, 0xa9];
+        assert_eq!(address_key(&desc, 0), vec![0x76, 0xa9, 0xff, 0xff, 0xff, 0xff]);
+        assert!(address_key(&desc, 200) < address_key(&desc, 100));
+    }
+
+    #[test]
+    fn block_info_layout() {
+        let mut hash = [0u8; 32];
+        hash[0] = 0x18;
+        hash[31] = 0x01;
+        let info = pack_block_info(&hash, 0x5e0be100, 3, 0x1234);
+        assert_eq!(info[0], 0x01);
+        assert_eq!(info[31], 0x18);
+        assert_eq!(hex::encode(&info[32..]), "5e0be10003a334");
+    }
+
+    #[test]
+    fn descriptors_of_base58_addresses() {
+        let params = chain_params::params();
+        let hash = [0x11u8; 20];
+        let p2pkh = address_descriptor(&hash_address(&hash, params.pubkey_address_prefix)).unwrap();
+        assert_eq!(hex::encode(p2pkh), format!("76a914{}88ac", "11".repeat(20)));
+        let p2sh = address_descriptor(&hash_address(&hash, params.script_address_prefix)).unwrap();
+        assert_eq!(hex::encode(p2sh), format!("a914{}87", "11".repeat(20)));
+        assert_eq!(address_descriptor(&hash_address(&hash, params.staking_address_prefix)), None);
+        assert_eq!(address_descriptor("not an address"), None);
+    }
+}
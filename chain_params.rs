@@ -0,0 +1,319 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::parser::types::CBlockHeader;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("Unknown chain.network '{}', expected mainnet, testnet or regtest", s)),
+        }
+    }
+}
+
+// Per-network parameters, mirroring CChainParams in PIVX Core
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    // pchMessageStart, which also frames every block in the blk files
+    pub magic: [u8; 4],
+    // Default P2P port of the network's nodes
+    pub default_port: u16,
+    pub pubkey_address_prefix: u8,
+    pub script_address_prefix: u8,
+    pub staking_address_prefix: u8,
+    // Version bytes of BIP32 extended public keys
+    pub ext_public_key_prefix: [u8; 4],
+    // Hash of the genesis block, as block explorers show it
+    pub genesis_hash: &'static str,
+    // Merkle root of the genesis block, in the same byte order. Identifies a
+    // Quark-hashed genesis header; None for chains only known by their hash.
+    pub genesis_merkle_root: Option<&'static str>,
+}
+
+impl ChainParams {
+    // Whether a header is this network's genesis block. Headers before version
+    // 4 are hashed with Quark, which is not computed here (block_hash is always
+    // SHA-256d), so PIVX's genesis is recognised by its merkle root. Chains
+    // hashed with SHA-256d, like the ones gen-fixture writes, match genesis_hash.
+    pub fn is_genesis(&self, header: &CBlockHeader) -> bool {
+        if header.hash_prev_block != [0u8; 32] {
+            return false;
+        }
+        let display = |bytes: &[u8; 32]| hex::encode(bytes.iter().rev().cloned().collect::<Vec<u8>>());
+        display(&header.block_hash) == self.genesis_hash
+            || (header.n_version < 4 && Some(display(&header.hash_merkle_root).as_str()) == self.genesis_merkle_root)
+    }
+}
+
+// PIVX's testnet and regtest start from the mainnet genesis block: in PIVX
+// Core's src/chainparams.cpp, CMainParams, CTestNetParams and CRegTestParams all
+// build CreateGenesisBlock(1454124731, 2402015, 0x1e0ffff0, 1, 250 * COIN) and
+// assert these hashGenesisBlock and hashMerkleRoot values
+const GENESIS_HASH: &str = "0000041e482b9b9691d98eefb48473405c0b8ec31b76df3797c74a78680ef818";
+const GENESIS_MERKLE_ROOT: &str = "1b2ef6e2f28be914103a277377ae7729dcd125dfeb8bf97bd5964ba72b6dc39b";
+
+static MAINNET: ChainParams = ChainParams {
+    network: Network::Mainnet,
+    magic: [0x90, 0xc4, 0xfd, 0xe9],
+    default_port: 51472,
+    pubkey_address_prefix: 30, // 'D'
+    script_address_prefix: 13, // '6'
+    staking_address_prefix: 63, // 'S'
+    ext_public_key_prefix: [0x02, 0x2d, 0x25, 0x33],
+    genesis_hash: GENESIS_HASH,
+    genesis_merkle_root: Some(GENESIS_MERKLE_ROOT),
+};
+
+static TESTNET: ChainParams = ChainParams {
+    network: Network::Testnet,
+    magic: [0xf5, 0xe6, 0xd5, 0xca],
+    default_port: 51474,
+    pubkey_address_prefix: 139, // 'x' or 'y'
+    script_address_prefix: 19, // '8' or '9'
+    staking_address_prefix: 73, // 'W'
+    ext_public_key_prefix: [0x3a, 0x80, 0x61, 0xa0],
+    genesis_hash: GENESIS_HASH,
+    genesis_merkle_root: Some(GENESIS_MERKLE_ROOT),
+};
+
+static REGTEST: ChainParams = ChainParams {
+    network: Network::Regtest,
+    magic: [0xa1, 0xcf, 0x7e, 0xac],
+    default_port: 51476,
+    pubkey_address_prefix: 139,
+    script_address_prefix: 19,
+    staking_address_prefix: 73,
+    ext_public_key_prefix: [0x3a, 0x80, 0x61, 0xa0],
+    genesis_hash: GENESIS_HASH,
+    genesis_merkle_root: Some(GENESIS_MERKLE_ROOT),
+};
+
+static ACTIVE: OnceLock<&'static ChainParams> = OnceLock::new();
+
+pub fn params_for(network: Network) -> &'static ChainParams {
+    match network {
+        Network::Mainnet => &MAINNET,
+        Network::Testnet => &TESTNET,
+        Network::Regtest => &REGTEST,
+    }
+}
+
+// Select the parameters once at startup; later calls keep the first choice
+pub fn select(params: ChainParams) -> &'static ChainParams {
+    ACTIVE.get_or_init(|| Box::leak(Box::new(params)))
+}
+
+// Parameters for the selected network, defaulting to mainnet
+pub fn params() -> &'static ChainParams {
+    ACTIVE.get_or_init(|| &MAINNET)
+}
+
+// Network of the blocks in a blk directory, from the magic at the start of its
+// first blk file; None when there are no blk files yet. A magic overridden in
+// the config counts as the selected network.
+pub fn detect_network(blk_dir: &Path) -> io::Result<Option<Network>> {
+    let mut blk_files: Vec<_> = fs::read_dir(blk_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name().and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat")))
+        .collect();
+    blk_files.sort();
+    let first = match blk_files.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    let mut magic = [0u8; 4];
+    File::open(first)?.read_exact(&mut magic)?;
+    [params(), &MAINNET, &TESTNET, &REGTEST].iter()
+        .find(|params| params.magic == magic)
+        .map(|params| Some(params.network))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+            format!("{} starts with unknown magic {}, not a PIVX blk file", first.display(), hex::encode(magic))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::address::{
+        hash_address, scriptpubkey_to_p2pk, scriptpubkey_to_p2pkh_address, scriptpubkey_to_p2sh_address,
+        scriptpubkey_to_staking_address,
+    };
+    use crate::parser::stream::RawBlock;
+    use crate::parser::tx::{merkle_root, sha256d};
+    use crate::parser::types::CScript;
+
+    // HASH160 of the compressed and uncompressed secp256k1 generator point,
+    // the keys of bitcoin's 1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH and
+    // 1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm
+    const G_COMPRESSED_HASH: &str = "751e76e8199196d454941c45d1b3a323f1433bd6";
+    const G_UNCOMPRESSED_HASH: &str = "91b24bf9f5288532960ac687abb035127b1d28a5";
+    const G_COMPRESSED: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_UNCOMPRESSED: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+        483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    fn script(parts: &[&[u8]]) -> CScript {
+        CScript { script: parts.concat() }
+    }
+
+    #[test]
+    fn base58check_matches_bitcoin() {
+        let hash = hex::decode(G_COMPRESSED_HASH).unwrap();
+        assert_eq!(hash_address(&hash, 0), "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+        let hash = hex::decode(G_UNCOMPRESSED_HASH).unwrap();
+        assert_eq!(hash_address(&hash, 0), "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm");
+    }
+
+    #[test]
+    fn mainnet_params() {
+        let params = params_for(Network::Mainnet);
+        assert_eq!(params.magic, [0x90, 0xc4, 0xfd, 0xe9]);
+        assert_eq!(params.default_port, 51472);
+        assert_eq!(params.ext_public_key_prefix, [0x02, 0x2d, 0x25, 0x33]);
+
+        let hash = hex::decode(G_COMPRESSED_HASH).unwrap();
+        assert_eq!(hash_address(&hash, params.pubkey_address_prefix), "DFpN6QqFfUm3gKNaxN6tNcab1FArL9cZLE");
+        assert_eq!(hash_address(&hash, params.script_address_prefix), "6R57MZmMbQt9mx17YESU8UxDJfnpL9jg7i");
+        assert_eq!(hash_address(&hash, params.staking_address_prefix), "SXyGazfm6S3xfcySmD6QNkZYmtfysC2jvc");
+    }
+
+    #[test]
+    fn testnet_and_regtest_params() {
+        let testnet = params_for(Network::Testnet);
+        assert_eq!(testnet.magic, [0xf5, 0xe6, 0xd5, 0xca]);
+        assert_eq!(testnet.default_port, 51474);
+        let regtest = params_for(Network::Regtest);
+        assert_eq!(regtest.magic, [0xa1, 0xcf, 0x7e, 0xac]);
+        assert_eq!(regtest.default_port, 51476);
+
+        let hash = hex::decode(G_COMPRESSED_HASH).unwrap();
+        for params in [testnet, regtest] {
+            assert_eq!(params.ext_public_key_prefix, [0x3a, 0x80, 0x61, 0xa0]);
+            assert_eq!(hash_address(&hash, params.pubkey_address_prefix), "y7f7RFKf49GYpZa2d6QdEHFLcEFfuoNcer");
+            assert_eq!(hash_address(&hash, params.script_address_prefix), "8q6jGDZ5rVfQgYqdgkSP3Eaw5hLUXD8Nyi");
+            assert_eq!(hash_address(&hash, params.staking_address_prefix), "WZMJS5eeCEgiqxNK1QRbE1HR4wFQwmCjJV");
+        }
+    }
+
+    #[test]
+    fn magics_are_distinct() {
+        let magics = [Network::Mainnet, Network::Testnet, Network::Regtest].map(|network| params_for(network).magic);
+        assert_ne!(magics[0], magics[1]);
+        assert_ne!(magics[0], magics[2]);
+        assert_ne!(magics[1], magics[2]);
+    }
+
+    // Scripts decode with the mainnet prefixes, as no test selects another network
+    #[test]
+    fn mainnet_script_addresses() {
+        let hash = hex::decode(G_COMPRESSED_HASH).unwrap();
+        let p2pkh = script(&[&[0x76, 0xa9, 0x14], &hash, &[0x88, 0xac]]);
+        assert_eq!(scriptpubkey_to_p2pkh_address(&p2pkh).as_deref(), Some("DFpN6QqFfUm3gKNaxN6tNcab1FArL9cZLE"));
+
+        let p2sh = script(&[&[0xa9, 0x14], &hash, &[0x87]]);
+        assert_eq!(scriptpubkey_to_p2sh_address(&p2sh).as_deref(), Some("6R57MZmMbQt9mx17YESU8UxDJfnpL9jg7i"));
+
+        // Pay-to-pubkey outputs are indexed under the compressed key's P2PKH address
+        let compressed = script(&[&[0x21], &hex::decode(G_COMPRESSED).unwrap(), &[0xac]]);
+        assert_eq!(scriptpubkey_to_p2pk(&compressed).as_deref(), Some("DFpN6QqFfUm3gKNaxN6tNcab1FArL9cZLE"));
+        let uncompressed = script(&[&[0x41], &hex::decode(G_UNCOMPRESSED).unwrap(), &[0xac]]);
+        assert_eq!(scriptpubkey_to_p2pk(&uncompressed).as_deref(), Some("DFpN6QqFfUm3gKNaxN6tNcab1FArL9cZLE"));
+
+        let owner = hex::decode(G_UNCOMPRESSED_HASH).unwrap();
+        for opcode in [0xd1, 0xd2] {
+            let cold_stake = script(&[&[0x76, 0xa9, 0x7b, 0x63, opcode, 0x14], &hash, &[0x67, 0x14], &owner, &[0x68, 0x88, 0xac]]);
+            assert_eq!(scriptpubkey_to_staking_address(&cold_stake), Some((
+                "SXyGazfm6S3xfcySmD6QNkZYmtfysC2jvc".to_string(),
+                "DJRU7MLhcPwCTNRZ4e8gJzDebtG1H5M7pc".to_string(),
+            )));
+        }
+    }
+
+    // PIVX's genesis block as CreateGenesisBlock in chainparams.cpp builds it
+    fn pivx_genesis_block() -> Vec<u8> {
+        let timestamp = b"U.S. News & World Report Jan 28 2016 With His Absence, Trump Dominates Another Debate";
+        let pubkey = hex::decode("04c10e83b2703ccf322f7dbd62dd5855ac7c10bd055814ce121ba32607d573b8810c02c0582aed05b4deb9c4b77b26d92428c61256cd42774babea0a073b2ed0c9").unwrap();
+        // nBits 486604799, CScriptNum(4), then the timestamp with OP_PUSHDATA1
+        let script_sig = [&[0x04, 0xff, 0xff, 0x00, 0x1d, 0x01, 0x04, 0x4c, timestamp.len() as u8][..], timestamp].concat();
+        let script_pubkey = [&[pubkey.len() as u8][..], &pubkey, &[0xac]].concat();
+        let coinbase = [
+            &1u32.to_le_bytes()[..], &[1], &[0u8; 32], &[0xff; 4], &[script_sig.len() as u8], &script_sig, &[0xff; 4],
+            &[1], &(250 * 100_000_000i64).to_le_bytes(), &[script_pubkey.len() as u8], &script_pubkey, &0u32.to_le_bytes(),
+        ].concat();
+
+        let merkle = merkle_root(&[sha256d(&coinbase)]);
+        [
+            &1u32.to_le_bytes()[..], &[0u8; 32], &merkle, &1454124731u32.to_le_bytes(), &0x1e0ffff0u32.to_le_bytes(),
+            &2402015u32.to_le_bytes(), &[1], &coinbase,
+        ].concat()
+    }
+
+    #[test]
+    fn pivx_genesis_is_recognised() {
+        let header = RawBlock::from_data(0, pivx_genesis_block()).unwrap().header;
+        let merkle: Vec<u8> = header.hash_merkle_root.iter().rev().cloned().collect();
+        assert_eq!(hex::encode(merkle), GENESIS_MERKLE_ROOT);
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            assert!(params_for(network).is_genesis(&header));
+        }
+
+        // Only PIVX's genesis merkle root counts once another genesis hash is configured
+        let mut other_chain = params_for(Network::Mainnet).clone();
+        other_chain.genesis_hash = "00".repeat(32).leak();
+        other_chain.genesis_merkle_root = None;
+        assert!(!other_chain.is_genesis(&header));
+    }
+
+    #[test]
+    fn other_first_blocks_are_rejected() {
+        let params = params_for(Network::Mainnet);
+        let mut block = pivx_genesis_block();
+        // A different nonce changes nothing the check looks at
+        block[76] ^= 1;
+        assert!(params.is_genesis(&RawBlock::from_data(0, block.clone()).unwrap().header));
+        // A block with a parent or another merkle root is not the genesis block
+        block[4] = 1;
+        assert!(!params.is_genesis(&RawBlock::from_data(0, block.clone()).unwrap().header));
+        block[4] = 0;
+        block[36] ^= 1;
+        assert!(!params.is_genesis(&RawBlock::from_data(0, block).unwrap().header));
+    }
+
+    // Generated chains hash with SHA-256d and are matched by genesis_hash
+    #[test]
+    fn sha256d_genesis_matches_configured_hash() {
+        let mut block = pivx_genesis_block();
+        block[36] ^= 1;
+        let header = RawBlock::from_data(0, block).unwrap().header;
+        let hash: Vec<u8> = header.block_hash.iter().rev().cloned().collect();
+        let mut params = params_for(Network::Regtest).clone();
+        params.genesis_hash = hex::encode(hash).leak();
+        params.genesis_merkle_root = None;
+        assert!(params.is_genesis(&header));
+    }
+
+    #[test]
+    fn network_names() {
+        assert_eq!("main".parse::<Network>(), Ok(Network::Mainnet));
+        assert_eq!("testnet".parse::<Network>(), Ok(Network::Testnet));
+        assert_eq!("regtest".parse::<Network>(), Ok(Network::Regtest));
+        assert!("signet".parse::<Network>().is_err());
+    }
+}
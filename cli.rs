@@ -0,0 +1,155 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::events::EventType;
+
+// Command line for the rustyblox binary; no subcommand means `sync`
+#[derive(Parser)]
+#[command(name = "rustyblox", about = "PIVX block file indexer")]
+pub struct Cli {
+    /// Configuration file to load
+    #[arg(long, default_value = "config.toml")]
+    pub config: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Index new blk files into the database
+    Sync,
+    /// Index blocks downloaded from a PIVX node instead of blk files
+    SyncPeer {
+        /// host or host:port; the port defaults to the network's P2P port
+        peer: String,
+    },
+    /// Run the tasks under [schedule] when due, until stopped
+    Schedule,
+    /// Print the last run of each scheduled task as JSON lines
+    Tasks,
+    /// Print how far each blk file has been scanned as JSON lines
+    BlkFiles,
+    /// Print recorded sync events as JSON lines
+    Events {
+        /// First event time to include, unix seconds
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Only events of this type, e.g. migration or issuance_divergence
+        #[arg(long = "type")]
+        event_type: Option<EventType>,
+    },
+    /// Print journal entries from a sequence number onward as JSON lines
+    DumpJournal {
+        #[arg(default_value_t = 0)]
+        from: u64,
+    },
+    /// Recompute one address's UTXO entries and balance from the UTXO set
+    RepairAddress { address: String },
+    /// Print an address's balance and UTXO summary as JSON
+    Address { address: String },
+    /// Derive an xpub's receive and change addresses and print their totals as JSON
+    Xpub {
+        xpub: String,
+        /// Consecutive unused addresses after which a chain is considered done
+        #[arg(long, default_value_t = 20)]
+        gap_limit: u32,
+    },
+    /// Print an address's UTXOs as of a past block height as JSON
+    UtxoAt { address: String, height: i32 },
+    /// Estimate the fee rate to confirm within a number of blocks from recent blocks
+    EstimateFee {
+        #[arg(default_value_t = 2)]
+        blocks: u32,
+    },
+    /// Print the stakers with the most PoS blocks as JSON
+    Stakers {
+        /// Only count the last N days (UTC); all time when omitted
+        #[arg(long)]
+        days: Option<u32>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Print the addresses with the largest balances as JSON
+    RichList {
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Print the UTXO set commitment after the block at a height
+    UtxoCommitment { height: i32 },
+    /// Print the Sapling tree size, final root and spent anchors of the block at a height
+    ShieldAnchors { height: i32 },
+    /// Print the staked value, input age and reward of the coinstake at a height
+    StakeInfo { height: i32 },
+    /// Print the blocks whose transactions didn't hash to their merkle root as JSON lines
+    MerkleMismatches,
+    /// Print the address that staked the block at a height
+    StakedBy { height: i32 },
+    /// Experimental: write Blockbook's height, addresses and transactions column families to a new database
+    ExportBlockbook { out: PathBuf },
+    /// Print one page of an address's transactions, newest first, as JSON
+    AddressTxs {
+        address: String,
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+        #[arg(long, default_value_t = 25)]
+        page_size: usize,
+        /// Lowest block height to include
+        #[arg(long, default_value_t = 0)]
+        from: i32,
+        /// Highest block height to include
+        #[arg(long, default_value_t = i32::MAX)]
+        to: i32,
+    },
+    /// Print an address's received, sent and balance per time bucket as JSON
+    BalanceHistory {
+        address: String,
+        /// First block time to include, unix seconds
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Last block time to include, unix seconds
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        /// Bucket size in seconds
+        #[arg(long, default_value_t = 3600)]
+        group_by: u64,
+    },
+    /// Print txids whose OP_RETURN text contains every word of the query
+    SearchText {
+        query: String,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Write a deterministic test chain as a blk file (needs no config or database)
+    GenFixture {
+        out: PathBuf,
+        #[arg(long, default_value_t = 100)]
+        pow_blocks: u32,
+        #[arg(long, default_value_t = 100)]
+        pos_blocks: u32,
+    },
+}
+
+impl Command {
+    // Commands that add blocks to the database
+    pub fn indexes(&self) -> bool {
+        matches!(self, Command::Sync | Command::SyncPeer { .. } | Command::Schedule)
+    }
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Print disk usage and key counts per column family
+    Stats,
+    /// Compact a column family, applying its current compression settings
+    Compact {
+        #[arg(default_value = "transactions")]
+        cf: String,
+    },
+}
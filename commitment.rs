@@ -0,0 +1,106 @@
+use std::io;
+
+use rocksdb::{IteratorMode, DB};
+use sha2::{Digest, Sha256};
+
+use crate::block_commit::BlockCommit;
+
+// Order-independent hash of the UTXO set: the sum, modulo 2^256, of
+// SHA256(key || value) over every UTXO entry. Adding and removing entries
+// updates it without rescanning, so two indexes at the same height can be
+// compared by this value alone.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct UtxoCommitment([u64; 4]);
+
+impl UtxoCommitment {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        UtxoCommitment(limbs)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0.iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let element = element_hash(key, value);
+        let mut carry = false;
+        for (limb, other) in self.0.iter_mut().zip(element.iter()) {
+            let (sum, overflow1) = limb.overflowing_add(*other);
+            let (sum, overflow2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = overflow1 || overflow2;
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8], value: &[u8]) {
+        let element = element_hash(key, value);
+        let mut borrow = false;
+        for (limb, other) in self.0.iter_mut().zip(element.iter()) {
+            let (diff, underflow1) = limb.overflowing_sub(*other);
+            let (diff, underflow2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = underflow1 || underflow2;
+        }
+    }
+}
+
+fn element_hash(key: &[u8], value: &[u8]) -> [u64; 4] {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(value);
+    UtxoCommitment::from_bytes(&hasher.finalize()).0
+}
+
+// Current commitment from the chain_state column family
+pub fn load(commit: &BlockCommit) -> io::Result<UtxoCommitment> {
+    let stored = commit.get_cf("chain_state", b"utxo_commitment")?;
+    Ok(stored.map_or_else(UtxoCommitment::default, |bytes| UtxoCommitment::from_bytes(&bytes)))
+}
+
+// Persist the commitment as current and as the value at `height`
+pub fn store(commit: &mut BlockCommit, height: i32, commitment: &UtxoCommitment) {
+    let bytes = commitment.to_bytes();
+
+    // 'c' + block_height -> UTXO set commitment after the block
+    let mut key = vec![b'c'];
+    key.extend_from_slice(&height.to_le_bytes());
+    commit.put_cf("blocks", &key, &bytes);
+    commit.put_cf("chain_state", b"utxo_commitment", &bytes);
+}
+
+// Commitment after the block at `height`, None for blocks indexed before it was tracked
+pub fn at(db: &DB, height: i32) -> io::Result<Option<UtxoCommitment>> {
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let mut key = vec![b'c'];
+    key.extend_from_slice(&height.to_le_bytes());
+    let value = db.get_cf(cf_blocks, &key).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(value.filter(|bytes| bytes.len() == 32).map(|bytes| UtxoCommitment::from_bytes(&bytes)))
+}
+
+// Compute the commitment from scratch, for databases indexed before it was tracked
+pub fn rebuild(db: &DB) -> io::Result<UtxoCommitment> {
+    let cf_utxo = db.cf_handle("utxo").expect("UTXO column family not found");
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let snapshot = db.snapshot();
+
+    let mut commitment = UtxoCommitment::default();
+    for item in snapshot.iterator_cf(cf_utxo, IteratorMode::Start) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        // 'u' + 32 byte txid + 4 byte index
+        if key.len() == 37 && key[0] == b'u' {
+            commitment.add(&key, &value);
+        }
+    }
+
+    db.put_cf(cf_state, b"utxo_commitment", commitment.to_bytes())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(commitment)
+}
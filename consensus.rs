@@ -0,0 +1,32 @@
+// PIVX mainnet consensus constants and block reward schedule
+// Mirrors GetBlockValue from the PIVX Core sources, which pays the 4.5 PIV
+// reward up to and including the zerocoin V2 start height
+
+pub const COIN: i64 = 100_000_000;
+// Seconds between blocks
+pub const TARGET_SPACING: i64 = 60;
+
+pub const ZEROCOIN_BLOCK_V2_START: i32 = 1153160;
+pub const V5_5_ACTIVATION_HEIGHT: i32 = 3715200;
+
+// Expected block subsidy (staker + masternode) at a given height, in satoshis
+// Budget superblock payments are not part of this value
+pub fn block_value(height: i32) -> i64 {
+    match height {
+        0 => 60001 * COIN,
+        1..=86399 => 250 * COIN,
+        86400..=151199 => 225 * COIN,
+        151200..=302399 => 45 * COIN, // PoW ends at 259200, PoS keeps the same reward
+        302400..=345599 => 405 * COIN / 10,
+        345600..=388799 => 36 * COIN,
+        388800..=431999 => 315 * COIN / 10,
+        432000..=475199 => 27 * COIN,
+        475200..=518399 => 225 * COIN / 10,
+        518400..=561599 => 18 * COIN,
+        561600..=604799 => 135 * COIN / 10,
+        604800..=647999 => 9 * COIN,
+        _ if height <= ZEROCOIN_BLOCK_V2_START => 45 * COIN / 10,
+        _ if height < V5_5_ACTIVATION_HEIGHT => 5 * COIN,
+        _ => 10 * COIN,
+    }
+}
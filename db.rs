@@ -0,0 +1,41 @@
+// RocksDB setup shared by every command
+pub mod codec;
+pub mod options;
+
+use std::io;
+
+use rocksdb::DB;
+
+use crate::COLUMN_FAMILIES;
+
+// On-disk size and estimated key count of a column family
+pub fn cf_usage(db: &DB, cf: &str) -> io::Result<(u64, u64)> {
+    let handle = db.cf_handle(cf).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No column family {}", cf)))?;
+    let property = |name: &str| db.property_int_value_cf(handle, name)
+        .map(|value| value.unwrap_or(0))
+        .map_err(|e| io::Error::other(e.to_string()));
+    Ok((property("rocksdb.total-sst-files-size")?, property("rocksdb.estimate-num-keys")?))
+}
+
+pub fn print_stats(db: &DB) -> io::Result<()> {
+    let mut total = 0;
+    println!("{:<16} {:>14} {:>14}", "column family", "bytes", "keys (est.)");
+    for cf in COLUMN_FAMILIES.iter() {
+        let (bytes, keys) = cf_usage(db, cf)?;
+        total += bytes;
+        println!("{:<16} {:>14} {:>14}", cf, bytes, keys);
+    }
+    println!("{:<16} {:>14}", "total", total);
+    Ok(())
+}
+
+// Rewrite every file of a column family with its current options, e.g. to
+// train and apply the zstd dictionary to already indexed transactions
+pub fn compact(db: &DB, cf: &str) -> io::Result<()> {
+    let (before, _) = cf_usage(db, cf)?;
+    let handle = db.cf_handle(cf).expect("column family checked by cf_usage");
+    db.compact_range_cf(handle, None::<&[u8]>, None::<&[u8]>);
+    let (after, _) = cf_usage(db, cf)?;
+    println!("{}: {} bytes before, {} bytes after compaction", cf, before, after);
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Column families holding large values (raw transactions, address UTXO lists)
+// that can be compressed before they are written
+pub const COMPRESSIBLE_CFS: [&str; 2] = ["transactions", "addr_index"];
+
+// Values shorter than this are stored as they are
+const MIN_COMPRESS_SIZE: usize = 256;
+
+// Compressed values start with MAGIC, the algorithm byte and the uncompressed
+// length as u32 LE. Values without it are legacy values and read as they are.
+const MAGIC: [u8; 3] = [0xfe, b'c', b'z'];
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+const ALGORITHM_LZ4: u8 = 1;
+const ALGORITHM_ZSTD: u8 = 2;
+const ZSTD_LEVEL: i32 = 3;
+// Larger lengths in a header mean it is not a header
+const MAX_DECODED_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression '{}', expected none, lz4 or zstd", s)),
+        }
+    }
+}
+
+static POLICIES: OnceLock<HashMap<String, Compression>> = OnceLock::new();
+
+// Set the per column family policy once at startup
+pub fn configure(policies: HashMap<String, Compression>) {
+    let _ = POLICIES.set(policies);
+}
+
+pub fn policy(cf: &str) -> Compression {
+    POLICIES.get().and_then(|policies| policies.get(cf)).copied().unwrap_or(Compression::None)
+}
+
+// Value as written to `cf` under its policy
+pub fn encode<'a>(cf: &str, value: &'a [u8]) -> Cow<'a, [u8]> {
+    let (algorithm, compressed) = match policy(cf) {
+        _ if value.len() < MIN_COMPRESS_SIZE => return Cow::Borrowed(value),
+        Compression::None => return Cow::Borrowed(value),
+        Compression::Lz4 => (ALGORITHM_LZ4, lz4_flex::block::compress(value)),
+        Compression::Zstd => match zstd::bulk::compress(value, ZSTD_LEVEL) {
+            Ok(compressed) => (ALGORITHM_ZSTD, compressed),
+            Err(_) => return Cow::Borrowed(value),
+        },
+    };
+    // Not worth the header
+    if compressed.len() + HEADER_LEN >= value.len() {
+        return Cow::Borrowed(value);
+    }
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN + compressed.len());
+    encoded.extend_from_slice(&MAGIC);
+    encoded.push(algorithm);
+    encoded.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&compressed);
+    Cow::Owned(encoded)
+}
+
+// Original bytes of a stored value, compressed or not. A legacy value that
+// merely starts like a header fails to decompress and is returned as it is.
+pub fn decode(value: Vec<u8>) -> Vec<u8> {
+    if value.len() < HEADER_LEN || value[..MAGIC.len()] != MAGIC {
+        return value;
+    }
+    let algorithm = value[MAGIC.len()];
+    let length = u32::from_le_bytes(value[MAGIC.len() + 1..HEADER_LEN].try_into().unwrap()) as usize;
+    if length > MAX_DECODED_LEN {
+        return value;
+    }
+    let body = &value[HEADER_LEN..];
+    let decoded = match algorithm {
+        ALGORITHM_LZ4 => lz4_flex::block::decompress(body, length).ok(),
+        ALGORITHM_ZSTD => zstd::bulk::decompress(body, length).ok(),
+        _ => None,
+    };
+    match decoded {
+        Some(decoded) if decoded.len() == length => decoded,
+        _ => value,
+    }
+}
@@ -0,0 +1,116 @@
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, Options};
+
+// Column families mostly queried by key, often for keys that were never written
+// (addresses without history, inputs spending unindexed outputs)
+const POINT_LOOKUP_CFS: [&str; 6] = ["transactions", "addr_index", "utxo", "pubkey", "addr_balances", "spent"];
+
+// Unit of every _mb setting in config.toml
+pub const MB: usize = 1024 * 1024;
+
+// Starting points for `[db] profile` in config.toml
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    LowMemory,
+    Balanced,
+    HighThroughput,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low-memory" => Ok(Profile::LowMemory),
+            "balanced" => Ok(Profile::Balanced),
+            "high-throughput" => Ok(Profile::HighThroughput),
+            _ => Err(format!("Unknown db.profile '{}', expected low-memory, balanced or high-throughput", s)),
+        }
+    }
+}
+
+// RocksDB knobs a profile sets; each can be overridden individually in config
+#[derive(Debug, Clone)]
+pub struct DbTuning {
+    // Memtable size per column family
+    pub write_buffer_size: usize,
+    pub max_write_buffer_number: i32,
+    pub max_background_jobs: i32,
+    // -1 keeps every table file open
+    pub max_open_files: i32,
+    // Block cache shared by all column families
+    pub block_cache_size: usize,
+    // zstd dictionary size for the transactions column family, 0 keeps the default compression
+    pub zstd_dictionary_size: usize,
+}
+
+impl Profile {
+    pub fn tuning(self) -> DbTuning {
+        match self {
+            Profile::LowMemory => DbTuning {
+                write_buffer_size: 16 * MB,
+                max_write_buffer_number: 2,
+                max_background_jobs: 2,
+                max_open_files: 256,
+                block_cache_size: 32 * MB,
+                zstd_dictionary_size: 0,
+            },
+            Profile::Balanced => DbTuning {
+                write_buffer_size: 64 * MB,
+                max_write_buffer_number: 3,
+                max_background_jobs: 4,
+                max_open_files: 1024,
+                block_cache_size: 256 * MB,
+                zstd_dictionary_size: 0,
+            },
+            Profile::HighThroughput => DbTuning {
+                write_buffer_size: 256 * MB,
+                max_write_buffer_number: 4,
+                max_background_jobs: 8,
+                max_open_files: -1,
+                block_cache_size: 1024 * MB,
+                zstd_dictionary_size: 0,
+            },
+        }
+    }
+}
+
+impl Default for DbTuning {
+    fn default() -> Self {
+        Profile::Balanced.tuning()
+    }
+}
+
+// Database wide options
+pub fn db_options(tuning: &DbTuning) -> Options {
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    options.set_max_background_jobs(tuning.max_background_jobs);
+    options.set_max_open_files(tuning.max_open_files);
+    options
+}
+
+// Options for one column family, all sharing `cache`
+pub fn cf_options(cf: &str, tuning: &DbTuning, cache: &Cache) -> Options {
+    let mut options = Options::default();
+    options.set_write_buffer_size(tuning.write_buffer_size);
+    options.set_max_write_buffer_number(tuning.max_write_buffer_number);
+
+    let mut table_options = BlockBasedOptions::default();
+    table_options.set_block_cache(cache);
+    if POINT_LOOKUP_CFS.contains(&cf) {
+        // Bloom filters let misses skip reading data blocks
+        table_options.set_bloom_filter(10.0, false);
+    }
+    options.set_block_based_table_factory(&table_options);
+
+    // Raw transactions share a lot of structure across records but little within
+    // one block, so a dictionary trained on samples compresses them much better
+    if cf == "transactions" && tuning.zstd_dictionary_size > 0 {
+        let dictionary_size = tuning.zstd_dictionary_size as i32;
+        options.set_compression_type(DBCompressionType::Zstd);
+        options.set_compression_options(-14, 3, 0, dictionary_size);
+        options.set_zstd_max_train_bytes(dictionary_size * 100);
+    }
+    options
+}
@@ -0,0 +1,351 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use crate::consensus;
+use crate::parser::tx::{merkle_root, sha256d};
+
+// First block time of a generated chain; each block is TARGET_SPACING later
+const GENESIS_TIME: u32 = 1_454_124_731;
+// Every this many PoS blocks also carries a transaction shielding a UTXO
+const SHIELD_TX_INTERVAL: u32 = 5;
+const SHIELD_FEE: i64 = consensus::COIN / 100;
+
+// Shape of a generated chain. The same spec always produces the same bytes.
+pub struct FixtureSpec {
+    pub pow_blocks: u32,
+    pub pos_blocks: u32,
+}
+
+struct FixtureIn {
+    prev_txid: [u8; 32],
+    vout: u32,
+    script_sig: Vec<u8>,
+}
+
+struct FixtureOut {
+    value: i64,
+    script: Vec<u8>,
+}
+
+// A spendable output of an earlier generated transaction
+struct Spendable {
+    txid: [u8; 32],
+    vout: u32,
+    value: i64,
+}
+
+// Build a miniature PIVX-like chain: PoW blocks paying P2PKH coinbases, then
+// PoS blocks whose coinstakes stake earlier outputs into cold staking scripts,
+// with a shielding transaction every few PoS blocks. Returns serialized blocks.
+pub fn generate(spec: &FixtureSpec) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut prev_hash = [0u8; 32];
+    let mut spendable = VecDeque::new();
+
+    for height in 0..spec.pow_blocks + spec.pos_blocks {
+        let mut txs = Vec::new();
+        let subsidy = consensus::block_value(height as i32);
+        if height < spec.pow_blocks {
+            let coinbase = serialize_tx(1, &[coinbase_input(height)], &[FixtureOut { value: subsidy, script: p2pkh(height % 4) }], None);
+            spendable.push_back(Spendable { txid: txid(&coinbase), vout: 0, value: subsidy });
+            txs.push(coinbase);
+        } else {
+            // PoS coinbases are empty, the reward goes through the coinstake
+            txs.push(serialize_tx(1, &[coinbase_input(height)], &[FixtureOut { value: 0, script: Vec::new() }], None));
+
+            let stake = spendable.pop_front().expect("generated chain always has a stakeable output");
+            let staked = stake.value + subsidy;
+            let coinstake = serialize_tx(1, &[spend(&stake)], &[
+                FixtureOut { value: 0, script: Vec::new() },
+                FixtureOut { value: staked, script: cold_stake(height % 4, height % 3) },
+            ], None);
+            spendable.push_back(Spendable { txid: txid(&coinstake), vout: 1, value: staked });
+            txs.push(coinstake);
+
+            let pos_index = height - spec.pow_blocks + 1;
+            if pos_index.is_multiple_of(SHIELD_TX_INTERVAL) && spendable.len() > 1 {
+                let shielded = spendable.pop_front().unwrap();
+                // A negative value balance moves the input, less the fee, into the shield pool
+                let value_balance = SHIELD_FEE - shielded.value;
+                txs.push(serialize_tx(3, &[spend(&shielded)], &[], Some((value_balance, height))));
+            }
+        }
+
+        let txids: Vec<Vec<u8>> = txs.iter().map(|tx| txid(tx).to_vec()).collect();
+        let mut merkle = [0u8; 32];
+        merkle.copy_from_slice(&merkle_root(&txids));
+
+        let header = serialize_header(height, spec.pow_blocks, &prev_hash, &merkle);
+        prev_hash.copy_from_slice(&sha256d(&header));
+
+        let mut block = header;
+        write_compact_size(&mut block, txs.len() as u64);
+        for tx in &txs {
+            block.extend_from_slice(tx);
+        }
+        blocks.push(block);
+    }
+    blocks
+}
+
+// Write blocks framed as in a node's blk files: magic, u32 LE size, block
+pub fn write_blk_file(path: &Path, magic: [u8; 4], blocks: &[Vec<u8>]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for block in blocks {
+        file.write_all(&magic)?;
+        file.write_u32::<LittleEndian>(block.len() as u32)?;
+        file.write_all(block)?;
+    }
+    file.flush()
+}
+
+// PoW blocks use 80 byte version 3 headers, PoS blocks 112 byte version 10
+// headers ending in a (dummy) final sapling root
+fn serialize_header(height: u32, pow_blocks: u32, prev_hash: &[u8; 32], merkle: &[u8; 32]) -> Vec<u8> {
+    let version: u32 = if height < pow_blocks { 3 } else { 10 };
+    let mut header = Vec::with_capacity(112);
+    header.write_u32::<LittleEndian>(version).unwrap();
+    header.extend_from_slice(prev_hash);
+    header.extend_from_slice(merkle);
+    header.write_u32::<LittleEndian>(GENESIS_TIME + height * consensus::TARGET_SPACING as u32).unwrap();
+    header.write_u32::<LittleEndian>(0x1e0ffff0).unwrap();
+    header.write_u32::<LittleEndian>(height).unwrap();
+    if version >= 8 {
+        header.extend_from_slice(&seeded(b"sapling root", height));
+    }
+    header
+}
+
+// version, type 0, inputs, outputs, lock time, then for Sapling versions the
+// optional sapling data: value balance, no spends, one dummy output, binding sig
+fn serialize_tx(version: u16, inputs: &[FixtureIn], outputs: &[FixtureOut], sapling: Option<(i64, u32)>) -> Vec<u8> {
+    let mut tx = Vec::new();
+    tx.write_u16::<LittleEndian>(version).unwrap();
+    tx.write_u16::<LittleEndian>(0).unwrap();
+
+    write_compact_size(&mut tx, inputs.len() as u64);
+    for input in inputs {
+        tx.extend_from_slice(&input.prev_txid);
+        tx.write_u32::<LittleEndian>(input.vout).unwrap();
+        write_compact_size(&mut tx, input.script_sig.len() as u64);
+        tx.extend_from_slice(&input.script_sig);
+        tx.write_u32::<LittleEndian>(0xffffffff).unwrap();
+    }
+
+    write_compact_size(&mut tx, outputs.len() as u64);
+    for output in outputs {
+        tx.write_i64::<LittleEndian>(output.value).unwrap();
+        write_compact_size(&mut tx, output.script.len() as u64);
+        tx.extend_from_slice(&output.script);
+    }
+    tx.write_u32::<LittleEndian>(0).unwrap();
+
+    if let Some((value_balance, height)) = sapling {
+        tx.push(1);
+        tx.write_i64::<LittleEndian>(value_balance).unwrap();
+        write_compact_size(&mut tx, 0);
+        write_compact_size(&mut tx, 1);
+        tx.extend_from_slice(&seeded(b"cv", height));
+        tx.extend_from_slice(&seeded(b"cmu", height));
+        tx.extend_from_slice(&seeded(b"epk", height));
+        tx.extend_from_slice(&[0u8; 580 + 80 + 192]);
+        tx.extend_from_slice(&[0u8; 64]);
+    }
+    tx
+}
+
+fn coinbase_input(height: u32) -> FixtureIn {
+    let mut script_sig = vec![0x04];
+    script_sig.extend_from_slice(&height.to_le_bytes());
+    FixtureIn { prev_txid: [0u8; 32], vout: u32::MAX, script_sig }
+}
+
+fn spend(output: &Spendable) -> FixtureIn {
+    FixtureIn { prev_txid: output.txid, vout: output.vout, script_sig: vec![0x00] }
+}
+
+// OP_DUP OP_HASH160 <20> OP_EQUALVERIFY OP_CHECKSIG
+fn p2pkh(key: u32) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(&seeded(b"owner", key)[..20]);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+// OP_DUP OP_HASH160 OP_ROT OP_IF OP_CHECKCOLDSTAKEVERIFY_LOF <staker> OP_ELSE <owner> OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG
+fn cold_stake(owner: u32, staker: u32) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x7b, 0x63, 0xd1, 0x14];
+    script.extend_from_slice(&seeded(b"staker", staker)[..20]);
+    script.extend_from_slice(&[0x67, 0x14]);
+    script.extend_from_slice(&seeded(b"owner", owner)[..20]);
+    script.extend_from_slice(&[0x68, 0x88, 0xac]);
+    script
+}
+
+// Stand-in for keys, hashes and roots, derived from a label and an index
+fn seeded(label: &[u8], index: u32) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(label);
+    hasher.update(index.to_le_bytes());
+    hasher.finalize().into()
+}
+
+// Internal byte order, as referenced by outpoints and the merkle tree
+fn txid(tx: &[u8]) -> [u8; 32] {
+    let mut txid = [0u8; 32];
+    txid.copy_from_slice(&sha256d(tx));
+    txid
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.write_u16::<LittleEndian>(n as u16).unwrap();
+        }
+        0x10000..=0xffffffff => {
+            buf.push(0xfe);
+            buf.write_u32::<LittleEndian>(n as u32).unwrap();
+        }
+        _ => {
+            buf.push(0xff);
+            buf.write_u64::<LittleEndian>(n).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::DB;
+
+    use super::*;
+    use crate::chain_params::{self, ChainParams, Network};
+    use crate::db::options::DbTuning;
+    use crate::parser::address::hash_address;
+    use crate::parser::stream::RawBlock;
+    use crate::progress::{FileProgress, ProgressSink};
+    use crate::{stakers, IndexSettings, Role};
+
+    struct NoProgress;
+
+    impl ProgressSink for NoProgress {
+        fn block_indexed(&mut self, _progress: &FileProgress) {}
+        fn file_finished(&mut self, _progress: &FileProgress) {}
+    }
+
+    // Regtest with the generated genesis block, the same for every spec
+    fn fixture_params() -> &'static ChainParams {
+        let genesis = generate(&FixtureSpec { pow_blocks: 1, pos_blocks: 0 }).remove(0);
+        let hash = RawBlock::from_data(0, genesis).unwrap().header.block_hash;
+        let mut params = chain_params::params_for(Network::Regtest).clone();
+        params.genesis_hash = hex::encode(hash.iter().rev().cloned().collect::<Vec<u8>>()).leak();
+        params.genesis_merkle_root = None;
+        chain_params::select(params)
+    }
+
+    // Write the chain to a blk file, index it without a block index, so heights
+    // come from the blocks' parent links, and run `check` on the database
+    fn with_synced_fixture(name: &str, spec: &FixtureSpec, check: impl FnOnce(&DB)) {
+        let params = fixture_params();
+        let dir = std::env::temp_dir().join(format!("rustyblox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let blk_file = dir.join("blk00000.dat");
+        write_blk_file(&blk_file, params.magic, &generate(spec)).unwrap();
+
+        // Closed before its files are removed
+        {
+            let db = crate::open_db(&dir.join("db"), &DbTuning::default()).unwrap();
+            let settings = IndexSettings {
+                role: Role::Full,
+                dust_relay_fee: crate::DEFAULT_DUST_RELAY_FEE,
+                ldb_dir: None,
+                min_free_disk_bytes: 0,
+                tag_unspendable: false,
+                index_text: false,
+            };
+            assert!(crate::process_blk_file(&blk_file, &db, &settings, &mut NoProgress).unwrap());
+            check(&db);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn owner_address(key: u32) -> String {
+        hash_address(&seeded(b"owner", key)[..20], chain_params::params().pubkey_address_prefix)
+    }
+
+    fn staker_address(key: u32) -> String {
+        hash_address(&seeded(b"staker", key)[..20], chain_params::params().staking_address_prefix)
+    }
+
+    fn balance(db: &DB, address: &str) -> i64 {
+        crate::address_summary(db, address, crate::DEFAULT_DUST_RELAY_FEE).unwrap().balance
+    }
+
+    fn best_height(db: &DB) -> i32 {
+        let cf_state = db.cf_handle("chain_state").unwrap();
+        let value = db.get_cf(cf_state, b"best_height").unwrap().unwrap();
+        i32::from_le_bytes(value[..4].try_into().unwrap())
+    }
+
+    // Heights 0-2 pay 60001, 250 and 250 PIV to owners 0-2. Height 3 stakes
+    // owner 0's coinbase into a cold stake for owner 3 with staker 0, height 4
+    // stakes owner 1's for owner 0 with staker 1, each adding 250 PIV.
+    #[test]
+    fn fixture_balances_and_stakers() {
+        with_synced_fixture("balances", &FixtureSpec { pow_blocks: 3, pos_blocks: 2 }, |db| {
+            let coin = consensus::COIN;
+            assert_eq!(best_height(db), 4);
+            assert!(crate::merkle_mismatches(db).unwrap().is_empty());
+
+            assert_eq!(balance(db, &owner_address(0)), 500 * coin);
+            assert_eq!(balance(db, &owner_address(1)), 0);
+            assert_eq!(balance(db, &owner_address(2)), 250 * coin);
+            assert_eq!(balance(db, &owner_address(3)), 60251 * coin);
+            // Cold staked outputs also count toward their staker
+            assert_eq!(balance(db, &staker_address(0)), 60251 * coin);
+            assert_eq!(balance(db, &staker_address(1)), 500 * coin);
+
+            assert_eq!(stakers::staked_by(db, 2).unwrap(), None);
+            assert_eq!(stakers::staked_by(db, 3).unwrap(), Some(staker_address(0)));
+            assert_eq!(stakers::staked_by(db, 4).unwrap(), Some(staker_address(1)));
+            let rows = stakers::top(db, None, 10).unwrap();
+            assert_eq!(rows.len(), 2);
+            for row in &rows {
+                assert_eq!(row.blocks, 1);
+                assert_eq!(row.rewards, 250 * coin);
+            }
+        });
+    }
+
+    // Longer chain with a shielding transaction every SHIELD_TX_INTERVAL PoS blocks
+    #[test]
+    fn fixture_heights_and_merkle_roots() {
+        let spec = FixtureSpec { pow_blocks: 10, pos_blocks: 20 };
+        with_synced_fixture("merkle", &spec, |db| {
+            let blocks = generate(&spec);
+            assert_eq!(best_height(db), blocks.len() as i32 - 1);
+            let cf_blocks = db.cf_handle("blocks").unwrap();
+            for (height, block) in blocks.into_iter().enumerate() {
+                let header = RawBlock::from_data(0, block).unwrap().header;
+                let mut key = vec![b'h'];
+                key.extend_from_slice(&(height as i32).to_le_bytes());
+                assert_eq!(db.get_cf(cf_blocks, &key).unwrap().as_deref(), Some(&header.block_hash[..]));
+            }
+            assert!(crate::merkle_mismatches(db).unwrap().is_empty());
+
+            for height in spec.pow_blocks..spec.pow_blocks + spec.pos_blocks {
+                assert_eq!(stakers::staked_by(db, height as i32).unwrap(), Some(staker_address(height % 3)));
+            }
+            let shield_txs = spec.pos_blocks / SHIELD_TX_INTERVAL;
+            let tip = crate::shield_info_at(db, best_height(db)).unwrap().unwrap();
+            assert_eq!(tip.tree_size, shield_txs as u64);
+        });
+    }
+}
@@ -0,0 +1,46 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use rocksdb::DB;
+
+use crate::db::options::MB;
+use crate::shutdown;
+
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Block until the database volume has at least `min_free_bytes` available.
+// While waiting, `disk_space_low` is set in chain_state so the condition is
+// visible from outside; it is cleared once there is room again. Returns
+// early when a shutdown is requested.
+pub fn wait_for_space(db: &DB, min_free_bytes: u64) -> io::Result<()> {
+    if min_free_bytes == 0 {
+        return Ok(());
+    }
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+
+    let mut paused = false;
+    loop {
+        let available = fs2::available_space(db.path())?;
+        if available >= min_free_bytes {
+            if paused {
+                println!("Free disk space back to {} MB, resuming sync", available / MB as u64);
+                db.delete_cf(cf_state, b"disk_space_low")
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+            }
+            return Ok(());
+        }
+
+        if !paused {
+            eprintln!("Only {} MB free on the database volume (minimum {} MB), pausing sync",
+                available / MB as u64, min_free_bytes / MB as u64);
+            db.put_cf(cf_state, b"disk_space_low", available.to_le_bytes())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            paused = true;
+        }
+        if shutdown::requested() {
+            return Ok(());
+        }
+        thread::sleep(RECHECK_INTERVAL);
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocksdb::{Direction, IteratorMode, DB};
+
+use crate::block_commit::BlockCommit;
+use serde::{Deserialize, Serialize};
+
+// Kinds of sync events kept in the events column family
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    PhaseStart,
+    PhaseFinish,
+    Migration,
+    Repair,
+    IssuanceDivergence,
+}
+
+impl FromStr for EventType {
+    type Err = String;
+
+    // Same names as in the stored events
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("Unknown event type '{}'", s))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncEvent {
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub detail: String,
+}
+
+// Event keyed by its timestamp in nanoseconds (big endian, so keys sort by time)
+fn new_event(event_type: EventType, detail: String) -> io::Result<([u8; 8], Vec<u8>)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let event = SyncEvent {
+        timestamp: now.as_secs(),
+        event_type,
+        detail,
+    };
+    Ok(((now.as_nanos() as u64).to_be_bytes(), serde_json::to_vec(&event)?))
+}
+
+// Append an event
+pub fn record_event(db: &DB, event_type: EventType, detail: impl Into<String>) -> io::Result<()> {
+    let cf_events = db.cf_handle("events").expect("Events column family not found");
+    let (key, value) = new_event(event_type, detail.into())?;
+    db.put_cf(cf_events, key, value)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Append an event found while indexing a block, with the block's writes
+pub fn append(commit: &mut BlockCommit, event_type: EventType, detail: impl Into<String>) -> io::Result<()> {
+    let (key, value) = new_event(event_type, detail.into())?;
+    commit.put_cf("events", &key, &value);
+    Ok(())
+}
+
+// Events from `from` (unix seconds) onward, oldest first, optionally of one type
+pub fn query(db: &DB, from: u64, event_type: Option<EventType>) -> io::Result<Vec<SyncEvent>> {
+    let cf_events = db.cf_handle("events").expect("Events column family not found");
+    let start = from.saturating_mul(1_000_000_000).to_be_bytes();
+    let mut events = Vec::new();
+    for item in db.iterator_cf(cf_events, IteratorMode::From(&start, Direction::Forward)) {
+        let (_, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        let event: SyncEvent = match serde_json::from_slice(&value) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if event_type.is_none_or(|event_type| event.event_type == event_type) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,81 @@
+use std::io;
+
+use rocksdb::{IteratorMode, DB};
+use serde::Serialize;
+
+use crate::{consensus, BlockStats};
+
+// Recent blocks whose fee rates feed an estimate
+const FEE_WINDOW: i32 = 200;
+// PIVX Core's default minimum relay fee, the floor of every estimate (sat/kB)
+const MIN_RELAY_FEERATE: i64 = 10_000;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FeeEstimate {
+    pub blocks: u32,
+    // sat/kB
+    pub feerate: i64,
+    // PIV/kB, as Blockbook's estimatefee returns it
+    pub result: String,
+    // Fee paying blocks the estimate was computed from
+    pub sample_blocks: usize,
+}
+
+// Highest indexed block, from chain_state "best_height" or, for databases
+// indexed before it was kept, the stats column family
+fn best_height(db: &DB) -> io::Result<Option<i32>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    if let Some(value) = db.get_cf(cf_state, b"best_height").map_err(to_io)? {
+        return Ok(Some(i32::from_le_bytes(value[..4].try_into().unwrap())));
+    }
+    let cf_stats = db.cf_handle("stats").expect("Stats column family not found");
+    let mut best = None;
+    for item in db.iterator_cf(cf_stats, IteratorMode::Start) {
+        let (key, _) = item.map_err(to_io)?;
+        if let Ok(bytes) = key[..].try_into() {
+            best = best.max(Some(i32::from_le_bytes(bytes)));
+        }
+    }
+    Ok(best)
+}
+
+// Fee rate likely to confirm within `blocks` blocks, from the median fee rates
+// of recent blocks: a higher percentile of them for a nearer target
+pub fn estimate(db: &DB, blocks: u32) -> io::Result<FeeEstimate> {
+    let cf_stats = db.cf_handle("stats").expect("Stats column family not found");
+    let best = best_height(db)?.unwrap_or(0);
+
+    let mut feerates = Vec::new();
+    let mut undecodable = 0;
+    for height in (best - FEE_WINDOW + 1).max(0)..=best {
+        let data = match db.get_cf(cf_stats, height.to_le_bytes()).map_err(|e| io::Error::other(e.to_string()))? {
+            Some(data) => data,
+            None => continue,
+        };
+        match BlockStats::decode(&data) {
+            // Rows from before fee rates were tracked decode with no fee paying transactions
+            Some(stats) if stats.fee_txs > 0 => feerates.push(stats.median_feerate),
+            Some(_) => {}
+            None => undecodable += 1,
+        }
+    }
+    if undecodable > 0 {
+        eprintln!("Skipped {} blocks whose stats could not be decoded", undecodable);
+    }
+    feerates.sort_unstable();
+
+    let percentile = 95u32.saturating_sub(5 * blocks.saturating_sub(1)).max(50) as usize;
+    let feerate = match feerates.len() {
+        0 => MIN_RELAY_FEERATE,
+        n => feerates[(n - 1) * percentile / 100].max(MIN_RELAY_FEERATE),
+    };
+
+    Ok(FeeEstimate {
+        blocks,
+        feerate,
+        result: format!("{}.{:08}", feerate / consensus::COIN, feerate % consensus::COIN),
+        sample_blocks: feerates.len(),
+    })
+}
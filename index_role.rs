@@ -0,0 +1,59 @@
+use std::io;
+
+use rocksdb::DB;
+
+use crate::cli::Command;
+use crate::Role;
+
+// Check the configured role against the one recorded in chain_state
+// "index_role" and return the role the database was indexed with, None for a
+// database that was never synced. The first sync records its role. Syncing
+// with another role would leave indexes that cover only part of the chain, so
+// it is refused; api-only only reads and works with any role.
+pub fn verify(db: &DB, configured: Role) -> io::Result<Option<Role>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+
+    let recorded = match db.get_cf(cf_state, b"index_role").map_err(to_io)? {
+        Some(value) => Some(String::from_utf8_lossy(&value).parse::<Role>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
+        None => None,
+    };
+    match recorded {
+        Some(recorded) if configured.syncs_blocks() && recorded != configured => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Database at {} was indexed with role {} but the config selects {}; use role = \"{}\" or \"api-only\", or another db_path",
+                db.path().display(), recorded.as_str(), configured.as_str(), recorded.as_str())))
+        }
+        Some(recorded) => Ok(Some(recorded)),
+        None if configured.syncs_blocks() => {
+            db.put_cf(cf_state, b"index_role", configured.as_str()).map_err(to_io)?;
+            Ok(Some(configured))
+        }
+        None => Ok(None),
+    }
+}
+
+// Refuse commands that read indexes the database's role doesn't build
+pub fn check_command(role: Option<Role>, command: &Command) -> Result<(), String> {
+    let role = match role {
+        Some(role) => role,
+        None => return Ok(()),
+    };
+    let (supported, indexes) = match command {
+        Command::Address { .. } | Command::Xpub { .. } | Command::RichList { .. } | Command::AddressTxs { .. }
+        | Command::UtxoAt { .. } | Command::BalanceHistory { .. } | Command::RepairAddress { .. } => {
+            (role.builds_address_index(), "address indexes")
+        }
+        Command::Stakers { .. } | Command::StakedBy { .. } | Command::StakeInfo { .. } | Command::EstimateFee { .. }
+        | Command::UtxoCommitment { .. } | Command::ShieldAnchors { .. } | Command::MerkleMismatches
+        | Command::SearchText { .. } => (role.parses_transactions(), "transaction data"),
+        Command::ExportBlockbook { .. } => (role.builds_tx_indexes(), "transaction indexes"),
+        _ => return Ok(()),
+    };
+    if supported {
+        Ok(())
+    } else {
+        Err(format!("This database was indexed with role {}, which keeps no {} for this command", role.as_str(), indexes))
+    }
+}
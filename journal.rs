@@ -0,0 +1,75 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use rocksdb::{Direction, IteratorMode, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::block_commit::BlockCommit;
+
+// Indexing events for external consumers, in the order they were applied
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEvent {
+    BlockConnected { height: i32, hash: String },
+    TxIndexed { txid: String, height: i32 },
+    // Value added to (positive) or removed from (negative) an address by an output
+    AddressDelta { address: String, txid: String, vout: u32, delta: i64 },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// Turn the journal on, continuing after the last stored sequence number
+pub fn enable(db: &DB) -> io::Result<()> {
+    let cf_journal = db.cf_handle("journal").expect("Journal column family not found");
+    let next = match db.iterator_cf(cf_journal, IteratorMode::End).next() {
+        Some(item) => {
+            let (key, _) = item.map_err(|e| io::Error::other(e.to_string()))?;
+            seq_from_key(&key) + 1
+        }
+        None => 0,
+    };
+    NEXT_SEQ.store(next, Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// Append an event keyed by sequence number (big endian, so keys sort in order)
+pub fn append(commit: &mut BlockCommit, event: JournalEvent) -> io::Result<()> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let entry = JournalEntry {
+        seq: NEXT_SEQ.fetch_add(1, Ordering::SeqCst),
+        event,
+    };
+    let value = serde_json::to_vec(&entry)?;
+    commit.put_cf("journal", &entry.seq.to_be_bytes(), &value);
+    Ok(())
+}
+
+// Entries with a sequence number of at least `from`, oldest first
+pub fn read_from(db: &DB, from: u64, limit: usize) -> io::Result<Vec<JournalEntry>> {
+    let cf_journal = db.cf_handle("journal").expect("Journal column family not found");
+    let start = from.to_be_bytes();
+    let mut entries = Vec::new();
+    for item in db.iterator_cf(cf_journal, IteratorMode::From(&start, Direction::Forward)).take(limit) {
+        let (_, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        entries.push(serde_json::from_slice(&value)?);
+    }
+    Ok(entries)
+}
+
+fn seq_from_key(key: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use rocksdb::{DB, IteratorMode, WriteBatch};
+use serde::{Deserialize, Serialize};
+
+// One row of the operator supplied labels file
+#[derive(Deserialize, Debug, PartialEq)]
+struct LabelRecord {
+    address: String,
+    label: String,
+    #[serde(default)]
+    category: String,
+    #[serde(default)]
+    url: String,
+}
+
+// Value stored in the labels column family, keyed by address
+#[derive(Serialize, Deserialize)]
+pub struct AddressLabel {
+    pub label: String,
+    pub category: String,
+    pub url: String,
+}
+
+// Labels file named in config, reloaded whenever its modification time changes
+pub struct LabelsFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl LabelsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LabelsFile { path: path.into(), modified: None }
+    }
+
+    pub fn refresh_if_changed(&mut self, db: &DB) -> Result<(), Box<dyn Error>> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(());
+        }
+
+        let count = load_labels(db, &self.path)?;
+        println!("Loaded {} address labels from {}", count, self.path.display());
+        self.modified = Some(modified);
+        Ok(())
+    }
+}
+
+// Replace the labels column family with the contents of a CSV or JSON file
+fn load_labels(db: &DB, path: &PathBuf) -> Result<usize, Box<dyn Error>> {
+    let records = parse_labels(path, &fs::read_to_string(path)?)?;
+
+    // Applied as one batch, so readers never see a half replaced set
+    let cf_labels = db.cf_handle("labels").expect("Labels column family not found");
+    let mut batch = WriteBatch::default();
+    let mut addresses = HashSet::new();
+    for record in records {
+        let label = AddressLabel {
+            label: record.label,
+            category: record.category,
+            url: record.url,
+        };
+        batch.put_cf(cf_labels, record.address.as_bytes(), serde_json::to_vec(&label)?);
+        addresses.insert(record.address.into_bytes());
+    }
+
+    // Drop labels that were removed from the file since the last load
+    for item in db.iterator_cf(cf_labels, IteratorMode::Start) {
+        let (key, _) = item?;
+        if !addresses.contains(key.as_ref()) {
+            batch.delete_cf(cf_labels, &key);
+        }
+    }
+    db.write(batch)?;
+
+    Ok(addresses.len())
+}
+
+// A .json file holds an array of records, anything else is CSV
+fn parse_labels(path: &Path, contents: &str) -> Result<Vec<LabelRecord>, Box<dyn Error>> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        Ok(serde_json::from_str(contents)?)
+    } else {
+        parse_labels_csv(contents)
+    }
+}
+
+// address,label,category,url per line; fields may not contain commas
+fn parse_labels_csv(contents: &str) -> Result<Vec<LabelRecord>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    let mut first_row = true;
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        // A header row, if any, is the first one after comments and blank lines
+        if std::mem::take(&mut first_row) && fields[0].eq_ignore_ascii_case("address") {
+            continue;
+        }
+        if fields.len() < 2 || fields[0].is_empty() {
+            return Err(format!("Invalid labels entry on line {}", line_number + 1).into());
+        }
+
+        records.push(LabelRecord {
+            address: fields[0].to_string(),
+            label: fields[1].to_string(),
+            category: fields.get(2).unwrap_or(&"").to_string(),
+            url: fields.get(3).unwrap_or(&"").to_string(),
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(address: &str, label: &str, category: &str, url: &str) -> LabelRecord {
+        LabelRecord { address: address.to_string(), label: label.to_string(), category: category.to_string(), url: url.to_string() }
+    }
+
+    #[test]
+    fn header_after_comments_is_skipped() {
+        let csv = "# exchange wallets\n\n  Address , Label,category,url\nDExch1, Exchange one ,exchange,https://one.example\n";
+        assert_eq!(parse_labels_csv(csv).unwrap(), vec![record("DExch1", "Exchange one", "exchange", "https://one.example")]);
+    }
+
+    #[test]
+    fn only_the_first_row_can_be_a_header() {
+        let csv = "DExch1,Exchange one\naddress,looks like a header\n";
+        assert_eq!(parse_labels_csv(csv).unwrap(), vec![
+            record("DExch1", "Exchange one", "", ""),
+            record("address", "looks like a header", "", ""),
+        ]);
+    }
+
+    #[test]
+    fn optional_fields_default_to_empty() {
+        let csv = "DPool1,Pool,mining\n# a comment between rows\nDDev1,Dev fund\n";
+        assert_eq!(parse_labels_csv(csv).unwrap(), vec![
+            record("DPool1", "Pool", "mining", ""),
+            record("DDev1", "Dev fund", "", ""),
+        ]);
+    }
+
+    #[test]
+    fn rows_need_an_address_and_a_label() {
+        for (csv, line) in [("DExch1\n", 1), ("# comment\naddress,label\nDExch1,ok\nDExch2\n", 4), (",no address\n", 1)] {
+            let error = parse_labels_csv(csv).err().unwrap_or_else(|| panic!("accepted {:?}", csv));
+            assert_eq!(error.to_string(), format!("Invalid labels entry on line {}", line));
+        }
+        // An empty label is still a label
+        assert_eq!(parse_labels_csv("DExch1,\n").unwrap(), vec![record("DExch1", "", "", "")]);
+    }
+
+    #[test]
+    fn json_by_extension() {
+        let json = r#"[{"address": "DExch1", "label": "Exchange one", "url": "https://one.example"}, {"address": "DDev1", "label": "Dev fund", "category": "fund"}]"#;
+        assert_eq!(parse_labels(Path::new("labels.json"), json).unwrap(), vec![
+            record("DExch1", "Exchange one", "", "https://one.example"),
+            record("DDev1", "Dev fund", "fund", ""),
+        ]);
+        // A label is required
+        assert!(parse_labels(Path::new("labels.json"), r#"[{"address": "DExch1"}]"#).is_err());
+        // Other extensions are read as CSV
+        assert_eq!(parse_labels(Path::new("labels.txt"), "DExch1,Exchange one").unwrap(), vec![record("DExch1", "Exchange one", "", "")]);
+    }
+}
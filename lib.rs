@@ -0,0 +1,5 @@
+// Block and transaction parsing without a database, shared by the indexer and
+// anything else that reads PIVX blk files
+pub mod chain_params;
+pub mod parser;
+pub mod scripts;
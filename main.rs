@@ -1,48 +1,66 @@
 use std::fs;
 use std::fs::File;
-use std::io::{self, BufRead, Read, Seek, SeekFrom, ErrorKind, Cursor};
+use std::io::{self, BufRead, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::fmt;
 use std::error::Error;
 use core::borrow::Borrow;
-use sha2::{Sha256, Digest};
-use ripemd160::{Ripemd160, Digest as Ripemd160Digest};
-use serde_json::{Value, json};
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use hex;
-use rocksdb::{DB, Options, ColumnFamilyDescriptor};
+use db::options::DbTuning;
+use rocksdb::{DB, Options, ColumnFamilyDescriptor, IteratorMode, Direction, Cache, Snapshot, WriteBatch};
 
-use bitcoin::consensus::encode::{Decodable, VarInt};
-use config::{Config, File as ConfigFile};
 use leveldb::database::Database;
 use leveldb::kv::KV;
 use leveldb::options::{Options as LevelDBOptions, ReadOptions as LevelDBReadOptions};
-struct Hash([u8; 32]);
-
-const PREFIX: [u8; 4] = [0x90, 0xc4, 0xfd, 0xe9];
-const MAX_PAYLOAD_SIZE: usize = 10000;
-
-#[derive(Clone)]
-enum AddressType {
-    CoinStakeTx,
-    CoinBaseTx,
-    Nonstandard,
-    P2PKH(String),
-    P2PK(String),
-    P2SH(String),
-    ZerocoinMint,
-    ZerocoinSpend,
-    ZerocoinPublicSpend,
-    Staking(String, String),
-    Sapling,
-}
+
+mod app_config;
+mod balance_history;
+mod blk_files;
+mod blockbook_export;
+mod block_commit;
+mod cli;
+mod commitment;
+mod consensus;
+mod db;
+mod devtools;
+mod disk_guard;
+mod events;
+mod fee_estimate;
+mod index_role;
+mod journal;
+mod labels;
+mod network_identity;
+mod p2p;
+mod progress;
+mod rich_list;
+mod scheduler;
+mod shutdown;
+mod stakers;
+mod text_search;
+mod tx_history;
+mod xpub;
+
+use block_commit::BlockCommit;
+use clap::Parser;
+use cli::{Cli, Command, DbCommand};
+use commitment::UtxoCommitment;
+use events::{record_event, EventType};
+use journal::JournalEvent;
+use parser::address::scriptpubkey_to_address;
+use parser::stream::{BlockStream, RawBlock};
+use parser::tx::{merkle_root, reverse_bytes, DecodedTx};
+use parser::types::{AddressType, CBlockHeader, CScript, CTxIn, CTxOut};
+use progress::{ConsoleProgress, FileProgress, ProgressSink};
+use rustyblox::{chain_params, parser, scripts};
+
+// Default dust relay feerate in satoshis per kB (3x the default min relay fee)
+const DEFAULT_DUST_RELAY_FEE: i64 = 30000;
 
 fn from_rocksdb_error(err: rocksdb::Error) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err.to_string())
+    io::Error::other(err.to_string())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -69,263 +87,793 @@ impl db_key::Key for Byte33 {
     }
 }
 
-impl fmt::LowerHex for Hash {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for byte in self.0.iter().rev() {
-            write!(f, "{:02x}", byte)?;
-        }
-        Ok(())
-    }
+// Unspent output as stored in the utxo column family
+pub struct UtxoEntry {
+    pub value: i64,
+    pub height: i32,
+    pub script_pubkey: Vec<u8>,
+}
+
+// What indexing a single transaction produced, used for per-block accounting
+#[derive(Default)]
+pub struct IndexedTx {
+    pub txid: Vec<u8>,
+    // Serialized size in bytes
+    pub size: u32,
+    pub value_in: i64,
+    // Sum of value * confirmations over the spent inputs (satoshi-blocks)
+    pub coin_age: i64,
+    pub outputs: Vec<CTxOut>,
+    pub is_coinstake: bool,
+    pub sapling_value_balance: i64,
+    pub shield_spends: u32,
+    pub shield_outputs: u32,
+    // Sapling anchors referenced by the shield spends
+    pub shield_anchors: Vec<Vec<u8>>,
 }
 
-pub struct CBlockHeader {
-    pub n_version: u32,
-    pub block_hash: [u8; 32],
-    pub block_height: Option<i32>,
-    pub hash_prev_block: [u8; 32],
-    pub hash_merkle_root: [u8; 32],
-    pub n_time: u32,
-    pub n_bits: u32,
-    pub n_nonce: u32,
-    pub n_accumulator_checkpoint: Option<[u8; 32]>,
-    pub hash_final_sapling_root: Option<[u8; 32]>,
+impl IndexedTx {
+    pub fn value_out(&self) -> i64 {
+        self.outputs.iter().map(|out| out.value).sum()
+    }
 }
 
-pub struct CTransaction {
-    pub version: i16,
-    pub inputs: Vec<CTxIn>,
-    pub outputs: Vec<CTxOut>,
-    pub lock_time: u32,
+// Which indexes this deployment maintains, from `role` in config.toml
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Full,
+    HeadersOnly,
+    ApiOnly,
+    Analytics,
+    Balances,
 }
 
-pub struct CTxIn {
-    pub prevout: Option<COutPoint>,
-    pub script_sig: CScript,
-    pub sequence: u32,
-    pub index: u64,
-    pub coinbase: Option<Vec<u8>>,
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Role::Full),
+            "headers-only" => Ok(Role::HeadersOnly),
+            "api-only" => Ok(Role::ApiOnly),
+            "analytics" => Ok(Role::Analytics),
+            "balances" => Ok(Role::Balances),
+            _ => Err(format!("Unknown role '{}', expected full, headers-only, api-only, analytics or balances", s)),
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct CTxOut {
-    pub value: i64,
-    pub script_length: i32,
-    pub script_pubkey: CScript,
-    pub index: u64,
-    pub address: Vec<String>,
+impl Role {
+    // Name as written in config.toml
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Full => "full",
+            Role::HeadersOnly => "headers-only",
+            Role::ApiOnly => "api-only",
+            Role::Analytics => "analytics",
+            Role::Balances => "balances",
+        }
+    }
+
+    // Whether blk files are read at all
+    pub fn syncs_blocks(self) -> bool {
+        self != Role::ApiOnly
+    }
+
+    // Whether transactions are parsed (UTXO set, issuance and block stats)
+    pub fn parses_transactions(self) -> bool {
+        matches!(self, Role::Full | Role::Analytics | Role::Balances)
+    }
+
+    // Whether the address UTXO and balance indexes are built
+    pub fn builds_address_index(self) -> bool {
+        matches!(self, Role::Full | Role::Balances)
+    }
+
+    // Whether the transaction, pubkey and spent indexes are built
+    pub fn builds_tx_indexes(self) -> bool {
+        self == Role::Full
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct COutPoint {
-    pub hash: String,
-    pub n: u32,
+// Settings shared by the blk file indexing path
+pub struct IndexSettings {
+    pub role: Role,
+    // Feerate used to classify dust outputs in the per-block stats
+    pub dust_relay_fee: i64,
+    // The node's block index, used to look up block heights; without it a
+    // block's height comes from its parent's
+    pub ldb_dir: Option<PathBuf>,
+    // Sync pauses while the database volume has less free space than this
+    pub min_free_disk_bytes: u64,
+    // Keep provably unspendable outputs out of the UTXO set and address indexes
+    pub tag_unspendable: bool,
+    // Index words found in OP_RETURN data for `search-text`
+    pub index_text: bool,
 }
 
-#[derive(Clone)]
-pub struct CScript {
-    pub script: Vec<u8>,
+// Running totals per address, stored in the addr_balances column family
+#[derive(Default, Serialize, Deserialize)]
+pub struct AddressBalance {
+    pub balance: i64,
+    pub received: i64,
+    pub sent: i64,
 }
 
+// Balance totals plus a summary of an address's unspent outputs
 #[derive(Serialize)]
-pub struct SaplingTxData {
-    pub value: i64,
-    pub vshield_spend: Vec<VShieldSpend>,
-    pub vshield_output: Vec<VShieldOutput>,
-    pub binding_sig: Vec<u8>,
+#[serde(rename_all = "camelCase")]
+pub struct AddressSummary {
+    pub address: String,
+    pub balance: i64,
+    pub received: i64,
+    pub sent: i64,
+    pub utxo_count: u32,
+    // Outputs worth less than the fee needed to spend them at dust_relay_fee
+    pub dust_utxo_count: u32,
+    pub largest_utxo: i64,
+}
+
+// Stored block stats start with this byte; bump it whenever fields change and
+// teach BlockStats::decode the previous layout
+const BLOCK_STATS_VERSION: u8 = 1;
+
+// Per-block size and output classification written to the stats column family
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BlockStats {
+    pub size: u32,
+    pub p2pkh_outputs: u32,
+    pub p2sh_outputs: u32,
+    pub p2pk_outputs: u32,
+    pub staking_outputs: u32,
+    pub zerocoin_outputs: u32,
+    pub nonstandard_outputs: u32,
+    pub empty_outputs: u32,
+    pub dust_outputs: u32,
+    // Nonstandard outputs that can never be spent, and the value they burn
+    pub unspendable_outputs: u32,
+    pub unspendable_value: i64,
+    // Net value moved into the shield pool (negative when unshielding dominates)
+    pub shield_net_flow: i64,
+    // Transactions with at least one shield spend or output
+    pub shield_txs: u32,
+    pub shield_spends: u32,
+    pub shield_outputs: u32,
+    // Fees paid by transactions other than the coinbase and coinstake
+    pub total_fees: i64,
+    pub fee_txs: u32,
+    // Feerates in satoshis per kB
+    pub min_feerate: i64,
+    pub median_feerate: i64,
+    pub max_feerate: i64,
+}
+
+impl BlockStats {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut data = vec![BLOCK_STATS_VERSION];
+        bincode::serialize_into(&mut data, self).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(data)
+    }
+
+    // Stats as stored by this or an earlier version, None for anything else
+    pub fn decode(data: &[u8]) -> Option<BlockStats> {
+        let current_len = bincode::serialized_size(&BlockStats::default()).ok()? as usize;
+        match data.split_first() {
+            Some((&BLOCK_STATS_VERSION, fields)) if fields.len() == current_len => bincode::deserialize(fields).ok(),
+            _ => BlockStats::decode_unversioned(data),
+        }
+    }
+
+    // Rows written before the version byte. Every field is fixed size, so the
+    // layout follows from the length: 32 bytes (output counts), 36 (+ size in
+    // front), 44 (+ shield flow), 56 (+ shield counts), 92 (+ fees) and 104
+    // (+ unspendable after the dust count, the current fields).
+    fn decode_unversioned(data: &[u8]) -> Option<BlockStats> {
+        let layout = [32, 36, 44, 56, 92, 104].iter().position(|len| *len == data.len())?;
+        let mut reader = data;
+        let read_u32 = |reader: &mut &[u8]| reader.read_u32::<LittleEndian>().ok();
+        let mut stats = BlockStats::default();
+        if layout >= 1 {
+            stats.size = read_u32(&mut reader)?;
+        }
+        stats.p2pkh_outputs = read_u32(&mut reader)?;
+        stats.p2sh_outputs = read_u32(&mut reader)?;
+        stats.p2pk_outputs = read_u32(&mut reader)?;
+        stats.staking_outputs = read_u32(&mut reader)?;
+        stats.zerocoin_outputs = read_u32(&mut reader)?;
+        stats.nonstandard_outputs = read_u32(&mut reader)?;
+        stats.empty_outputs = read_u32(&mut reader)?;
+        stats.dust_outputs = read_u32(&mut reader)?;
+        if layout >= 5 {
+            stats.unspendable_outputs = read_u32(&mut reader)?;
+            stats.unspendable_value = reader.read_i64::<LittleEndian>().ok()?;
+        }
+        if layout >= 2 {
+            stats.shield_net_flow = reader.read_i64::<LittleEndian>().ok()?;
+        }
+        if layout >= 3 {
+            stats.shield_txs = read_u32(&mut reader)?;
+            stats.shield_spends = read_u32(&mut reader)?;
+            stats.shield_outputs = read_u32(&mut reader)?;
+        }
+        if layout >= 4 {
+            stats.total_fees = reader.read_i64::<LittleEndian>().ok()?;
+            stats.fee_txs = read_u32(&mut reader)?;
+            stats.min_feerate = reader.read_i64::<LittleEndian>().ok()?;
+            stats.median_feerate = reader.read_i64::<LittleEndian>().ok()?;
+            stats.max_feerate = reader.read_i64::<LittleEndian>().ok()?;
+        }
+        Some(stats)
+    }
+
+    // Summarize the (fee, size) of every fee paying transaction in the block
+    pub fn record_fees(&mut self, fees: &[(i64, u32)]) {
+        let mut feerates: Vec<i64> = fees.iter().map(|(fee, size)| fee * 1000 / (*size).max(1) as i64).collect();
+        feerates.sort_unstable();
+        self.total_fees = fees.iter().map(|(fee, _)| fee).sum();
+        self.fee_txs = fees.len() as u32;
+        if let (Some(min), Some(max)) = (feerates.first(), feerates.last()) {
+            self.min_feerate = *min;
+            self.median_feerate = feerates[feerates.len() / 2];
+            self.max_feerate = *max;
+        }
+    }
+
+    pub fn record_output(&mut self, tx_out: &CTxOut, dust_relay_fee: i64) {
+        if tx_out.script_pubkey.script.is_empty() {
+            // Coinstake markers and PoS coinbase outputs
+            self.empty_outputs += 1;
+            return;
+        }
+
+        match scripts::script_type(&tx_out.script_pubkey.script) {
+            "pubkeyhash" => self.p2pkh_outputs += 1,
+            "scripthash" => self.p2sh_outputs += 1,
+            "pubkey" => self.p2pk_outputs += 1,
+            "coldstake" => self.staking_outputs += 1,
+            "zerocoinmint" | "zerocoinspend" | "zerocoinpublicspend" => {
+                self.zerocoin_outputs += 1;
+                return;
+            }
+            _ => {
+                self.nonstandard_outputs += 1;
+                if scripts::is_unspendable(&tx_out.script_pubkey.script) {
+                    self.unspendable_outputs += 1;
+                    self.unspendable_value += tx_out.value;
+                }
+                return;
+            }
+        }
+
+        if tx_out.value < dust_threshold(tx_out, dust_relay_fee) {
+            self.dust_outputs += 1;
+        }
+    }
 }
 
-#[derive(Serialize)]
-pub struct VShieldSpend {
-    pub cv: Vec<u8>,
-    pub anchor: Vec<u8>,
-    pub nullifier: Vec<u8>,
-    pub rk: Vec<u8>,
-    pub proof: Vec<u8>,
-    pub spend_auth_sig: Vec<u8>,
+// Staked input age and reward of a block's coinstake, stored in the blocks column family
+#[derive(Default, Serialize, Deserialize)]
+pub struct StakeInfo {
+    pub txid: Vec<u8>,
+    pub staked_value: i64,
+    // Value-weighted average confirmations of the staked inputs
+    pub age_blocks: i32,
+    pub coin_days: f64,
+    // Value created by the coinstake, including any masternode payment it carries
+    pub reward: i64,
+}
+
+impl StakeInfo {
+    pub fn from_coinstake(indexed: &IndexedTx) -> Self {
+        let age_blocks = if indexed.value_in > 0 { indexed.coin_age / indexed.value_in } else { 0 };
+        StakeInfo {
+            txid: indexed.txid.clone(),
+            staked_value: indexed.value_in,
+            age_blocks: age_blocks as i32,
+            coin_days: indexed.coin_age as f64 * consensus::TARGET_SPACING as f64 / 86400.0 / consensus::COIN as f64,
+            reward: indexed.value_out() - indexed.value_in,
+        }
+    }
 }
 
-#[derive(Serialize)]
-pub struct VShieldOutput {
-    pub cv: Vec<u8>,
-    pub cmu: Vec<u8>,
-    pub ephemeral_key: Vec<u8>,
-    pub enc_ciphertext: Vec<u8>,
-    pub out_ciphertext: Vec<u8>,
-    pub proof: Vec<u8>,
+// Sapling note commitment tree state after a block, stored in the shield column family
+#[derive(Default, Serialize, Deserialize)]
+pub struct ShieldBlockInfo {
+    // Number of note commitments in the tree
+    pub tree_size: u64,
+    // Tree root after the block (hashFinalSaplingRoot), the anchor spends can use
+    pub final_root: Vec<u8>,
+    // Distinct anchors referenced by the block's shield spends
+    pub anchors: Vec<Vec<u8>>,
 }
 
-impl std::fmt::Debug for CScript {
-    // Formatting for CScript
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", hex::encode(&self.script))
+const COLUMN_FAMILIES: [&str; 17] = [
+    "blocks", "transactions",
+    "addr_index", "utxo",
+    "chain_metadata", "pubkey",
+    "chain_state", "stats",
+    "labels", "addr_balances",
+    "events", "spent",
+    "journal", "shield",
+    "addr_history", "blk_files",
+    "stakers",
+];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    if let Some(Command::GenFixture { out, pow_blocks, pos_blocks }) = &cli.command {
+        let blocks = devtools::generate(&devtools::FixtureSpec { pow_blocks: *pow_blocks, pos_blocks: *pos_blocks });
+        devtools::write_blk_file(out, chain_params::params().magic, &blocks)?;
+        println!("Wrote {} blocks to {}", blocks.len(), out.display());
+        if let Some(genesis) = blocks.first() {
+            // What [chain] genesis_hash needs to be to index the file
+            let header = RawBlock::from_data(0, genesis.clone())?.header;
+            println!("Genesis block {}", hex::encode(header.block_hash.iter().rev().cloned().collect::<Vec<u8>>()));
+        }
+        return Ok(());
     }
-}
 
-impl std::fmt::Debug for CBlockHeader {
-    // Formatting for CBlockHeader
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Block Header {{")?;
-        if let Some(block_height) = &self.block_height {
-            writeln!(f, "Block Height: {}", block_height)?;
-        } else {
-            writeln!(f, "Block Height: None")?;
-        }
-        writeln!(f, "Block Version: {}", self.n_version)?;
-        writeln!(f, "Previous Block Hash: {:x}", Hash(self.hash_prev_block))?;
-        writeln!(f, "Merkle Root: {:x}", Hash(self.hash_merkle_root))?;
-        writeln!(f, "Block Time: {}", self.n_time)?;
-        writeln!(f, "Block Bits: {:x}", self.n_bits)?;
-        writeln!(f, "Block Nonce: {:?}", self.n_nonce)?;
-        if let Some(accumulator_checkpoint) = &self.n_accumulator_checkpoint {
-            writeln!(f, "Accumulator Checkpoint: {:?}", hex::encode(accumulator_checkpoint))?;
-        } else {
-            writeln!(f, "Accumulator Checkpoint: None")?;
+    // Load and validate the configuration file before touching anything
+    let app_config = match app_config::load(&cli.config) {
+        Ok(app_config) => app_config,
+        Err(errors) => {
+            eprintln!("Invalid {}:", cli.config);
+            for error in &errors {
+                eprintln!("  - {}", error);
+            }
+            return Err(format!("{} configuration error(s)", errors.len()).into());
         }
+    };
 
-        if let Some(final_sapling_root) = &self.hash_final_sapling_root {
-            writeln!(f, "Final Sapling Root: {:x}", Hash(*final_sapling_root))?;
-        } else {
-            writeln!(f, "Final Sapling Root: None")?;
+    // Select network parameters before anything encodes addresses
+    let params = chain_params::select(app_config.chain_params.clone());
+    println!("Network: {:?}", params.network);
+
+    db::codec::configure(app_config.value_compression.clone());
+    let db = open_db(&app_config.db_path, &app_config.db_tuning)?;
+    // Never run one network's config against another network's database
+    let command = cli.command.unwrap_or(Command::Sync);
+    network_identity::verify(&db, &app_config.blk_dir, command.indexes())?;
+    // Nor sync a database with another role's indexes, or query indexes it doesn't have
+    let indexed_role = index_role::verify(&db, app_config.index.role)?;
+    index_role::check_command(indexed_role, &command)?;
+
+    match command {
+        Command::Sync => {
+            shutdown::install()?;
+            let db_path = app_config.db_path.clone();
+            sync(&db, &app_config).inspect_err(|e| flag_corruption(&db_path, e.as_ref()))
+        }
+        Command::SyncPeer { peer } => {
+            shutdown::install()?;
+            let db_path = app_config.db_path.clone();
+            sync_peer(&db, app_config, &peer).inspect_err(|e| flag_corruption(&db_path, e.as_ref()))
+        }
+        Command::Schedule => {
+            if app_config.schedule.is_empty() {
+                return Err("No tasks under [schedule] in the config".into());
+            }
+            shutdown::install()?;
+            scheduler::run(&db, &app_config.schedule, |task| match task {
+                "sync" => sync(&db, &app_config).inspect_err(|e| flag_corruption(&app_config.db_path, e.as_ref())),
+                "compact" => {
+                    for cf in COLUMN_FAMILIES.iter() {
+                        db::compact(&db, cf)?;
+                    }
+                    Ok(())
+                }
+                _ => unreachable!("task names are checked when loading the config"),
+            })?;
+            Ok(())
+        }
+        Command::BalanceHistory { address, from, to, group_by } => {
+            let buckets = balance_history::query(&db, &address, from, to, group_by)?;
+            println!("{}", serde_json::to_string_pretty(&buckets)?);
+            Ok(())
+        }
+        Command::SearchText { query, limit } => {
+            for txid in text_search::search(&db, &query, limit)? {
+                println!("{}", txid);
+            }
+            Ok(())
+        }
+        Command::AddressTxs { address, page, page_size, from, to } => {
+            let history = tx_history::page(&db, &address, from, to, page, page_size)?;
+            println!("{}", serde_json::to_string_pretty(&history)?);
+            Ok(())
+        }
+        Command::Xpub { xpub, gap_limit } => {
+            let summary = xpub::discover(&db, &xpub, gap_limit, app_config.index.dust_relay_fee)?;
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            Ok(())
+        }
+        Command::UtxoAt { address, height } => {
+            let utxos = tx_history::utxos_at(&db, &address, height)?;
+            println!("{}", serde_json::to_string_pretty(&utxos)?);
+            Ok(())
+        }
+        Command::EstimateFee { blocks } => {
+            let estimate = fee_estimate::estimate(&db, blocks)?;
+            println!("{}", serde_json::to_string_pretty(&estimate)?);
+            Ok(())
+        }
+        Command::Stakers { days, limit } => {
+            let rows = stakers::top(&db, days, limit)?;
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+            Ok(())
+        }
+        Command::RichList { limit } => {
+            let entries = rich_list::top(&db, limit)?;
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+            Ok(())
+        }
+        Command::UtxoCommitment { height } => {
+            let commitment = commitment::at(&db, height)?.map(|commitment| hex::encode(commitment.to_bytes()));
+            println!("{}", serde_json::json!({ "height": height, "utxoCommitment": commitment }));
+            Ok(())
+        }
+        Command::ShieldAnchors { height } => {
+            let info = shield_info_at(&db, height)?.map(|info| serde_json::json!({
+                "treeSize": info.tree_size,
+                "finalRoot": hex::encode(&info.final_root),
+                "anchors": info.anchors.iter().map(hex::encode).collect::<Vec<_>>(),
+            }));
+            println!("{}", serde_json::json!({ "height": height, "shield": info }));
+            Ok(())
+        }
+        Command::StakeInfo { height } => {
+            let info = stake_info_at(&db, height)?.map(|info| serde_json::json!({
+                "txid": hex::encode(&info.txid),
+                "stakedValue": info.staked_value,
+                "ageBlocks": info.age_blocks,
+                "coinDays": info.coin_days,
+                "reward": info.reward,
+            }));
+            println!("{}", serde_json::json!({ "height": height, "coinstake": info }));
+            Ok(())
+        }
+        Command::MerkleMismatches => {
+            for (height, header_root, computed_root) in merkle_mismatches(&db)? {
+                println!("{}", serde_json::json!({ "height": height, "headerRoot": header_root, "computedRoot": computed_root }));
+            }
+            Ok(())
+        }
+        Command::ExportBlockbook { out } => {
+            let counts = blockbook_export::export(&db, &out)?;
+            println!("{}", serde_json::to_string_pretty(&counts)?);
+            Ok(())
+        }
+        Command::StakedBy { height } => {
+            let staker = stakers::staked_by(&db, height)?;
+            println!("{}", serde_json::json!({ "height": height, "stakedBy": staker }));
+            Ok(())
+        }
+        Command::Tasks => {
+            for (task, status) in scheduler::load_statuses(&db)? {
+                println!("{}", serde_json::json!({ "task": task, "status": status }));
+            }
+            Ok(())
+        }
+        Command::BlkFiles => {
+            for (file, state) in blk_files::all(&db)? {
+                println!("{}", serde_json::json!({ "file": file, "state": state }));
+            }
+            Ok(())
+        }
+        Command::Events { from, event_type } => {
+            for event in events::query(&db, from, event_type)? {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+            Ok(())
+        }
+        Command::DumpJournal { from } => dump_journal(&db, from),
+        Command::RepairAddress { address } => {
+            let diff = repair_address(&db, &address)?;
+            println!("Repaired {}", diff);
+            record_event(&db, EventType::Repair, format!("repaired {}", diff))?;
+            Ok(())
         }
-        write!(f, "}}")
+        Command::Db { command: DbCommand::Stats } => Ok(db::print_stats(&db)?),
+        Command::Db { command: DbCommand::Compact { cf } } => Ok(db::compact(&db, &cf)?),
+        Command::Address { address } => {
+            let summary = address_summary(&db, &address, app_config.index.dust_relay_fee)?;
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            Ok(())
+        }
+        Command::GenFixture { .. } => unreachable!("handled before loading the config"),
     }
 }
 
-impl std::fmt::Debug for CTransaction {
-    // Formatting for CTransaction
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Transaction {{")?;
-        writeln!(f, "    version: {}", self.version)?;
-        writeln!(f, "    inputs: {:?}", self.inputs)?;
-        writeln!(f, "    outputs: {:?}", self.outputs)?;
-        writeln!(f, "    lock_time: {}", self.lock_time)?;
-        write!(f, "}}")
+// Blocks asked for in one getdata while syncing from a peer
+const P2P_BLOCKS_PER_REQUEST: usize = 16;
+
+// Marker left in the database directory when corruption is hit while running
+const REPAIR_MARKER: &str = "REPAIR_NEEDED";
+
+// Open RocksDB, running DB::repair first if the last run hit corruption or
+// the database reports corruption when opened
+fn open_db(db_path: &Path, tuning: &DbTuning) -> Result<DB, Box<dyn std::error::Error>> {
+    let block_cache = Cache::new_lru_cache(tuning.block_cache_size);
+    let cf_descriptors = || {
+        let mut cf_descriptors = vec![ColumnFamilyDescriptor::new("default", Options::default())];
+        for cf in COLUMN_FAMILIES.iter() {
+            cf_descriptors.push(ColumnFamilyDescriptor::new(cf.to_string(), db::options::cf_options(cf, tuning, &block_cache)));
+        }
+        cf_descriptors
+    };
+
+    let db_options = db::options::db_options(tuning);
+
+    let marker = db_path.join(REPAIR_MARKER);
+    let mut repaired = None;
+    if marker.exists() {
+        println!("Repairing database after corruption in the previous run");
+        DB::repair(&db_options, db_path)?;
+        repaired = Some("corruption reported by the previous run");
     }
-}
 
-impl std::fmt::Debug for CTxIn {
-    // Formatting for CTxIn
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{{")?;
-        writeln!(f, "    prevout: {:?}", self.prevout)?;
-        writeln!(f, "    script_sig: {:?}", self.script_sig)?;
-        writeln!(f, "    sequence: {}", self.sequence)?;
-        writeln!(f, "    coinbase: {:?}", self.coinbase)?;
-        write!(f, "}}")
+    let db = match DB::open_cf_descriptors(&db_options, db_path, cf_descriptors()) {
+        Err(e) if e.kind() == rocksdb::ErrorKind::Corruption && repaired.is_none() => {
+            eprintln!("Database is corrupted ({}), attempting repair", e);
+            DB::repair(&db_options, db_path)?;
+            repaired = Some("corruption reported when opening");
+            DB::open_cf_descriptors(&db_options, db_path, cf_descriptors())?
+        }
+        result => result?,
+    };
+
+    if let Some(reason) = repaired {
+        if marker.exists() {
+            fs::remove_file(&marker)?;
+        }
+        record_event(&db, EventType::Repair, format!("ran DB::repair after {}", reason))?;
     }
+    Ok(db)
 }
 
-impl fmt::Debug for CTxOut {
-    // Formatting for CTxOut
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "{{")?;
-        writeln!(f, "    value: {:?}", self.value)?;
-        writeln!(f, "    script_pubkey: {:?}", self.script_pubkey)?;
-        writeln!(f, "    script_length: {:?}", self.script_length)?;
-        writeln!(f, "    address: {:?}", self.address)?;
-        writeln!(f, "}}")
+// Leave a marker so the next start repairs the database before opening it
+fn flag_corruption(db_path: &Path, error: &dyn std::error::Error) {
+    if error.to_string().starts_with("Corruption") {
+        eprintln!("Database corruption detected, it will be repaired on the next start");
+        if let Err(e) = fs::write(db_path.join(REPAIR_MARKER), error.to_string()) {
+            eprintln!("Failed to write repair marker: {}", e);
+        }
     }
 }
 
-impl std::fmt::Debug for SaplingTxData {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Formatting SaplingTxData
-        writeln!(f, "SaplingTxData {{")?;
-        writeln!(f, "    value: {}", self.value)?;
-        writeln!(f, "    vshield_spend: {:?}", self.vshield_spend)?;
-        writeln!(f, "    vshield_output: {:?}", self.vshield_output)?;
-        writeln!(f, "    binding_sig: {:?}", hex::encode(&self.binding_sig))?;
-        write!(f, "}}")
+// Print journal entries from `from` onward as JSON lines
+fn dump_journal(db: &DB, mut from: u64) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let entries = journal::read_from(db, from, 1000)?;
+        match entries.last() {
+            Some(last) => from = last.seq + 1,
+            None => break,
+        }
+        for entry in &entries {
+            println!("{}", serde_json::to_string(entry)?);
+        }
     }
+    Ok(())
 }
 
-impl fmt::Debug for VShieldSpend {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Formatting vShieldSpend
-        writeln!(f, "{{")?;
-        writeln!(f, "    cv: {:?}", hex::encode(&self.cv))?;
-        writeln!(f, "    anchor: {:?}", hex::encode(&self.anchor))?;
-        writeln!(f, "    nullifier: {:?}", hex::encode(&self.nullifier))?;
-        writeln!(f, "    rk: {:?}", hex::encode(&self.rk))?;
-        writeln!(f, "    proof: {:?}", hex::encode(&self.proof))?;
-        writeln!(f, "    spend_auth_sig: {:?}", hex::encode(&self.spend_auth_sig))?;
-        write!(f, "}}")
+fn sync(db: &DB, app_config: &app_config::AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+    // Indexing event journal for external consumers, off unless enabled
+    if app_config.journal_enabled {
+        journal::enable(db)?;
+    }
+
+    let settings = &app_config.index;
+    println!("Role: {:?}", settings.role);
+
+    // Optional address labels file, reloaded when it changes on disk
+    let mut labels_file = app_config.labels_file.clone().map(labels::LabelsFile::new);
+    if let Some(labels_file) = labels_file.as_mut() {
+        labels_file.refresh_if_changed(db)?;
+    }
+
+    if !settings.role.syncs_blocks() {
+        println!("Role {:?} does not index blk files, nothing to sync", settings.role);
+        return Ok(());
+    }
+    migrate_for_sync(db, settings)?;
+
+    // Scanning with the wrong magic would silently find no blocks at all
+    let network = chain_params::params().network;
+    if let Some(detected) = chain_params::detect_network(&app_config.blk_dir)? {
+        if detected != network {
+            return Err(format!("blk files in {} are {:?} blocks but chain.network is {:?}",
+                app_config.blk_dir.display(), detected, network).into());
+        }
+    }
+
+    if let Some(checkpoint) = shutdown::last_checkpoint(db)? {
+        println!("Last stopped at height {} in {}", checkpoint.height, checkpoint.source);
+    }
+
+    // Files fully read before scan states were kept
+    let processed_files = load_processed_files_from_db(db).unwrap_or_default();
+
+    // Process the blk files in order; names are zero padded
+    let mut files: Vec<PathBuf> = fs::read_dir(&app_config.blk_dir)
+        .map_err(|err| format!("Failed to read directory entries: {}", err))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat")))
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    record_event(db, EventType::PhaseStart, format!("blk file sync from {}", app_config.blk_dir.display()))?;
+    let mut progress_sink = ConsoleProgress::new(1000);
+
+    // The newest file read before scan states were kept may have had blocks
+    // appended since, so it is scanned again like any file without a state
+    let legacy_newest = processed_files.iter().max();
+    for file_path in &files {
+        // Files with a scan state always resume from its offset, which costs
+        // a seek for files that are no longer appended to
+        if blk_files::load(db, file_path)?.is_none() && processed_files.contains(file_path) && Some(file_path) != legacy_newest {
+            continue;
+        }
+        if shutdown::requested() {
+            break;
+        }
+        if let Some(labels_file) = labels_file.as_mut() {
+            if let Err(e) = labels_file.refresh_if_changed(db) {
+                eprintln!("Failed to reload labels file: {}", e);
+            }
+        }
+        disk_guard::wait_for_space(db, settings.min_free_disk_bytes)?;
+        record_event(db, EventType::PhaseStart, format!("processing {}", file_path.display()))?;
+        if !process_blk_file(file_path, db, settings, &mut progress_sink)? {
+            // Interrupted; the next sync resumes from the file's scan state
+            record_event(db, EventType::PhaseFinish, format!("interrupted in {}", file_path.display()))?;
+            return Ok(());
+        }
+        record_event(db, EventType::PhaseFinish, format!("processed {}", file_path.display()))?;
+    }
+
+    if shutdown::requested() {
+        record_event(db, EventType::PhaseFinish, "blk file sync interrupted")?;
+        return Ok(());
     }
+    record_event(db, EventType::PhaseFinish, "blk file sync")?;
+    Ok(())
 }
 
-impl std::fmt::Debug for VShieldOutput {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Formatting vShieldOutput
-        writeln!(f, "{{")?;
-        writeln!(f, "    cv: {:?}", hex::encode(&self.cv))?;
-        writeln!(f, "    cmu: {:?}", hex::encode(&self.cmu))?;
-        writeln!(f, "    ephemeral_key: {:?}", hex::encode(&self.ephemeral_key))?;
-        writeln!(f, "    enc_ciphertext: {:?}", hex::encode(&self.enc_ciphertext))?;
-        writeln!(f, "    out_ciphertext: {:?}", hex::encode(&self.out_ciphertext))?;
-        writeln!(f, "    proof: {:?}", hex::encode(&self.proof))?;
-        write!(f, "}}")
+// Bring databases indexed by older versions up to date before indexing more blocks
+fn migrate_for_sync(db: &DB, settings: &IndexSettings) -> Result<(), Box<dyn std::error::Error>> {
+    // Materialize address balances for databases indexed before they were tracked
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    if settings.role.builds_address_index() && db.get_cf(cf_state, b"addr_balances_built")?.is_none() {
+        println!("Rebuilding address balances from the UTXO set");
+        let sequence = rebuild_address_balances(db)?;
+        record_event(db, EventType::Migration, format!("rebuilt address balances from the UTXO set at sequence {}", sequence))?;
+    }
+
+    // Rank the stored balances for databases indexed before the rich list was kept
+    if settings.role.builds_address_index() && db.get_cf(cf_state, b"rich_list_built")?.is_none() {
+        println!("Building the rich list");
+        rich_list::build(db)?;
+        record_event(db, EventType::Migration, "built the rich list from address balances")?;
+    }
+
+    // Staker totals were kept in stats before they had their own column family
+    if db.get_cf(cf_state, b"stakers_moved")?.is_none() {
+        let moved = stakers::migrate_from_stats(db)?;
+        if moved > 0 {
+            record_event(db, EventType::Migration, format!("moved {} staker totals from stats to stakers", moved))?;
+        }
+    }
+
+    // Issuance records shared the 'i' prefix with the indexed markers
+    if db.get_cf(cf_state, b"issuance_moved")?.is_none() {
+        let moved = migrate_issuance_keys(db)?;
+        if moved > 0 {
+            record_event(db, EventType::Migration, format!("moved {} issuance records to the 'e' prefix", moved))?;
+        }
     }
+
+    // Block stats were stored without a version byte in several layouts
+    if db.get_cf(cf_state, b"block_stats_versioned")?.is_none() {
+        let (rewritten, undecodable) = migrate_block_stats(db)?;
+        if rewritten > 0 || undecodable > 0 {
+            if undecodable > 0 {
+                eprintln!("{} block stats rows have an unknown layout and were left as they are", undecodable);
+            }
+            record_event(db, EventType::Migration, format!("rewrote {} block stats rows with a version byte, {} undecodable", rewritten, undecodable))?;
+        }
+    }
+
+    // Start the UTXO set commitment from the current set for databases indexed before it was tracked
+    if settings.role.parses_transactions() && db.get_cf(cf_state, b"utxo_commitment")?.is_none() {
+        println!("Computing the UTXO set commitment");
+        let utxo_commitment = commitment::rebuild(db)?;
+        record_event(db, EventType::Migration, format!("computed UTXO set commitment {}", hex::encode(utxo_commitment.to_bytes())))?;
+    }
+    Ok(())
 }
 
-const COLUMN_FAMILIES: [&str; 7] = [
-    "blocks", "transactions",
-    "addr_index", "utxo",
-    "chain_metadata", "pubkey",
-    "chain_state",
-];
+// Index blocks fetched over P2P from one node, continuing from the last block
+// taken from a peer. Heights follow from the chain order of the headers.
+fn sync_peer(db: &DB, app_config: app_config::AppConfig, peer: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if app_config.journal_enabled {
+        journal::enable(db)?;
+    }
+    let settings = app_config.index;
+    if !settings.role.syncs_blocks() {
+        println!("Role {:?} does not index blocks, nothing to sync", settings.role);
+        return Ok(());
+    }
+    migrate_for_sync(db, &settings)?;
+
+    let params = chain_params::params();
+    let addr = if peer.contains(':') { peer.to_string() } else { format!("{}:{}", peer, params.default_port) };
+    let mut node = p2p::Peer::connect(&addr, params.magic)?;
+    println!("Connected to {} ({}, height {})", addr, node.user_agent, node.start_height);
+    record_event(db, EventType::PhaseStart, format!("peer sync from {}", addr))?;
+
+    // chain_state "p2p_tip" -> i32 LE height + block hash
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let (mut height, mut tip) = match db.get_cf(cf_state, b"p2p_tip")? {
+        Some(value) if value.len() == 36 => {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&value[4..]);
+            (i32::from_le_bytes([value[0], value[1], value[2], value[3]]), Some(hash))
+        }
+        _ => (0, None),
+    };
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load the configuration file
-    let mut config = Config::default();
-    config.merge(ConfigFile::with_name("config.toml"))?;
-    let paths = config.get_table("paths")?;
-
-    // Open RocksDB
-    let db_path: &str = &paths
-        .get("db_path")
-        .and_then(|value| value.to_owned().into_string().ok())
-        .ok_or("Missing or invalid db_path in config.toml")?;
-    let mut cf_descriptors = vec![ColumnFamilyDescriptor::new("default", Options::default())];
-    for cf in COLUMN_FAMILIES.iter() {
-        cf_descriptors.push(ColumnFamilyDescriptor::new(cf.to_string(), Options::default()));
-    }
-
-    let mut db_options = Options::default();
-    db_options.create_if_missing(true);
-    db_options.create_missing_column_families(true);
-    let db = DB::open_cf_descriptors(&db_options, db_path, cf_descriptors)?;
-
-    // Path for blk files "blocks" folder
-    let blk_dir: &str = &paths
-        .get("blk_dir")
-        .and_then(|value| value.to_owned().into_string().ok())
-        .ok_or("Invalid blk_dir in config.toml")?;
-
-    // Load processed files from the default column family
-    let mut processed_files = load_processed_files_from_db(&db).unwrap_or_default();
-
-    // Process each file in the directory
-    let dir = fs::read_dir(blk_dir)
-        .map_err(|err| format!("Failed to read directory entries: {}", err))?;
-
-    for entry in dir {
-        if let Ok(entry) = entry {
-            if let Some(file_name) = entry.file_name().to_str() {
-                if file_name.starts_with("blk") && file_name.ends_with(".dat") {
-                    let file_path = entry.path();
-                    if processed_files.contains(&file_path) {
-                        continue; // Skip already processed files
+    // A peer doesn't answer an empty locator, so a first sync asks for the
+    // blocks after genesis (wire byte order)
+    let mut genesis = [0u8; 32];
+    genesis.copy_from_slice(&hex::decode(params.genesis_hash)?);
+    genesis.reverse();
+
+    'sync: loop {
+        let locator = [tip.unwrap_or(genesis)];
+        let (hashes, full) = node.get_headers(&locator)?;
+        if hashes.is_empty() {
+            break;
+        }
+
+        for chunk in hashes.chunks(P2P_BLOCKS_PER_REQUEST) {
+            for (hash, data) in chunk.iter().zip(node.get_blocks(chunk)?) {
+                let mut block = RawBlock::from_data(0, data)?;
+                height += 1;
+                block.header.block_height = Some(height);
+                // The header hash of a Quark-hashed block comes from the headers batch
+                block.header.block_hash = *hash;
+                if height == 1 {
+                    if block.header.hash_prev_block != genesis {
+                        return Err(format!("{} does not build on the {:?} genesis block {}", addr, params.network, params.genesis_hash).into());
                     }
-                    process_blk_file(&file_path, &db)?;
+                    network_identity::record(db)?;
+                }
+
+                let mut commit = BlockCommit::new(db);
+                index_block(&mut commit, &block, &settings)?;
+                let mut value = height.to_le_bytes().to_vec();
+                value.extend_from_slice(&block.header.block_hash);
+                commit.put_cf("chain_state", b"p2p_tip", &value);
+                commit.commit()?;
 
-                    // Save updated processed files to the default column family
-                    processed_files.insert(file_path.clone());
-                    let _ = save_processed_files_to_db(&db, &processed_files);
+                tip = Some(*hash);
+
+                if shutdown::requested() {
+                    shutdown::write_checkpoint(db, &addr, 0, height)?;
+                    break 'sync;
                 }
             }
+            disk_guard::wait_for_space(db, settings.min_free_disk_bytes)?;
+        }
+        println!("{}: indexed to height {}", addr, height);
+
+        if !full {
+            break; // Caught up with the peer
         }
     }
 
+    record_event(db, EventType::PhaseFinish, format!("peer sync from {}, height {}", addr, height))?;
     Ok(())
 }
 
@@ -342,789 +890,583 @@ fn load_processed_files_from_db(db: &DB) -> Result<HashSet<PathBuf>, String> {
     }
 }
 
-fn save_processed_files_to_db(db: &DB, processed_files: &HashSet<PathBuf>) -> Result<(), String> {
-    let cf = db.cf_handle("chain_metadata").expect("Chain metadata column family not found.");
-    let data = bincode::serialize(processed_files)
-        .map_err(|e| format!("Bincode serialization error: {}", e))?;
-    db.put_cf(cf, b"processed_files", &data)?;
-    Ok(())
-}
-
-fn process_blk_file(file_path: impl AsRef<Path>, _db: &DB) -> io::Result<()> {
+// Index the blocks of one blk file from where its last scan stopped.
+// Returns false when a shutdown was requested before the end of the file.
+fn process_blk_file(file_path: impl AsRef<Path>, _db: &DB, settings: &IndexSettings, progress_sink: &mut dyn ProgressSink) -> io::Result<bool> {
+    let file_path = file_path.as_ref();
     // Open file
     let mut file = File::open(file_path)?;
-    // Set buffers for prefix, size
-    let mut prefix_buffer = [0u8; 4];
-    let mut size_buffer = [0u8; 4];
-    // Counting positions for loop
-    let mut stream_position = 0;
-
-    loop {
-        let mut reader = io::BufReader::new(&file);
-        reader.seek(SeekFrom::Start(stream_position))?;
-
-        // Read the prefix
-        if reader.read_exact(&mut prefix_buffer).is_err() {
-            // Reached end of stream
-            break;
-        }
+    let file_size = file.metadata()?.len();
+    let checksum = blk_files::checksum(&mut file)?;
+
+    let cf_blocks = _db.cf_handle("blocks").expect("Blocks column family not found");
+    // Resume only while the file is still the one scanned and its last block is indexed
+    let mut state = match blk_files::load(_db, file_path)? {
+        Some(state) if state.checksum == checksum && state.offset <= file_size && last_block_indexed(_db, &state)? => state,
+        _ => blk_files::ScanState { checksum, ..Default::default() },
+    };
+    state.file_size = file_size;
+    state.complete = false;
+    if state.offset > 0 {
+        println!("Resuming {} at byte {}", file_path.display(), state.offset);
+    }
 
-        // Check if the prefix matches
-        if prefix_buffer != PREFIX {
-            // Find the next prefix
-            let _next_prefix = [0u8; 4];
-            let mut prefix_found = false;
-
-            while !prefix_found {
-                // Move to the next byte in the stream
-                let mut byte = [0u8; 1];
-                if reader.read_exact(&mut byte).is_err() {
-                    // Reached end of stream
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid prefix found or end of file",
-                    ));
-                }
+    file.seek(SeekFrom::Start(state.offset))?;
+    let mut progress = FileProgress::new(file_path, file_size);
+    progress.bytes_processed = state.offset;
+    let blocks = BlockStream::starting_at(io::BufReader::new(file), chain_params::params().magic, state.offset);
 
-                // Shift the bytes in the prefix buffer
-                for i in (2..4).rev() {
-                    prefix_buffer[i] = prefix_buffer[i - 2];
-                }
-                
-                // Add the new byte to the prefix buffer
-                prefix_buffer[0] = prefix_buffer[2];
-                prefix_buffer[1] = byte[0];
-
-                // Check if the prefix matches
-                if prefix_buffer == PREFIX {
-                    prefix_found = true;
-                    continue;
-                }
-            }
+    for block in blocks {
+        let mut block = block?;
+        // Magic and size prefix, then the block
+        let block_end = block.offset + 8 + block.size() as u64;
 
-            // Alert to no prefix being found
-            if !prefix_found {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "No prefix found",
-                ));
-            }
+        let block_hash = hex::encode(block.header.block_hash.iter().rev().cloned().collect::<Vec<u8>>());
 
-            continue; // Continue to the next iteration of the outer loop
+        // Blocks indexed before scan states were kept, or before a crash, are
+        // skipped; the state moves past them with the next commit or at the end
+        if _db.get_cf(cf_blocks, indexed_marker_key(&block.header.block_hash)).map_err(from_rocksdb_error)?.is_some() {
+            state.offset = block_end;
+            state.last_block_hash = block_hash;
+            continue;
         }
-        //println!("Prefix buffer: {:?}", prefix_buffer);
-        println!("Prefix hex: {}", hex::encode(&prefix_buffer));
-
-        // Convert the block size to little-endian u32
-        reader.read_exact(&mut size_buffer)?;
-        let block_size = u32::from_le_bytes(size_buffer);
-
-        println!("Block Size: {}", block_size);
-
-        // Peek at next 4 bytes, need to know the version before setting header size
-        let _version = read_4_bytes(&mut reader)?;
-        let ver_as_int = u32::from_le_bytes(_version);
-
-        // Variable header size based on block versions
-        let header_size = match ver_as_int {
-            4 | 5 | 6 | 8 | 9 | 10 | 11 => 112, // Version 4, 5, 6, 8: 112 bytes header
-            7 => 80, // Version 7 is 80 bytes
-            //8..=u32::MAX => 144, // Version 8 and above: 144 bytes header
-            _ => 80, // Default: Version 1 to 3: 80 bytes header
-        };
-
-        // Read the block header
-        let mut header_buffer = vec![0u8; header_size];
-        reader.read_exact(&mut header_buffer)?;
-
-        // Process and print the block header
-        let block_header = parse_block_header(&header_buffer, header_size);
-        println!("{:?}", block_header);
 
-        // Write to RocksDB
-        // 'b' + block_hash -> block_data
-        let cf_blocks = _db.cf_handle("blocks").expect("Blocks column family not found.");
-        let mut key = vec![b'b'];
-        key.extend_from_slice(&block_header.block_hash);
-        _db.put_cf(cf_blocks, &key, &header_buffer).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        // 'h' + block_height -> block_hash
-        let mut key_height = vec![b'h'];
-        let height = block_header.block_height.unwrap_or(0);
-        let height_bytes = height.to_le_bytes();
-        key_height.extend_from_slice(&height_bytes);
-        _db.put_cf(cf_blocks, &key_height, &block_header.block_hash).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        block.header.block_height = block_height(_db, settings, &block.header)?;
+        let height = block.header.block_height.unwrap_or(0);
+
+        // Everything written for the block becomes visible at once, scan state included
+        let mut commit = BlockCommit::new(_db);
+        index_block(&mut commit, &block, settings)?;
+        state.offset = block_end;
+        state.last_block_hash = block_hash;
+        blk_files::record(&mut commit, file_path, &state)?;
+        commit.commit()?;
+
+        progress.bytes_processed = block_end;
+        progress.blocks_indexed += 1;
+        progress.height = height;
+        progress_sink.block_indexed(&progress);
+        // Stop between blocks, never inside one
+        if shutdown::requested() {
+            shutdown::write_checkpoint(_db, &file_path.display().to_string(), progress.bytes_processed, height)?;
+            return Ok(false);
+        }
+        if progress.blocks_indexed.is_multiple_of(500) {
+            disk_guard::wait_for_space(_db, settings.min_free_disk_bytes)?;
+        }
+    }
 
-        // Process and print tx data
-        process_transaction(&mut reader, ver_as_int, &block_header.block_hash, _db)?;
+    state.complete = true;
+    blk_files::save(_db, file_path, &state)?;
+    progress_sink.file_finished(&progress);
+    Ok(true)
+}
 
-        // Move to the next position in the stream
-        let next_position = stream_position + block_size as u64 + 8; // 8 bytes for the prefix and size
-        file.seek(SeekFrom::Start(next_position))?;
-        stream_position = next_position;
+// Height of a blk file block: from the node's block index when ldb_dir is set,
+// otherwise one above its parent's. Parents are found by their indexed marker,
+// so this only links blocks hashed with SHA-256d (version 4 and later, and
+// generated chains); the Quark-hashed genesis block is known from the chain
+// parameters instead.
+fn block_height(db: &DB, settings: &IndexSettings, header: &CBlockHeader) -> io::Result<Option<i32>> {
+    if let Some(ldb_dir) = &settings.ldb_dir {
+        return Ok(read_ldb_block(ldb_dir, &header.hash_prev_block).unwrap_or(None));
     }
 
-    Ok(())
+    let params = chain_params::params();
+    if params.is_genesis(header) {
+        return Ok(Some(0));
+    }
+    if hex::encode(reverse_bytes(&header.hash_prev_block)) == params.genesis_hash {
+        return Ok(Some(1));
+    }
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    match db.get_cf(cf_blocks, indexed_marker_key(&header.hash_prev_block)).map_err(from_rocksdb_error)? {
+        Some(value) => Ok(Some(i32::from_le_bytes(value[..4].try_into().unwrap()) + 1)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Parent {} of block {} is not indexed; set paths.ldb_dir to index blk files with blocks out of order",
+            hex::encode(reverse_bytes(&header.hash_prev_block)), hex::encode(reverse_bytes(&header.block_hash))))),
+    }
 }
 
-fn parse_block_header(slice: &[u8], header_size: usize) -> CBlockHeader {
-    // Grab header bytes
-    let mut reader = io::Cursor::new(slice);
-
-    // Set buffer
-    let max_size = 112;
-    let mut header_buffer = vec![0u8; header_size.min(max_size)];
-    // Set position
-    let current_position = match reader.seek(SeekFrom::Current(0)) {
-        Ok(pos) => pos,
-        Err(e) => {
-            eprintln!("Error while setting current position: {:?}", e);
-            0 // or some other default value or action
-        }
-    };
-    // Read buffer
-    if let Err(e) = reader.read_exact(&mut header_buffer) {
-        eprintln!("Error while reading header buffer: {:?}", e);
-    }
-    println!("Header Buffer: {:?}", hex::encode(&header_buffer));
-    // Start hashing header for block_hash
-    let first_hash = Sha256::digest(&header_buffer);
-    let block_hash = Sha256::digest(&first_hash);
-    // Reverse final hash
-    let reversed_hash: Vec<_> = block_hash.iter().rev().cloned().collect();
-    // Test print hash
-    println!("Block hash: {:?}", hex::encode(&reversed_hash));
-    // Return to original position to start breaking down header
-    if let Err(e) = reader.seek(SeekFrom::Start(current_position)) {
-        eprintln!("Error while seeking: {:?}", e);
-    }
-
-    // Read block version
-    let n_version = reader.read_u32::<LittleEndian>().unwrap();
-    // Read previous block hash
-    let hash_prev_block = {
-        let mut buf = [0u8; 32];
-        reader.read_exact(&mut buf).unwrap();
-        buf
-    };
-    let block_height = read_ldb_block(&hash_prev_block, header_size).unwrap_or(None);
-    // Read merkle root
-    let hash_merkle_root = {
-        let mut buf = [0u8; 32];
-        reader.read_exact(&mut buf).unwrap();
-        buf
-    };
-    // Read nTime, nBits, and nNonce
-    let n_time = reader.read_u32::<LittleEndian>().unwrap();
-    let n_bits = reader.read_u32::<LittleEndian>().unwrap();
-    let n_nonce = reader.read_u32::<LittleEndian>().unwrap();
-
-    // Handle the expanded header size based on the params given
-    let (hash_final_sapling_root, n_accumulator_checkpoint) = match n_version {
-        7 => (None, None),
-        8..=11 => {
-            let mut final_sapling_root = [0u8; 32];
-            reader.read_exact(&mut final_sapling_root).expect("Failed to read final sapling root");
-            (Some(final_sapling_root), None)
-        },
-        4..=6 => {
-            let mut accumulator_checkpoint = [0u8; 32];
-            reader.read_exact(&mut accumulator_checkpoint).expect("Failed to read accumulator checkpoint");
-            (None, Some(accumulator_checkpoint))
-        },
-        _ => (None, None),
+// Whether the block a scan state ends at is in the index, i.e. the database
+// wasn't replaced or rolled back since
+fn last_block_indexed(db: &DB, state: &blk_files::ScanState) -> io::Result<bool> {
+    if state.offset == 0 {
+        return Ok(true);
+    }
+    let mut hash = match hex::decode(&state.last_block_hash) {
+        Ok(hash) => hash,
+        Err(_) => return Ok(false),
     };
+    hash.reverse();
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    Ok(db.get_cf(cf_blocks, indexed_marker_key(&hash)).map_err(from_rocksdb_error)?.is_some())
+}
+
+// Index one block whose height has been set, from any source (blk files, peers, ...)
+fn index_block(commit: &mut BlockCommit, block: &RawBlock, settings: &IndexSettings) -> io::Result<()> {
+    let block_header = &block.header;
+
+    // 'b' + block_hash -> block_data
+    let mut key = vec![b'b'];
+    key.extend_from_slice(&block_header.block_hash);
+    commit.put_cf("blocks", &key, block.header_bytes());
+    // 'h' + block_height -> block_hash
+    let mut key_height = vec![b'h'];
+    let height = block_header.block_height.unwrap_or(0);
+    let height_bytes = height.to_le_bytes();
+    key_height.extend_from_slice(&height_bytes);
+    commit.put_cf("blocks", &key_height, &block_header.block_hash);
+    journal::append(commit, JournalEvent::BlockConnected {
+        height,
+        hash: hex::encode(block_header.block_hash.iter().rev().cloned().collect::<Vec<u8>>()),
+    })?;
+
+    // Process and print tx data
+    if settings.role.parses_transactions() {
+        // Spent outputs per address are kept from the first block indexed with them
+        if settings.role.builds_address_index() && commit.get_cf("chain_state", b"spent_outputs_since")?.is_none() {
+            commit.put_cf("chain_state", b"spent_outputs_since", &height_bytes);
+        }
+        // Likewise each address's transactions and per-block amounts, which
+        // repair-address can only rely on when they start at the first block
+        if settings.role.builds_address_index() && commit.get_cf("chain_state", b"address_history_since")?.is_none() {
+            commit.put_cf("chain_state", b"address_history_since", &height_bytes);
+        }
+        let mut reader = io::Cursor::new(block.transactions());
+        let mut stats = process_transaction(&mut reader, block_header.n_version, &block_header.hash_merkle_root, block_header.hash_final_sapling_root, height, block_header.n_time, settings, commit)?;
+        // Serialized size as framed in the blk file (PIVX has no witness data, so weight is 4x this)
+        stats.size = block.size();
+        store_block_stats(commit, height, &stats)?;
+    }
 
-    let block_height = block_height;
-
-    // Create CBlockHeader
-    CBlockHeader {
-        n_version,
-        block_hash: block_hash.into(),
-        block_height,
-        hash_prev_block,
-        hash_merkle_root,
-        n_time,
-        n_bits,
-        n_nonce,
-        n_accumulator_checkpoint,
-        hash_final_sapling_root,
+    // chain_state "best_height" -> highest block indexed so far
+    let best = commit.get_cf("chain_state", b"best_height")?
+        .map(|value| i32::from_le_bytes(value[..4].try_into().unwrap()));
+    if best < Some(height) {
+        commit.put_cf("chain_state", b"best_height", &height_bytes);
     }
+
+    // Written last, in the same batch as everything above
+    commit.put_cf("blocks", &indexed_marker_key(&block_header.block_hash), &height_bytes);
+    Ok(())
 }
 
-fn read_script<R: io::Read>(reader: &mut R) -> Result<Vec<u8>, io::Error> {
-    let script_length = read_varint(reader)?;
-    let mut script = vec![0u8; script_length as usize];
-    reader.read_exact(&mut script)?;
-    Ok(script)
+// 'i' + block_hash -> block_height, present once the block is fully indexed
+fn indexed_marker_key(block_hash: &[u8]) -> Vec<u8> {
+    let mut key = vec![b'i'];
+    key.extend_from_slice(block_hash);
+    key
 }
 
-fn handle_address(_db: &DB, address_type: &AddressType, reversed_txid: &Vec<u8>, tx_out_index: u32) -> Result<(), io::Error> {
-    let address_keys = match address_type {
+
+// Addresses an output is indexed under; cold staking outputs belong to both staker and owner
+fn address_keys(address_type: &AddressType) -> Vec<String> {
+    match address_type {
         AddressType::P2PKH(address) | AddressType::P2SH(address) => vec![address.clone()],
         AddressType::P2PK(pubkey) => vec![pubkey.clone()],
         AddressType::Staking(staker, owner) => vec![staker.clone(), owner.clone()],
-        _ => return Ok(()),
-    };
-    
-    for address_key in &address_keys {
-        let cf_addr = _db.cf_handle("addr_index").expect("Address_index column family not found");
+        _ => Vec::new(),
+    }
+}
+
+fn handle_address(commit: &mut BlockCommit, address_type: &AddressType, reversed_txid: &Vec<u8>, tx_out_index: u32, value: i64, height: i32) -> Result<(), io::Error> {
+    for address_key in &address_keys(address_type) {
         let mut key_address = vec![b'a']; 
         key_address.extend_from_slice(address_key.as_bytes());
-        let existing_data = _db.get_cf(cf_addr, &key_address).map_err(from_rocksdb_error)?;
+        let existing_data = commit.get_cf("addr_index", &key_address)?;
         let mut existing_utxos = existing_data.as_deref().map_or(Vec::new(), deserialize_utxos);
         existing_utxos.push((reversed_txid.clone(), tx_out_index.into()));
-        _db.put_cf(cf_addr, &key_address, &serialize_utxos(&existing_utxos)).map_err(from_rocksdb_error)?;
+        commit.put_cf("addr_index", &key_address, &serialize_utxos(&existing_utxos));
+        update_address_balance(commit, address_key, height, value, 0)?;
+        journal::append(commit, JournalEvent::AddressDelta {
+            address: address_key.clone(),
+            txid: hex::encode(reversed_txid),
+            vout: tx_out_index,
+            delta: value,
+        })?;
     }
 
     Ok(())
 }
 
-fn process_transaction(mut reader: &mut io::BufReader<&File>, block_version: u32, block_hash: &[u8], _db: &DB) -> Result<(), io::Error> {
-    let tx_amt = read_varint(reader)?;
-    for _ in 0..tx_amt {
-        let start_pos = reader.stream_position()?;
-
-        let tx_ver_out = reader.read_u16::<LittleEndian>()?;
-        let tx_type = reader.read_u16::<LittleEndian>()?;
-
-        if block_version == 11 {
-            if tx_ver_out < 3 {
-                process_transaction_v1(reader, tx_ver_out.try_into().unwrap(), block_version, block_hash, _db, start_pos)?;
-            } else {
-                parse_sapling_tx_data(reader, start_pos, _db)?;
-            }
-        } else if (tx_ver_out <= 2 && block_version < 11) || (tx_ver_out > 1 && block_version > 7) {
-            if tx_ver_out <= 2 {
-                process_transaction_v1(reader, tx_ver_out.try_into().unwrap(), block_version, block_hash, _db, start_pos)?;
-            } else {
-                parse_sapling_tx_data(reader, start_pos, _db)?;
-            }
+#[allow(clippy::too_many_arguments)]
+fn process_transaction<R: BufRead + Seek>(reader: &mut R, block_version: u32, hash_merkle_root: &[u8; 32], final_sapling_root: Option<[u8; 32]>, height: i32, block_time: u32, settings: &IndexSettings, commit: &mut BlockCommit) -> Result<BlockStats, io::Error> {
+    let decoded = parser::tx::decode_transactions(reader, block_version)?;
+    let mut issuance: i64 = 0;
+    let mut stats = BlockStats::default();
+
+    // A merkle root that doesn't match means a transaction was parsed with the wrong length.
+    // Blocks with unsupported transactions have no txid for them and can't be checked.
+    let txids: Option<Vec<Vec<u8>>> = decoded.iter().map(|tx| tx.as_ref().map(|tx| reverse_bytes(&tx.txid))).collect();
+    if let Some(txids) = txids {
+        let computed = merkle_root(&txids);
+        if computed[..] != hash_merkle_root[..] {
+            println!("Merkle root mismatch at height {}: header {} computed {}", height,
+                hex::encode(reverse_bytes(hash_merkle_root)), hex::encode(reverse_bytes(&computed)));
+            record_merkle_mismatch(commit, height, hash_merkle_root, &computed)?;
         }
     }
-    Ok(())
-}
-
-fn process_transaction_v1(reader: &mut io::BufReader<&File>, tx_ver_out: i16, block_version: u32, block_hash: &[u8], _db: &DB, start_pos: u64) -> Result<(), io::Error> {
-    let cf_transactions = _db.cf_handle("transactions").expect("Transaction column family not found");
-    let cf_pubkey = _db.cf_handle("pubkey").expect("Pubkey column family not found");
-    let cf_utxo = _db.cf_handle("utxo").expect("UTXO column family not found");
-    let input_count = read_varint(reader)?;
-
-    let inputs = (0..input_count)
-    .map(|i| {
-        let mut coinbase = None;
-        let mut prev_output = None;
-        let mut script = None;
-
-        match (block_version, tx_ver_out) {
-            (ver, 2) if ver < 3 => {
-                let mut buffer = [0; 26];
-                reader.read_exact(&mut buffer)?;
-                coinbase = Some(buffer.to_vec());
-            }
-            _ => {
-                prev_output = Some(read_outpoint(reader)?);
-                script = Some(read_script(reader)?);
-            }
-        }
-
-        let sequence = reader.read_u32::<LittleEndian>()?;
-        Ok(CTxIn {
-            prevout: prev_output,
-            script_sig: CScript { script: script.unwrap_or_default() }, 
-            sequence,
-            index: i,
-            coinbase,
-        })
-    })
-    .collect::<Result<Vec<_>, std::io::Error>>()?;
-
-    let output_count = read_varint(reader)?;
-    let mut general_address_type = if input_count == 1 && output_count == 1 {
-        AddressType::CoinBaseTx
-    } else if output_count > 1 {
-        AddressType::CoinStakeTx
-    } else {
-        AddressType::Nonstandard
-    };
-    let outputs = (0..output_count)
-        .map(|i| {
-            let value = reader.read_i64::<LittleEndian>()?;
-            let script = read_script(reader)?;
-            let address_type = get_address_type(&CTxOut {
-                value,
-                script_length: script.len().try_into().unwrap(),
-                script_pubkey: CScript { script: script.clone() }, // Cloned because we need it again
-                index: i,
-                address: Vec::new(), // Temporary dummy value
-            }, &general_address_type);
-            let addresses = address_type_to_string(Some(address_type.clone()));
-
-            Ok(CTxOut {
-                value,
-                script_length: script.len().try_into().unwrap(),
-                script_pubkey: CScript { script },
-                index: i,
-                address: addresses, // directly assign the Vec<String>
-            })
-        })
-        .collect::<Result<Vec<_>, std::io::Error>>()?;
-
-    let lock_time_buff = reader.read_u32::<LittleEndian>()?;
-
-    let transaction = CTransaction {
-        version: tx_ver_out, 
-        inputs,
-        outputs: outputs.clone(),
-        lock_time: lock_time_buff, 
-    };
-
-    let end_pos: u64 = set_end_pos(reader, start_pos)?;
-    let tx_bytes: Vec<u8> = get_txid_bytes(reader, start_pos, end_pos)?;
-    //println!("Tx Bytes: {:?}", hex::encode(&tx_bytes));
-    let reversed_txid: Vec<u8> = hash_txid(&tx_bytes)?;
-
-    println!("Transaction ID: {:?}", hex::encode(&reversed_txid));
-
-    let mut key_pubkey = vec![b'p'];
-    for tx_out in &transaction.outputs {
-        let address_type = get_address_type(tx_out, &general_address_type);
-
-        // Associate by these with UTXO set
-        handle_address(_db, &address_type, &reversed_txid, tx_out.index.try_into().unwrap())?;
-
-        // 'p' + scriptpubkey -> list of (txid, output_index)
-        key_pubkey.extend_from_slice(&tx_out.script_pubkey.script); 
 
-        // Fetch existing UTXOs
-        let existing_data_option = _db.get(&key_pubkey);
-        if let Ok(Some(existing_data)) = existing_data_option {
-            let mut existing_utxos = deserialize_utxos(&existing_data);
-            // Add new UTXO
-            existing_utxos.push((reversed_txid.clone(), tx_out.index));
+    // Index updates are applied in block order so spends see earlier outputs
+    let mut utxo_commitment = commitment::load(commit)?;
+    let mut anchors: Vec<Vec<u8>> = Vec::new();
+    let mut fees: Vec<(i64, u32)> = Vec::new();
+    for (tx_index, tx) in decoded.into_iter().enumerate() {
+        let indexed = match tx {
+            Some(tx) => apply_transaction(commit, tx, height, tx_index as u32, settings, &mut utxo_commitment)?,
+            None => IndexedTx::default(),
+        };
 
-            // Store the updated UTXOs
-            let serialized_utxos = serialize_utxos(&existing_utxos);
-            _db.put_cf(cf_pubkey, &key_pubkey, &serialized_utxos).unwrap();
+        // New coins are only created by the coinbase and, on PoS blocks, the
+        // coinstake. As in PIVX's IsProofOfStake, a block is PoS when its second
+        // transaction is a coinstake, whatever the height.
+        let is_block_coinstake = tx_index == 1 && indexed.is_coinstake;
+        if tx_index == 0 || is_block_coinstake {
+            issuance += indexed.value_out() - indexed.value_in;
         }
-        // Create a UTXO identifier (txid + output index)
-        let mut key_utxo = vec![b'u'];
-        key_utxo.extend_from_slice(&hex::encode(&reversed_txid).into_bytes());
-        let utxos_to_serialize = vec![(reversed_txid.clone(), tx_out.index)];
-        _db.put_cf(cf_utxo, &key_utxo, &serialize_utxos(&utxos_to_serialize)).unwrap();
-    }
-
-    for tx_in in &transaction.inputs {
-        let mut key = vec![b't'];
-        if let Some(actual_prevout) = &tx_in.prevout {
-            key.extend_from_slice(&actual_prevout.hash.as_bytes());
-        }
-        let spent_output: Option<&CTxOut> = None;
-        let tx_data_option = _db.get_cf(cf_transactions, &key).unwrap();
-        if let Some(tx_data) = tx_data_option {
-            let referenced_transaction = deserialize_transaction(&tx_data, block_version).unwrap();
-        
-            if let Some(prevout) = &tx_in.prevout {
-                let output = &referenced_transaction.outputs[prevout.n as usize];
-                let address_type = get_address_type(output, &general_address_type);
-        
-                let _ = remove_utxo_addr(_db, &address_type, &prevout.hash, prevout.n);
+        if is_block_coinstake {
+            store_stake_info(commit, height, &StakeInfo::from_coinstake(&indexed))?;
+            stakers::record_block(commit, height, block_time, &indexed)?;
+        } else if tx_index > 0 && indexed.size > 0 {
+            // Transactions spending outputs we never indexed would show a negative fee
+            let fee = indexed.value_in + indexed.sapling_value_balance - indexed.value_out();
+            if fee >= 0 {
+                fees.push((fee, indexed.size));
             }
         }
-        let mut key_utxo = vec![b'u'];
-        if let Some(actual_prevout) = &tx_in.prevout {
-            key.extend_from_slice(&actual_prevout.hash.as_bytes());
+
+        for tx_out in &indexed.outputs {
+            stats.record_output(tx_out, settings.dust_relay_fee);
         }
-        if let Ok(Some(data)) = _db.get_cf(cf_pubkey, &key_utxo) {
-            let mut utxos = deserialize_utxos(&data);
-    
-            // Remove the UTXO that matches the current transaction's input
-            if let Some(prevout) = &tx_in.prevout {
-                let _hash = &prevout.hash;
-                let _n = prevout.n;
-                if let Some(pos) = utxos.iter().position(|(txid, index)| *txid == _hash.as_bytes() && *index == _n as u64) {
-                    utxos.remove(pos);
-                }
-            }
-    
-            // Serialize the updated list of UTXOs and store it back in the database
-            if !utxos.is_empty() {
-                _db.put_cf(cf_pubkey, &key_pubkey, &serialize_utxos(&utxos)).unwrap();
-            } else {
-                _db.delete_cf(cf_pubkey, &key_pubkey);
+        // valueBalance is what leaves the shield pool, so shielding is its negation
+        stats.shield_net_flow -= indexed.sapling_value_balance;
+        if indexed.shield_spends > 0 || indexed.shield_outputs > 0 {
+            stats.shield_txs += 1;
+            stats.shield_spends += indexed.shield_spends;
+            stats.shield_outputs += indexed.shield_outputs;
+        }
+        for anchor in indexed.shield_anchors {
+            if !anchors.contains(&anchor) {
+                anchors.push(anchor);
             }
         }
-    
-        // Remove the referenced UTXO from the UTXO set
-        _db.delete_cf(cf_utxo, &key_utxo).unwrap();
     }
 
-    // 't' + txid -> tx_bytes
-    let mut key = vec![b't'];
-    key.extend_from_slice(&reversed_txid);
-    _db.put_cf(cf_transactions, &key, &tx_bytes).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    stats.record_fees(&fees);
+    store_block_issuance(commit, height, issuance)?;
+    commitment::store(commit, height, &utxo_commitment);
+    // Blocks before the Sapling upgrade have no commitment tree
+    if let Some(final_sapling_root) = final_sapling_root {
+        store_shield_info(commit, height, &final_sapling_root, stats.shield_outputs, anchors)?;
+    }
+    Ok(stats)
+}
 
-    reader.seek(SeekFrom::Start(end_pos))?;
+fn store_shield_info(commit: &mut BlockCommit, height: i32, final_sapling_root: &[u8; 32], new_notes: u32, anchors: Vec<Vec<u8>>) -> Result<(), io::Error> {
+    // Every shield output appends one note commitment to the tree
+    let tree_size = match commit.get_cf("chain_state", b"sapling_tree_size")? {
+        Some(data) if data.len() == 8 => u64::from_le_bytes(data[..8].try_into().unwrap()),
+        _ => 0,
+    } + new_notes as u64;
 
+    let info = ShieldBlockInfo {
+        tree_size,
+        final_root: reverse_bytes(final_sapling_root),
+        anchors,
+    };
+    let data = bincode::serialize(&info)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    // block_height -> ShieldBlockInfo
+    commit.put_cf("shield", &height.to_le_bytes(), &data);
+    commit.put_cf("chain_state", b"sapling_tree_size", &tree_size.to_le_bytes());
     Ok(())
 }
 
-fn get_address_type(tx_out: &CTxOut, general_address_type: &AddressType) -> AddressType {
-    let address_type = if !tx_out.script_pubkey.script.is_empty() {
-        scriptpubkey_to_address(&tx_out.script_pubkey).unwrap_or_else(|| general_address_type.clone())
-    } else {
-        general_address_type.clone()
-    };
-    address_type
+// Shield tree state after the block at `height`, None before the Sapling upgrade
+fn shield_info_at(db: &DB, height: i32) -> io::Result<Option<ShieldBlockInfo>> {
+    let cf_shield = db.cf_handle("shield").expect("Shield column family not found");
+    match db.get_cf(cf_shield, height.to_le_bytes()).map_err(from_rocksdb_error)? {
+        Some(data) => bincode::deserialize(&data).map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        None => Ok(None),
+    }
 }
 
-fn get_txid_bytes<R: Read>(reader: &mut R, start_pos: u64, end_pos: u64) -> Result<Vec<u8>, io::Error> where R: Seek {
-    // Calculate tx_size
-    let tx_size = (end_pos - start_pos) as usize;
-    let mut tx_bytes = vec![0u8; tx_size];
-    // Read the transaction bytes
-    reader.seek(SeekFrom::Start(start_pos))?;
-    reader.read_exact(&mut tx_bytes)?;
-
-    Ok(tx_bytes)
+// Flag a block whose transactions don't hash to its merkle root so it can be re-indexed
+fn record_merkle_mismatch(commit: &mut BlockCommit, height: i32, header_root: &[u8; 32], computed_root: &[u8]) -> Result<(), io::Error> {
+    // 'q' + block_height -> header merkle root, computed merkle root
+    let mut key = vec![b'q'];
+    key.extend_from_slice(&height.to_le_bytes());
+    let mut value = header_root.to_vec();
+    value.extend_from_slice(computed_root);
+    commit.put_cf("blocks", &key, &value);
+
+    let mismatches = match commit.get_cf("chain_state", b"merkle_mismatches")? {
+        Some(data) if data.len() == 8 => u64::from_le_bytes(data[..8].try_into().unwrap()),
+        _ => 0,
+    } + 1;
+    commit.put_cf("chain_state", b"merkle_mismatches", &mismatches.to_le_bytes());
+    Ok(())
 }
 
-fn set_end_pos<R: Read + Seek>(reader: &mut R, start_pos: u64) -> Result<u64, io::Error> {
-    let end_pos = reader.stream_position()?;
-    reader.seek(SeekFrom::Start(start_pos))?;
-    Ok(end_pos)
+// Every recorded mismatch as (height, header root, computed root), both in display byte order
+fn merkle_mismatches(db: &DB) -> io::Result<Vec<(i32, String, String)>> {
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let mut mismatches = Vec::new();
+    for item in db.iterator_cf(cf_blocks, IteratorMode::From(b"q", Direction::Forward)) {
+        let (key, value) = item.map_err(from_rocksdb_error)?;
+        if key[0] != b'q' {
+            break;
+        }
+        if key.len() != 5 || value.len() != 64 {
+            continue;
+        }
+        let height = i32::from_le_bytes(key[1..].try_into().unwrap());
+        mismatches.push((height, hex::encode(reverse_bytes(&value[..32])), hex::encode(reverse_bytes(&value[32..]))));
+    }
+    mismatches.sort_by_key(|(height, _, _)| *height);
+    Ok(mismatches)
 }
 
-fn hash_txid(tx_bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
-    //Create TXID by hashing twice and reversing result
-    let first_hash = Sha256::digest(&tx_bytes);
-    let txid = Sha256::digest(&first_hash);
-    let reversed_txid: Vec<_> = txid.iter().rev().cloned().collect();
-
-    Ok(reversed_txid)
+fn store_stake_info(commit: &mut BlockCommit, height: i32, info: &StakeInfo) -> Result<(), io::Error> {
+    let data = bincode::serialize(info)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    // 'k' + block_height -> StakeInfo
+    let mut key = vec![b'k'];
+    key.extend_from_slice(&height.to_le_bytes());
+    commit.put_cf("blocks", &key, &data);
+    Ok(())
 }
 
-fn read_outpoint(reader: &mut dyn Read) -> io::Result<COutPoint> {
-    // Set size for hash
-    let mut hash = [0u8; 32];
-    // Read hash
-    reader.read_exact(&mut hash)?;
-    // Read output index
-    let n = reader.read_u32::<LittleEndian>()?;
-    let reversed_bytes = reverse_bytes(&hash);
-    let hex_hash = hex::encode(&reversed_bytes);
-
-    Ok(COutPoint { hash: hex_hash, n })
+// Coinstake details of the PoS block at `height`
+fn stake_info_at(db: &DB, height: i32) -> io::Result<Option<StakeInfo>> {
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let mut key = vec![b'k'];
+    key.extend_from_slice(&height.to_le_bytes());
+    match db.get_cf(cf_blocks, &key).map_err(from_rocksdb_error)? {
+        Some(data) => bincode::deserialize(&data).map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        None => Ok(None),
+    }
 }
 
-fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
-    let varint = VarInt::consensus_decode(reader)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-    Ok(varint.0)
+fn store_block_stats(commit: &mut BlockCommit, height: i32, stats: &BlockStats) -> Result<(), io::Error> {
+    // block_height -> version byte + BlockStats
+    commit.put_cf("stats", &height.to_le_bytes(), &stats.encode()?);
+    Ok(())
 }
 
-fn read_4_bytes(reader: &mut dyn BufRead) -> io::Result<[u8; 4]> {
-    let mut buffer = [0u8; 4];
-    let peek_buffer = reader.fill_buf()?;
-    if peek_buffer.len() < 4 {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "Insufficient bytes available in the stream",
-        ));
+// Rewrite block stats stored before they carried a version byte in the
+// current layout. Returns the rows rewritten and the rows left undecodable.
+fn migrate_block_stats(db: &DB) -> io::Result<(usize, usize)> {
+    let cf_stats = db.cf_handle("stats").expect("Stats column family not found");
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let mut batch = WriteBatch::default();
+    let (mut rewritten, mut undecodable) = (0, 0);
+    for item in db.iterator_cf(cf_stats, IteratorMode::Start) {
+        let (key, value) = item.map_err(from_rocksdb_error)?;
+        // Only block_height keys hold stats
+        if key.len() != 4 {
+            continue;
+        }
+        match BlockStats::decode(&value).map(|stats| stats.encode()).transpose()? {
+            Some(encoded) if encoded[..] == value[..] => {}
+            Some(encoded) => {
+                batch.put_cf(cf_stats, &key, encoded);
+                rewritten += 1;
+            }
+            None => undecodable += 1,
+        }
+        if batch.len() >= 100_000 {
+            db.write(std::mem::take(&mut batch)).map_err(from_rocksdb_error)?;
+        }
     }
-
-    // Copy the 4 bytes into the buffer
-    buffer.copy_from_slice(&peek_buffer[..4]);
-    Ok(buffer)
+    batch.put_cf(cf_state, b"block_stats_versioned", [1u8]);
+    db.write(batch).map_err(from_rocksdb_error)?;
+    Ok((rewritten, undecodable))
+}
+
+// Smallest value an output may carry before relaying it costs more than it is worth
+fn dust_threshold(tx_out: &CTxOut, dust_relay_fee: i64) -> i64 {
+    let script_len = tx_out.script_pubkey.script.len();
+    let varint_len = match script_len {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    };
+    // Serialized output plus the size of the input that would later spend it
+    let size = (8 + varint_len + script_len + 148) as i64;
+    size * dust_relay_fee / 1000
 }
 
-fn parse_sapling_tx_data(reader: &mut io::BufReader<&File>, start_pos: u64, _db: &DB) -> Result<SaplingTxData, io::Error> {
-    let cf_transactions = _db.cf_handle("transactions").expect("Transaction column family not found");
-    let cf_pubkey = _db.cf_handle("pubkey").expect("Pubkey column family not found");
-    let cf_utxo = _db.cf_handle("utxo").expect("UTXO column family not found");
-
-    // Set empty vectors for later access
-    let mut inputs: Vec<CTxIn> = Vec::new();
-    let mut outputs: Vec<CTxOut> = Vec::new();
-    // Potential Vin Vector
-    let input_count = read_varint2(reader)? as u64;
-    println!("Input Count: {}", input_count);
-
-    if input_count > 0 {
-        inputs = (0..input_count)
-            .map(|i| {
-                let coinbase = None;
-                let prev_output = read_outpoint(reader)?;
-                let script = read_script(reader)?;
-                let sequence = reader.read_u32::<LittleEndian>()?;
-                Ok(CTxIn {
-                    prevout: Some(prev_output),
-                    script_sig: CScript { script },
-                    sequence,
-                    index: i,
-                    coinbase,
-                })
-            })
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-    }
-
-    let output_count = read_varint(reader)?;
-    println!("Output Count: {}", output_count);
-    let mut general_address_type = if input_count == 1 && output_count == 1 {
-        AddressType::CoinBaseTx
-    } else if output_count > 1 {
-        AddressType::CoinStakeTx
-    } else {
-        AddressType::Nonstandard
+fn store_block_issuance(commit: &mut BlockCommit, height: i32, actual: i64) -> Result<(), io::Error> {
+    // The subsidy schedule is mainnet's; other networks only record what was issued
+    let expected = match chain_params::params().network {
+        chain_params::Network::Mainnet => consensus::block_value(height),
+        _ => actual,
     };
+    if actual != expected {
+        events::append(commit, EventType::IssuanceDivergence,
+            format!("height {}: expected {} actual {}", height, expected, actual))?;
+    }
 
-    if output_count > 0 {
-        outputs = (0..output_count)
-            .map(|i| {
-                let value = reader.read_i64::<LittleEndian>()?;
-                let script = read_script(reader)?;
-                let address_type = get_address_type(&CTxOut {
-                    value,
-                    script_length: script.len().try_into().unwrap(),
-                    script_pubkey: CScript { script: script.clone() },
-                    index: i,
-                    address: Vec::new(),
-                }, &general_address_type);
-                let addresses = address_type_to_string(Some(address_type.clone()));
-
-                Ok(CTxOut {
-                    value,
-                    script_length: script.len().try_into().unwrap(),
-                    script_pubkey: CScript { script },
-                    index: i,
-                    address: addresses, // directly assign the Vec<String>
-                })
-            })
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-    }
-
-    let lock_time_buff = reader.read_u32::<LittleEndian>()?;
-    println!("Lock Time: {}", lock_time_buff);
-    // Hacky fix for getting proper values/spends/outputs for Sapling
-    let value_count = read_varint(reader)?;
-    let value = reader.read_i64::<LittleEndian>()?;
-    println!("Value: {}", value);
-    // Read the SaplingTxData
-    let vshield_spend = parse_vshield_spends(reader)?;
-    let vshield_output = parse_vshield_outputs(reader)?;
-    // Read the binding_sig as an array of unsigned chars max size 64
-    let mut binding_sig = [0u8; 64];
-    reader.read_exact(&mut binding_sig)?;
-
-    // Create and return the SaplingTxData struct
-    let sapling_tx_data = SaplingTxData {
-        value,
-        vshield_spend,
-        vshield_output,
-        binding_sig: binding_sig.to_vec(),
-    };
+    // 'e' + block_height -> expected subsidy, actual issuance
+    let mut key = vec![b'e'];
+    key.extend_from_slice(&height.to_le_bytes());
+    let mut value = Vec::with_capacity(16);
+    value.extend_from_slice(&expected.to_le_bytes());
+    value.extend_from_slice(&actual.to_le_bytes());
+    commit.put_cf("blocks", &key, &value);
+    Ok(())
+}
 
-    let serialized_data = bincode::serialize(&sapling_tx_data)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    let end_pos: u64 = set_end_pos(reader, start_pos)?;
-    let tx_bytes: Vec<u8> = get_txid_bytes(reader, start_pos, end_pos)?;
-    //println!("Tx Bytes: {:?}", hex::encode(&tx_bytes));
-    let reversed_txid: Vec<u8> = hash_txid(&tx_bytes)?;
-    println!("Sapling TXID: {:?}", hex::encode(&reversed_txid));
-    println!("{:?}", sapling_tx_data);
-
-    let mut key_utxo = vec![b'u'];
-    let mut referenced_utxo_id: Option<String> = None;
-    for tx_in in &inputs {
-        let mut key_pubkey = vec![b'p'];
-        if let Some(prevout) = &tx_in.prevout {
-            key_pubkey.extend_from_slice(&prevout.hash.as_bytes());
-            referenced_utxo_id = Some(hex::encode(&prevout.hash));
+// Move issuance records from 'i' + height, where databases indexed before
+// they had their own prefix kept them next to the 'i' + hash markers. Only
+// the 5 byte keys are issuance records.
+fn migrate_issuance_keys(db: &DB) -> io::Result<usize> {
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let mut batch = WriteBatch::default();
+    let mut moved = 0;
+    for item in db.iterator_cf(cf_blocks, IteratorMode::From(b"i", Direction::Forward)) {
+        let (key, value) = item.map_err(from_rocksdb_error)?;
+        if key[0] != b'i' {
+            break;
         }
+        if key.len() == 5 {
+            let mut new_key = key.to_vec();
+            new_key[0] = b'e';
+            batch.put_cf(cf_blocks, new_key, &value);
+            batch.delete_cf(cf_blocks, &key);
+            moved += 1;
+        }
+        if batch.len() >= 100_000 {
+            db.write(std::mem::take(&mut batch)).map_err(from_rocksdb_error)?;
+        }
+    }
+    batch.put_cf(cf_state, b"issuance_moved", [1u8]);
+    db.write(batch).map_err(from_rocksdb_error)?;
+    Ok(moved)
+}
 
-        if let Ok(Some(data)) = _db.get_cf(cf_pubkey, &key_pubkey) {
-            let mut utxos = deserialize_utxos(&data);
-
+fn apply_transaction(commit: &mut BlockCommit, tx: DecodedTx, height: i32, tx_index: u32, settings: &IndexSettings, utxo_commitment: &mut UtxoCommitment) -> Result<IndexedTx, io::Error> {
+    let transaction = tx.transaction;
 
-            if let Some(prevout) = &tx_in.prevout {
-                if let Some(pos) = utxos.iter().position(|(txid, index)| *txid == prevout.hash.as_bytes() && *index == prevout.n as u64) {
-                    utxos.remove(pos);
-                }
-            }
+    let size = tx.tx_bytes.len() as u32;
+    let mut addresses = Vec::new();
+    let (value_in, coin_age) = spend_inputs(commit, &transaction.inputs, &tx.txid, height, settings, utxo_commitment, &mut addresses)?;
+    index_outputs(commit, &transaction.outputs, &tx.address_types, &tx.txid, height, settings, utxo_commitment)?;
 
-            if !utxos.is_empty() {
-                _db.put_cf(cf_pubkey, &key_pubkey, &serialize_utxos(&utxos)).unwrap();
-            } else {
-                _db.delete_cf(cf_pubkey, &key_pubkey).unwrap();
-            }
+    if settings.role.builds_address_index() {
+        addresses.extend(tx.address_types.iter().flat_map(address_keys));
+        addresses.sort();
+        addresses.dedup();
+        for address in &addresses {
+            tx_history::record(commit, address, height, tx_index, &tx.txid);
         }
-
-        key_utxo.extend_from_slice(referenced_utxo_id.as_ref().unwrap().as_bytes());
-        _db.delete_cf(cf_utxo, &key_utxo).unwrap();
     }
 
-    for tx_out in &outputs {
-        let address_type = get_address_type(tx_out, &general_address_type);
-        handle_address(_db, &address_type, &reversed_txid, tx_out.index.try_into().unwrap())?;
-
-        let mut key_pubkey = vec![b'p'];
-        key_pubkey.extend_from_slice(&tx_out.script_pubkey.script);
-
-        let existing_data_option = _db.get(&key_pubkey);
-        if let Ok(Some(existing_data)) = existing_data_option {
-            let mut existing_utxos = deserialize_utxos(&existing_data);
-            existing_utxos.push((reversed_txid.clone(), tx_out.index));
-
-            let serialized_utxos = serialize_utxos(&existing_utxos);
-            _db.put_cf(cf_pubkey, &key_pubkey, &serialized_utxos).unwrap();
-        }
-
-        key_utxo.extend_from_slice(&reversed_txid);
-        let utxos_to_serialize = vec![(reversed_txid.clone(), tx_out.index)];
-        _db.put_cf(cf_utxo, &key_utxo, &serialize_utxos(&utxos_to_serialize)).unwrap();
-    }
-
-    // 't' + txid -> serialized_data
-    let mut key = vec![b't'];
-    key.extend_from_slice(&reversed_txid);
-    _db.put_cf(cf_transactions, &key, &serialized_data).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-    Ok(sapling_tx_data)
-}
-
-fn parse_vshield_spends(reader: &mut io::BufReader<&File>) -> Result<Vec<VShieldSpend>, io::Error> {
-    // Read the number of vShieldSpend entries
-    let count = read_varint(reader)? as usize;
-    //println!("vShieldSpend Count: {}", count);
-    if count == 0 {
-        return Ok(Vec::new());
-    }
-
-    // Define buffer sizes for respective fields
-    let buff_32 = [0u8; 32];
-    let buff_64 = [0u8; 64];
-    let buff_192 = [0u8; 192];
-
-    // Read each vShieldSpend entry
-    let mut vshield_spends = Vec::with_capacity(count.try_into().unwrap());
-    for _ in 0..count {
-        // Read each field
-        let mut cv = buff_32;
-        reader.read_exact(&mut cv)?;
-        let mut anchor = buff_32;
-        reader.read_exact(&mut anchor)?;
-        let mut nullifier = buff_32;
-        reader.read_exact(&mut nullifier)?;
-        let mut rk = buff_32;
-        reader.read_exact(&mut rk)?;
-        let mut proof = buff_192;
-        reader.read_exact(&mut proof)?;
-        let mut spend_auth_sig = buff_64;
-        reader.read_exact(&mut spend_auth_sig)?;
-
-        // Create and return the VShieldSpend struct
-        let vshield_spend = VShieldSpend {
-            cv: reverse_bytes(&cv),
-            anchor: reverse_bytes(&anchor),
-            nullifier: reverse_bytes(&nullifier),
-            rk: reverse_bytes(&rk),
-            proof: proof.to_vec(),
-            spend_auth_sig: spend_auth_sig.to_vec(),
-        };
-        vshield_spends.push(vshield_spend);
-        //println!("{:?}", vshield_spends);
-    }
-    Ok(vshield_spends)
-}
-
-fn parse_vshield_outputs(reader: &mut io::BufReader<&File>) -> Result<Vec<VShieldOutput>, io::Error> {
-    // Read the number of vShieldOutput entries
-    let count = read_varint(reader)? as usize;
-    //println!("vShieldOutput Count: {}", count);
-    if count == 0 {
-        return Ok(Vec::new());
-    }
-
-    // Define buffer sizes for respective fields
-    let buff_32 = [0u8; 32];
-    let buff_80 = [0u8; 80];
-    let buff_192 = [0u8; 192];
-    let buff_580 = [0u8; 580];
-
-    // Read each vShieldOutput entry
-    let mut vshield_outputs = Vec::with_capacity(count);
-    for _ in 0..count {
-        // Read each field
-        let mut cv = buff_32;
-        reader.read_exact(&mut cv)?;
-        let mut cmu = buff_32;
-        reader.read_exact(&mut cmu)?;
-        let mut ephemeral_key = buff_32;
-        reader.read_exact(&mut ephemeral_key)?;
-        let mut enc_ciphertext = buff_580;
-        reader.read_exact(&mut enc_ciphertext)?;
-        let mut out_ciphertext = buff_80;
-        reader.read_exact(&mut out_ciphertext)?;
-        let mut proof = buff_192;
-        reader.read_exact(&mut proof)?;
-
-        // Create and return the VShieldOutput struct
-        let vshield_output = VShieldOutput {
-            cv: reverse_bytes(&cv),
-            cmu: reverse_bytes(&cmu),
-            ephemeral_key: reverse_bytes(&ephemeral_key),
-            enc_ciphertext: enc_ciphertext.to_vec(),
-            out_ciphertext: out_ciphertext.to_vec(),
-            proof: proof.to_vec(),
+    if settings.role.builds_tx_indexes() {
+        // 't' + txid -> tx_bytes, or the serialized SaplingTxData for sapling transactions
+        let value = match &tx.sapling {
+            Some(sapling_tx_data) => bincode::serialize(sapling_tx_data)
+                .map_err(|e| io::Error::other(e.to_string()))?,
+            None => tx.tx_bytes,
         };
-        vshield_outputs.push(vshield_output);
-        //println!("{:?}", vshield_outputs);
+        let mut key = vec![b't'];
+        key.extend_from_slice(&tx.txid);
+        commit.put_cf("transactions", &key, &value);
+        journal::append(commit, JournalEvent::TxIndexed { txid: hex::encode(&tx.txid), height })?;
     }
 
-    Ok(vshield_outputs)
+    Ok(match tx.sapling {
+        Some(sapling_tx_data) => IndexedTx {
+            txid: tx.txid,
+            size,
+            value_in,
+            coin_age,
+            outputs: transaction.outputs,
+            is_coinstake: false,
+            sapling_value_balance: sapling_tx_data.value,
+            shield_spends: sapling_tx_data.vshield_spend.len() as u32,
+            shield_outputs: sapling_tx_data.vshield_output.len() as u32,
+            shield_anchors: sapling_tx_data.vshield_spend.iter().map(|spend| spend.anchor.clone()).collect(),
+        },
+        None => IndexedTx {
+            txid: tx.txid,
+            size,
+            value_in,
+            coin_age,
+            // A coinstake spends inputs and marks itself with an empty first of at least two outputs
+            is_coinstake: !transaction.inputs.is_empty()
+                && transaction.outputs.len() >= 2
+                && transaction.outputs[0].value == 0 && transaction.outputs[0].script_pubkey.script.is_empty(),
+            outputs: transaction.outputs,
+            ..Default::default()
+        },
+    })
 }
 
-fn parse_payload_data(reader: &mut io::BufReader<&File>) -> Result<Option<Vec<u8>>, io::Error> {
-    let mut prefix_found = false;
-    let mut byte_count = 0;
-    let mut buffer = [0u8; 4];
+// Add a transaction's outputs to the UTXO set and, for full indexes, the address/pubkey indexes
+fn index_outputs(commit: &mut BlockCommit, outputs: &[CTxOut], address_types: &[AddressType], reversed_txid: &Vec<u8>, height: i32, settings: &IndexSettings, utxo_commitment: &mut UtxoCommitment) -> Result<(), io::Error> {
+    for (tx_out, address_type) in outputs.iter().zip(address_types) {
+        if settings.index_text {
+            if let Some(data) = scripts::op_return_data(&tx_out.script_pubkey.script) {
+                text_search::index(commit, reversed_txid, &data);
+            }
+        }
 
-    // Read byte by byte until the PREFIX is found or the end of the stream is reached
-    while !prefix_found && reader.read_exact(&mut buffer).is_ok() {
-        byte_count += 1;
-        if buffer == PREFIX {
-            prefix_found = true;
+        if settings.tag_unspendable && scripts::classify(&tx_out.script_pubkey.script).is_none()
+            && scripts::is_unspendable(&tx_out.script_pubkey.script)
+        {
+            tag_unspendable_output(commit, reversed_txid, tx_out)?;
+            continue;
         }
-    }
 
-    // Adjust the byte count to exclude the PREFIX sequence
-    if prefix_found && byte_count >= PREFIX.len() {
-        byte_count -= PREFIX.len();
-    }
+        if settings.role.builds_address_index() {
+            // Associate by these with UTXO set
+            handle_address(commit, address_type, reversed_txid, tx_out.index.try_into().unwrap(), tx_out.value, height)?;
+        }
 
-    // Check if the byte count exceeds the maximum payload size
-    if byte_count > MAX_PAYLOAD_SIZE {
-        // Handle the case where the payload exceeds the maximum size
-        Err(io::Error::new(io::ErrorKind::Other, "Payload size exceeds the maximum."))
-    } else {
-        // Return the payload data as Some if prefix was found, None otherwise
-        if prefix_found {
-            let mut payload_data = vec![0u8; byte_count];
-            reader.read_exact(&mut payload_data)?;
-            Ok(Some(payload_data))
-        } else {
-            Ok(None)
+        if settings.role.builds_tx_indexes() {
+            // 'p' + scriptpubkey -> list of (txid, output_index)
+            let mut key_pubkey = vec![b'p'];
+            key_pubkey.extend_from_slice(&tx_out.script_pubkey.script);
+
+            // Fetch existing UTXOs
+            if let Some(existing_data) = commit.get_cf("pubkey", &key_pubkey)? {
+                let mut existing_utxos = deserialize_utxos(&existing_data);
+                // Add new UTXO
+                existing_utxos.push((reversed_txid.clone(), tx_out.index));
+
+                // Store the updated UTXOs
+                let serialized_utxos = serialize_utxos(&existing_utxos);
+                commit.put_cf("pubkey", &key_pubkey, &serialized_utxos);
+            }
         }
+
+        // 'u' + txid + output_index -> value, height, scriptpubkey
+        store_utxo(commit, reversed_txid, tx_out.index.try_into().unwrap(), &UtxoEntry {
+            value: tx_out.value,
+            height,
+            script_pubkey: tx_out.script_pubkey.script.clone(),
+        }, utxo_commitment)?;
     }
+
+    Ok(())
 }
 
-fn read_ldb_block(hash_prev_block: &[u8; 32], header_size: usize) -> Result<Option<i32>, Box<dyn Error>> {
-    // Load the configuration
-    let mut config = Config::default();
-    config.merge(ConfigFile::with_name("config.toml"))?;
-    let ldb_files_dir = config.get::<String>("paths.ldb_dir")?;
-    let ldb_files_path = std::path::Path::new(&ldb_files_dir);
+// 'x' + txid + output_index -> value of an output that can never be spent
+fn tag_unspendable_output(commit: &mut BlockCommit, txid: &[u8], tx_out: &CTxOut) -> Result<(), io::Error> {
+    let mut key = vec![b'x'];
+    key.extend_from_slice(txid);
+    key.extend_from_slice(&(tx_out.index as u32).to_le_bytes());
+    commit.put_cf("transactions", &key, &tx_out.value.to_le_bytes());
+    Ok(())
+}
 
+fn read_ldb_block(ldb_files_path: &Path, hash_prev_block: &[u8; 32]) -> Result<Option<i32>, Box<dyn Error>> {
     // Open the LevelDB database
     let options = LevelDBOptions::new();
     let database: Database<Byte33> = match Database::open(ldb_files_path, options) {
@@ -1144,7 +1486,6 @@ fn read_ldb_block(hash_prev_block: &[u8; 32], header_size: usize) -> Result<Opti
     let read_options: leveldb::options::ReadOptions<'_, Byte33> = LevelDBReadOptions::new();
     let height = match database.get(read_options, key) {
         Ok(Some(value)) => {
-            let value_str = hex::encode(&value);
             match parse_ldb_block(&value) {
                 Ok(Some(height)) => {
                     Some(height)
@@ -1171,25 +1512,6 @@ fn read_ldb_block(hash_prev_block: &[u8; 32], header_size: usize) -> Result<Opti
     Ok(height)
 }
 
-fn reverse_bytes(array: &[u8]) -> Vec<u8> {
-    let mut vec = Vec::from(array);
-    vec.reverse();
-    vec
-}
-
-// Bitcoin normal varint
-pub fn read_varint2<R: Read + ?Sized>(reader: &mut R) -> io::Result<u64> {
-    let first = reader.read_u8()?; // read first length byte
-    let value = match first {
-        0x00..=0xfc => u64::from(first),
-        0xfd => u64::from(reader.read_u16::<LittleEndian>()?),
-        0xfe => u64::from(reader.read_u32::<LittleEndian>()?),
-        0xff => reader.read_u64::<LittleEndian>()?,
-        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid varint")),
-    };
-    Ok(value)
-}
-
 // Bitcoin varint128
 fn read_varint128(data: &[u8]) -> (usize, u64) {
     let mut index = 0;
@@ -1218,7 +1540,7 @@ fn parse_ldb_block(block: &[u8]) -> Result<Option<i32>, Box<dyn Error>> {
     let remaining_data = &block[0..];
 
     // Read version
-    let (bytes_consumed_for_version, version) = read_varint128(remaining_data);
+    let (bytes_consumed_for_version, _) = read_varint128(remaining_data);
 
     // After reading the version, move to the next unread part of remaining_data
     let next_data = &remaining_data[bytes_consumed_for_version..];
@@ -1238,378 +1560,541 @@ fn parse_ldb_block(block: &[u8]) -> Result<Option<i32>, Box<dyn Error>> {
     Ok(Some(incremented_block_height.try_into()?))
 }
 
-fn compute_address_hash(data: &[u8]) -> Vec<u8> {
-    let sha = Sha256::digest(data);
-    Ripemd160::digest(&sha).to_vec()
-}
-
-// Function to convert hash to P2PKH Bitcoin address (with prefix 0x00 for mainnet)
-fn hash_address(hash: &[u8], prefix: u8) -> String {
-    let mut extended_hash = vec![prefix]; // This is 30 in hex, the P2PKH prefix you provided
-    extended_hash.extend_from_slice(hash);
-
-    let checksum = sha256d(&extended_hash);
-    extended_hash.extend_from_slice(&checksum[0..4]);
-
-    bs58::encode(extended_hash).into_string()
-}
-
-fn sha256(data: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    hasher.finalize().to_vec()
-}
-
-fn ripemd160_hash(data: &[u8]) -> Vec<u8> {
-    let mut hasher = Ripemd160::new();
-    hasher.update(data);
-    hasher.finalize().to_vec()
-}
-
-fn sha256d(data: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    let first = hasher.finalize();
-
-    let mut hasher = Sha256::new();
-    hasher.update(&first);
-    hasher.finalize().to_vec()
-}
-
-// Function to parse script_pubkey to a P2PKH address
-fn scriptpubkey_to_p2pkh_address(script: &CScript) -> Option<String> {
-    if script.script.len() == 25 && 
-       script.script[0] == 0x76 && 
-       script.script[1] == 0xa9 && 
-       script.script[2] == 0x14 && 
-       script.script[23] == 0x88 && 
-       script.script[24] == 0xac {
-           let address_hash = &script.script[3..23];
-           Some(hash_address(address_hash, 30))
-    } else {
-        None
-    }
-}
-
-fn scriptpubkey_to_p2sh_address(script: &CScript) -> Option<String> {
-    // OP_HASH160 = 0xa9, followed by a length byte of 20 (0x14 in hexadecimal) and then OP_EQUAL = 0x87
-    if script.script.len() == 23 && script.script[0] == 0xa9 && script.script[1] == 0x14 && script.script[22] == 0x87 {
-        let address_hash = &script.script[2..22];
-        Some(hash_address(address_hash, 13))
-    } else {
-        None
+fn serialize_utxos(utxos: &Vec<(Vec<u8>, u64)>) -> Vec<u8> {
+    let mut serialized = Vec::new();
+    for (txid, index) in utxos {
+        serialized.extend(txid);
+        serialized.extend(&index.to_le_bytes());
     }
+    serialized
 }
 
-fn compress_pubkey(pub_key_bytes: &[u8]) -> Option<Vec<u8>> {
-    match pub_key_bytes.len() {
-        65 if pub_key_bytes[0] == 0x04 => {
-            let x = &pub_key_bytes[1..33];
-            let y = &pub_key_bytes[33..65];
-            let parity = if y[31] % 2 == 0 { 2 } else { 3 };
-            let mut compressed_key: Vec<u8> = vec![parity];
-            compressed_key.extend_from_slice(x);
-            Some(compressed_key)
-        },
-        33 if pub_key_bytes[0] == 0x02 || pub_key_bytes[0] == 0x03 => {
-            // Already compressed, just return as is
-            Some(pub_key_bytes.to_vec())
-        },
-        _ => None
+fn deserialize_utxos(data: &[u8]) -> Vec<(Vec<u8>, u64)> {
+    let mut utxos = Vec::new();
+    // 32 bytes for txid and 8 bytes for index
+    for chunk in data.chunks_exact(40) {
+        let txid = chunk[0..32].to_vec();
+        let index = u64::from_le_bytes(chunk[32..40].try_into().unwrap());
+        utxos.push((txid, index));
     }
+    utxos
 }
 
-fn extract_pubkey_from_script(script: &[u8]) -> Option<&[u8]> {
-    const OP_CHECKSIG: u8 = 0xAC;
-
-    if script.last()? != &OP_CHECKSIG {
-        return None;
-    }
+fn remove_utxo_addr(commit: &mut BlockCommit, address_type: &AddressType, txid: &[u8], index: u32, value: i64, height: i32) -> Result<(), io::Error> {
+    for address_key in &address_keys(address_type) {
+        let mut key_address = vec![b'a']; 
+        key_address.extend_from_slice(address_key.as_bytes());
 
-    match script.len() {
-        67 => Some(&script[1..66]), // skip the OP_PUSHDATA, then take uncompressed pubkey
-        35 => Some(&script[1..34]), // skip the OP_PUSHDATA, then take compressed pubkey
-        _ => None,
-    }
-}
+        // Fetch existing UTXOs associated with this address
+        let existing_data = commit.get_cf("addr_index", &key_address)?;
+        let mut existing_utxos = existing_data.as_deref().map_or(Vec::new(), deserialize_utxos);
 
-fn scriptpubkey_to_p2pk(script: &CScript) -> Option<String> {
-    const OP_DUP: u8 = 0x76;
+        // Find the UTXO to remove
+        if let Some(pos) = existing_utxos.iter().position(|(stored_txid, stored_index)| stored_txid.as_slice() == txid && *stored_index == index as u64) {
+            existing_utxos.remove(pos);
+        }
 
-    if script.script.contains(&OP_DUP) {
-        return None; // Not a P2PK script.
+        // Update or delete the UTXO entry for this address
+        if !existing_utxos.is_empty() {
+            commit.put_cf("addr_index", &key_address, &serialize_utxos(&existing_utxos));
+        } else {
+            commit.delete_cf("addr_index", &key_address);
+        }
+        update_address_balance(commit, address_key, height, 0, value)?;
+        journal::append(commit, JournalEvent::AddressDelta {
+            address: address_key.clone(),
+            txid: hex::encode(txid),
+            vout: index,
+            delta: -value,
+        })?;
     }
 
-    let pubkey = extract_pubkey_from_script(&script.script)?;
-
-    let pubkey_compressed = compress_pubkey(pubkey)?;
-    let pubkey_hex: Vec<u8> = hex::encode(&pubkey_compressed).into();
-    let pubkey_hash = compute_address_hash(&pubkey_compressed);
-    let pubkey_addr = hash_address(&pubkey_hash, 30);
-
-    Some(pubkey_addr)
+    Ok(())
 }
 
-fn scriptpubkey_to_staking_address(script: &CScript) -> Option<(String, String)> {
-    const HASH_LEN: usize = 20; // Length of public key hash
-    const OP_CHECKCOLDSTAKEVERIFY: u8 = 0xd2;
-    const OP_CHECKCOLDSTAKEVERIFY_LOF: u8 = 0xd1;
-    const OP_ELSE: u8 = 0x67;
-
-    let pos_checkcoldstakeverify = script.script.iter().position(|&x| x == OP_CHECKCOLDSTAKEVERIFY || x == OP_CHECKCOLDSTAKEVERIFY_LOF)?;
-
-    // Boundary check to avoid panic during slicing
-    if script.script.len() < pos_checkcoldstakeverify + 1 + HASH_LEN {
-        return None;
-    }
+fn utxo_key(txid: &[u8], index: u32) -> Vec<u8> {
+    let mut key = vec![b'u'];
+    key.extend_from_slice(txid);
+    key.extend_from_slice(&index.to_le_bytes());
+    key
+}
 
-    let staker_key_hash = &script.script[(pos_checkcoldstakeverify + 1)..(pos_checkcoldstakeverify + 1 + HASH_LEN)];
+fn serialize_utxo_entry(entry: &UtxoEntry) -> Vec<u8> {
+    let mut serialized = Vec::with_capacity(12 + entry.script_pubkey.len());
+    serialized.extend(&entry.value.to_le_bytes());
+    serialized.extend(&entry.height.to_le_bytes());
+    serialized.extend(&entry.script_pubkey);
+    serialized
+}
 
-    // Find the position of OP_ELSE
-    let pos_else = script.script.iter().position(|&x| x == OP_ELSE)?;
-    if script.script.len() < pos_else + 1 + HASH_LEN {
+fn deserialize_utxo_entry(data: &[u8]) -> Option<UtxoEntry> {
+    if data.len() < 12 {
         return None;
     }
-
-    let owner_key_hash = &script.script[(pos_else + 1)..(pos_else + 1 + HASH_LEN)];
-
-    let staker_address = hash_address(staker_key_hash, 63); // 63 is the prefix for staker P2PKH
-    let owner_address = hash_address(owner_key_hash, 30); // 30 is the prefix for owner P2PKH
-
-    Some((staker_address, owner_address))
+    Some(UtxoEntry {
+        value: i64::from_le_bytes(data[0..8].try_into().unwrap()),
+        height: i32::from_le_bytes(data[8..12].try_into().unwrap()),
+        script_pubkey: data[12..].to_vec(),
+    })
 }
 
-fn scriptpubkey_to_address(script: &CScript) -> Option<AddressType> {
-    // Verify non-empty script
-    if script.script.is_empty() {
-        return Some(AddressType::Nonstandard);
-    }
+fn store_utxo(commit: &mut BlockCommit, txid: &[u8], index: u32, entry: &UtxoEntry, utxo_commitment: &mut UtxoCommitment) -> Result<(), io::Error> {
+    let key = utxo_key(txid, index);
+    let value = serialize_utxo_entry(entry);
+    utxo_commitment.add(&key, &value);
+    commit.put_cf("utxo", &key, &value);
+    Ok(())
+}
 
-    // Define op_codes
-    const OP_DUP: u8 = 0x76;
-    const OP_HASH160: u8 = 0xa9;
-    const OP_EQUAL: u8 = 0x87;
-    const OP_EQUALVERIFY: u8 = 0x88;
-    const OP_CHECKSIG: u8 = 0xac;
-    const OP_CHECKCOLDSTAKEVERIFY_LOF: u8 = 0xd1;
-    const OP_CHECKCOLDSTAKEVERIFY: u8 = 0xd2;
+// Remove each input's prevout from the UTXO set and the address/pubkey indexes,
+// record where it was spent, and return the total value spent and its coin age
+// (value * confirmations). Prevouts we never indexed count as zero. The
+// addresses of spent outputs are added to `addresses`.
+fn spend_inputs(commit: &mut BlockCommit, inputs: &[CTxIn], spending_txid: &[u8], height: i32, settings: &IndexSettings, utxo_commitment: &mut UtxoCommitment, addresses: &mut Vec<String>) -> Result<(i64, i64), io::Error> {
+    let mut value_in = 0;
+    let mut coin_age = 0;
 
-    // Check the first byte and script length
-    match script.script.as_slice() {
-        [OP_DUP, OP_HASH160, 0x14, .., OP_EQUALVERIFY, OP_CHECKSIG] if script.script.len() == 25 => {
-            if let Some(address) = scriptpubkey_to_p2pkh_address(script) {
-                Some(AddressType::P2PKH(address))
-            } else {
-                Some(AddressType::Nonstandard)
-            }
-        }
-        [OP_HASH160, 0x14, .., OP_EQUAL] if script.script.len() == 23 => {
-            if let Some(address) = scriptpubkey_to_p2sh_address(script) {
-                Some(AddressType::P2SH(address))
-            } else {
-                Some(AddressType::Nonstandard)
+    for tx_in in inputs {
+        let prevout = match &tx_in.prevout {
+            Some(prevout) => prevout,
+            None => continue,
+        };
+        let prev_txid = match hex::decode(&prevout.hash) {
+            Ok(txid) => txid,
+            Err(_) => continue,
+        };
+        let key_utxo = utxo_key(&prev_txid, prevout.n);
+        let data = match commit.get_cf("utxo", &key_utxo)? {
+            Some(data) => data,
+            None => continue,
+        };
+        let entry = match deserialize_utxo_entry(&data) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        value_in += entry.value;
+        coin_age += entry.value * (height - entry.height) as i64;
+
+        utxo_commitment.remove(&key_utxo, &data);
+        commit.delete_cf("utxo", &key_utxo);
+        let script = CScript { script: entry.script_pubkey };
+        if settings.role.builds_address_index() {
+            let address_type = scriptpubkey_to_address(&script).unwrap_or(AddressType::Nonstandard);
+            remove_utxo_addr(commit, &address_type, &prev_txid, prevout.n, entry.value, height)?;
+            for address in address_keys(&address_type) {
+                tx_history::record_spent(commit, &address, height, &prev_txid, prevout.n, entry.value, entry.height);
+                addresses.push(address);
             }
         }
-        [0xc1, ..] => Some(AddressType::ZerocoinMint),
-        [0xc2, ..] => Some(AddressType::ZerocoinSpend),
-        [0xc3, ..] => Some(AddressType::ZerocoinPublicSpend),
-        [.., OP_CHECKSIG] if !script.script.contains(&OP_DUP) && script.script.len() > 1 && !script.script.contains(&OP_CHECKCOLDSTAKEVERIFY) && !script.script.contains(&OP_CHECKCOLDSTAKEVERIFY_LOF) => {
-            if let Some(pubkey) = scriptpubkey_to_p2pk(script) {
-                Some(AddressType::P2PK(pubkey))
-            } else {
-                Some(AddressType::Nonstandard)
-            }
+        if !settings.role.builds_tx_indexes() {
+            continue;
         }
-        _ if script.script.contains(&OP_CHECKCOLDSTAKEVERIFY) || script.script.contains(&OP_CHECKCOLDSTAKEVERIFY_LOF) => {
-            if let Some((staker_address, owner_address)) = scriptpubkey_to_staking_address(script) {
-                Some(AddressType::Staking(staker_address, owner_address))
+
+        // 'p' + scriptpubkey -> list of (txid, output_index)
+        let mut key_pubkey = vec![b'p'];
+        key_pubkey.extend_from_slice(&script.script);
+        if let Some(data) = commit.get_cf("pubkey", &key_pubkey)? {
+            let mut utxos = deserialize_utxos(&data);
+            utxos.retain(|(txid, index)| !(*txid == prev_txid && *index == prevout.n as u64));
+            if !utxos.is_empty() {
+                commit.put_cf("pubkey", &key_pubkey, &serialize_utxos(&utxos));
             } else {
-                Some(AddressType::Nonstandard)
+                commit.delete_cf("pubkey", &key_pubkey);
             }
         }
-        _ => Some(AddressType::Nonstandard), // Doesn't match non-standard
-    }
-}
 
-fn address_type_to_string(address: Option<AddressType>) -> Vec<String> {
-    match address {
-        Some(AddressType::CoinStakeTx) => vec!["CoinStakeTx".to_string()],
-        Some(AddressType::CoinBaseTx) => vec!["CoinBaseTx".to_string()],
-        Some(AddressType::Nonstandard) => vec!["Nonstandard".to_string()],
-        Some(AddressType::P2PKH(addr)) => vec![addr],
-        Some(AddressType::P2PK(pubkey)) => vec![pubkey],
-        Some(AddressType::P2SH(addr)) => vec![addr],
-        Some(AddressType::ZerocoinMint) => vec!["ZerocoinMint".to_string()],
-        Some(AddressType::ZerocoinSpend) => vec!["ZerocoinSpend".to_string()],
-        Some(AddressType::ZerocoinPublicSpend) => vec!["ZerocoinPublicSpend".to_string()],
-        Some(AddressType::Staking(staker, owner)) => vec![format!("Staking({}, {})", staker, owner)],
-        Some(AddressType::Sapling) => vec!["Sapling".to_string()],
-        None => Vec::new(),
+        // 's' + txid + output_index -> spending txid, input index, spending height
+        let mut key_spent = vec![b's'];
+        key_spent.extend_from_slice(&prev_txid);
+        key_spent.extend_from_slice(&prevout.n.to_le_bytes());
+        let mut spent = Vec::with_capacity(40);
+        spent.extend_from_slice(spending_txid);
+        spent.extend_from_slice(&(tx_in.index as u32).to_le_bytes());
+        spent.extend_from_slice(&height.to_le_bytes());
+        commit.put_cf("spent", &key_spent, &spent);
     }
+
+    Ok((value_in, coin_age))
 }
 
-fn add_utxo_to_pubkey(db: &DB, pubkey: &[u8], txid: &str, index: u32) {
-    let key = format!("pubkey_{}", hex::encode(pubkey));
+fn balance_key(address: &str) -> Vec<u8> {
+    let mut key = vec![b'b'];
+    key.extend_from_slice(address.as_bytes());
+    key
+}
 
-    // Fetch existing data, if any.
-    let existing_data = db.get(&key).unwrap_or(None);
+fn update_address_balance(commit: &mut BlockCommit, address: &str, height: i32, received: i64, sent: i64) -> Result<(), io::Error> {
+    balance_history::record(commit, address, height, received, sent)?;
 
-    let mut utxos = match existing_data {
-        Some(data) => serde_json::from_slice::<Vec<Value>>(&data).expect("Failed to parse JSON"),
-        None => vec![],
+    let key = balance_key(address);
+    let mut entry: AddressBalance = match commit.get_cf("addr_balances", &key)? {
+        Some(data) => bincode::deserialize(&data).unwrap_or_default(),
+        None => AddressBalance::default(),
     };
-
-    // Append the new UTXO.
-    utxos.push(json!({
-        "txid": txid,
-        "index": index,
-    }));
-
-    // Serialize and store back in RocksDB.
-    let serialized_data = serde_json::to_vec(&utxos).expect("Failed to serialize JSON");
-    db.put(key, serialized_data).expect("Failed to write to RocksDB");
+    let old_balance = entry.balance;
+    entry.received += received;
+    entry.sent += sent;
+    entry.balance += received - sent;
+    rich_list::update(commit, address, height, old_balance, entry.balance)?;
+
+    let data = bincode::serialize(&entry)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    commit.put_cf("addr_balances", &key, &data);
+    Ok(())
 }
 
-fn serialize_utxos(utxos: &Vec<(Vec<u8>, u64)>) -> Vec<u8> {
-    let mut serialized = Vec::new();
-    for (txid, index) in utxos {
-        serialized.extend(txid);
-        serialized.extend(&index.to_le_bytes());
-    }
-    serialized
-}
+// Addresses summed in memory before being merged into addr_balances
+const REBUILD_BATCH_ADDRESSES: usize = 100_000;
 
-fn deserialize_utxos(data: &[u8]) -> Vec<(Vec<u8>, u64)> {
-    let mut utxos = Vec::new();
-    let mut iter = data.chunks_exact(40); // 32 bytes for txid and 8 bytes for index
-    while let Some(chunk) = iter.next() {
-        let txid = chunk[0..32].to_vec();
-        let index = u64::from_le_bytes(chunk[32..40].try_into().unwrap());
-        utxos.push((txid, index));
+// Recompute every address balance from the UTXO set. Received/sent totals
+// cannot be recovered from unspent outputs alone, so existing totals are kept
+// (new entries start at received = balance) until a full reindex.
+// Reads come from a single snapshot so the result reflects one point in time;
+// returns the sequence number the snapshot was taken at
+fn rebuild_address_balances(_db: &DB) -> Result<u64, io::Error> {
+    let cf_utxo = _db.cf_handle("utxo").expect("UTXO column family not found");
+    let cf_balances = _db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let snapshot = _db.snapshot();
+    let sequence = _db.latest_sequence_number();
+
+    // Zero the existing balances so partial sums can be added onto them
+    let mut batch = WriteBatch::default();
+    for item in snapshot.iterator_cf(cf_balances, IteratorMode::From(b"b", Direction::Forward)) {
+        let (key, value) = item.map_err(from_rocksdb_error)?;
+        // Rich list entries follow the balances
+        if key[0] != b'b' {
+            break;
+        }
+        let mut entry: AddressBalance = bincode::deserialize(&value).unwrap_or_default();
+        entry.balance = 0;
+        let data = bincode::serialize(&entry)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        batch.put_cf(cf_balances, &key, &data);
+        if batch.len() >= REBUILD_BATCH_ADDRESSES {
+            _db.write(std::mem::take(&mut batch)).map_err(from_rocksdb_error)?;
+        }
     }
-    utxos
-}
-
-fn deserialize_transaction(data: &[u8], block_version: u32) -> Result<CTransaction, std::io::Error> {
-    let mut cursor = Cursor::new(data);
-
-    let version = cursor.read_i16::<LittleEndian>().unwrap();
-    let input_count = read_varint(&mut cursor)?;
-    let mut inputs = Vec::new();
-    for _ in 0..input_count {
-        inputs.push(deserialize_tx_in(&mut cursor, version.try_into().unwrap(), block_version));
+    _db.write(batch).map_err(from_rocksdb_error)?;
+
+    // Sum the UTXO set in bounded chunks of addresses. `merged` keeps the
+    // balance written so far for every address merged, 8 bytes plus the
+    // address each, so one spread over several chunks adds up.
+    let mut balances: HashMap<String, i64> = HashMap::new();
+    let mut merged: HashMap<String, i64> = HashMap::new();
+    let mut utxos_read: u64 = 0;
+    for item in snapshot.iterator_cf(cf_utxo, IteratorMode::Start) {
+        let (key, value) = item.map_err(from_rocksdb_error)?;
+        // 'u' + 32 byte txid + 4 byte index
+        if key.len() != 37 || key[0] != b'u' {
+            continue;
+        }
+        utxos_read += 1;
+        if let Some(entry) = deserialize_utxo_entry(&value) {
+            let script = CScript { script: entry.script_pubkey };
+            let address_type = scriptpubkey_to_address(&script).unwrap_or(AddressType::Nonstandard);
+            for address in address_keys(&address_type) {
+                *balances.entry(address).or_insert(0) += entry.value;
+            }
+        }
+        if balances.len() >= REBUILD_BATCH_ADDRESSES {
+            let buffered_bytes: usize = balances.keys().map(|address| address.len() + 32).sum();
+            merge_address_balances(_db, &snapshot, &mut balances, &mut merged)?;
+            println!("Balance rebuild: {} UTXOs read, merged a batch of ~{} KB", utxos_read, buffered_bytes / 1024);
+        }
     }
-
-    let output_count = read_varint(&mut cursor)?;
-    let mut outputs = Vec::new();
-    for _ in 0..output_count {
-        outputs.push(deserialize_tx_out(&mut cursor));
+    merge_address_balances(_db, &snapshot, &mut balances, &mut merged)?;
+    println!("Balance rebuild: {} UTXOs read", utxos_read);
+    rich_list::build(_db)?;
+
+    let cf_state = _db.cf_handle("chain_state").expect("Chain state column family not found");
+    _db.put_cf(cf_state, b"addr_balances_built", [1u8]).map_err(from_rocksdb_error)?;
+    Ok(sequence)
+}
+
+// Add partial sums to the balances merged so far in one write batch and
+// empty `balances`. Totals are kept from the entry in the snapshot; addresses
+// without one have received their whole balance.
+fn merge_address_balances(_db: &DB, snapshot: &Snapshot, balances: &mut HashMap<String, i64>, merged: &mut HashMap<String, i64>) -> Result<(), io::Error> {
+    let cf_balances = _db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let mut batch = WriteBatch::default();
+    for (address, value) in balances.drain() {
+        let key = balance_key(&address);
+        let balance = merged.get(&address).copied().unwrap_or(0) + value;
+        let entry = match snapshot.get_cf(cf_balances, &key).map_err(from_rocksdb_error)? {
+            Some(data) => AddressBalance { balance, ..bincode::deserialize(&data).unwrap_or_default() },
+            None => AddressBalance { balance, received: balance, sent: 0 },
+        };
+        merged.insert(address, balance);
+        let data = bincode::serialize(&entry)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        batch.put_cf(cf_balances, &key, &data);
     }
-
-    let lock_time = cursor.read_u32::<LittleEndian>().unwrap();
-
-    Ok(CTransaction {
-        version: version,
-        inputs: inputs,
-        outputs: outputs,
-        lock_time: lock_time,
-    })
+    _db.write(batch).map_err(from_rocksdb_error)
 }
 
-fn deserialize_tx_in(cursor: &mut Cursor<&[u8]>, tx_ver_out: u32, block_version: u32) -> CTxIn {
-    if block_version < 3 && tx_ver_out == 2 {
-        // It's a coinbase transaction
-        let mut buffer = [0; 26];
-        cursor.read_exact(&mut buffer).unwrap();
-        let coinbase = buffer.to_vec();
-        let sequence = cursor.read_u32::<LittleEndian>().unwrap();
+// Recompute one address's UTXO list, balance and totals and rewrite them in
+// one batch; returns a description of what changed. When the address history
+// goes back to the first block, only the outputs of the address's own
+// transactions are read and received/sent are summed from its per-block
+// history. Otherwise the whole UTXO set is scanned and the totals are kept.
+fn repair_address(_db: &DB, address: &str) -> Result<String, io::Error> {
+    let cf_utxo = _db.cf_handle("utxo").expect("UTXO column family not found");
+    let cf_addr = _db.cf_handle("addr_index").expect("Address_index column family not found");
+    let cf_balances = _db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let cf_state = _db.cf_handle("chain_state").expect("Chain state column family not found");
+    let snapshot = _db.snapshot();
+
+    // Peer syncs start at height 1, after genesis
+    let history_complete = match snapshot.get_cf(cf_state, b"address_history_since").map_err(from_rocksdb_error)? {
+        Some(value) if value.len() == 4 => i32::from_le_bytes(value[..4].try_into().unwrap()) <= 1,
+        _ => false,
+    };
 
-        CTxIn {
-            prevout: None,
-            script_sig: CScript { script: Vec::new() },
-            sequence: sequence,
-            index: 0,
-            coinbase: Some(coinbase),
+    let mut utxos: Vec<(Vec<u8>, u64)> = Vec::new();
+    let mut balance = 0;
+    let mut add_if_owned = |key: &[u8], value: &[u8]| {
+        // 'u' + 32 byte txid + 4 byte index
+        if key.len() != 37 || key[0] != b'u' {
+            return;
+        }
+        if let Some(entry) = deserialize_utxo_entry(value) {
+            let script = CScript { script: entry.script_pubkey };
+            let address_type = scriptpubkey_to_address(&script).unwrap_or(AddressType::Nonstandard);
+            if address_keys(&address_type).iter().any(|key| key == address) {
+                let index = u32::from_le_bytes([key[33], key[34], key[35], key[36]]);
+                utxos.push((key[1..33].to_vec(), index as u64));
+                balance += entry.value;
+            }
+        }
+    };
+    if history_complete {
+        for txid in tx_history::txids(_db, address)? {
+            let mut prefix = vec![b'u'];
+            prefix.extend_from_slice(&txid);
+            for item in snapshot.iterator_cf(cf_utxo, IteratorMode::From(&prefix, Direction::Forward)) {
+                let (key, value) = item.map_err(from_rocksdb_error)?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                add_if_owned(&key, &value);
+            }
         }
     } else {
-        // It's a regular transaction
-        let prevout = deserialize_out_point(cursor);
-        let script_sig = read_script(cursor).unwrap();
-        let sequence = cursor.read_u32::<LittleEndian>().unwrap();
-        let index = cursor.read_u64::<LittleEndian>().unwrap();
-
-        CTxIn {
-            prevout: Some(prevout),
-            script_sig: CScript { script: script_sig },
-            sequence: sequence,
-            index: index,
-            coinbase: None,
+        for item in snapshot.iterator_cf(cf_utxo, IteratorMode::Start) {
+            let (key, value) = item.map_err(from_rocksdb_error)?;
+            add_if_owned(&key, &value);
         }
     }
-}
 
+    let mut key_address = vec![b'a'];
+    key_address.extend_from_slice(address.as_bytes());
+    let stored_utxos = snapshot.get_cf(cf_addr, &key_address).map_err(from_rocksdb_error)?
+        .map(db::codec::decode)
+        .as_deref()
+        .map_or(Vec::new(), deserialize_utxos);
+    let key = balance_key(address);
+    let stored: AddressBalance = match snapshot.get_cf(cf_balances, &key).map_err(from_rocksdb_error)? {
+        Some(data) => bincode::deserialize(&data).unwrap_or_default(),
+        None => AddressBalance::default(),
+    };
+    let (received, sent) = if history_complete {
+        balance_history::totals(_db, address)?
+    } else {
+        (stored.received, stored.sent)
+    };
 
-fn deserialize_tx_out(cursor: &mut Cursor<&[u8]>) -> CTxOut {
-    let value = cursor.read_i64::<LittleEndian>().unwrap();
-    let script_length = read_varint(cursor);
-    let mut script_pubkey = vec![0; script_length.unwrap() as usize];
-    cursor.read_exact(&mut script_pubkey).unwrap();
-    let index = cursor.read_u64::<LittleEndian>().unwrap();
-
-    let mut address_data = Vec::new();
-    cursor.read_to_end(&mut address_data).unwrap();
-    let address = String::from_utf8(address_data).unwrap();
+    let missing = utxos.iter().filter(|utxo| !stored_utxos.contains(utxo)).count();
+    let stale = stored_utxos.iter().filter(|utxo| !utxos.contains(utxo)).count();
+    let diff = format!(
+        "{}: {} missing and {} stale UTXO entries, balance {} -> {}, received {} -> {}, sent {} -> {}",
+        address, missing, stale, stored.balance, balance, stored.received, received, stored.sent, sent
+    );
 
-    CTxOut {
-        value: value,
-        script_length: script_pubkey.len() as i32,
-        script_pubkey: CScript { script: script_pubkey },
-        index: index,
-        address: vec![address],
+    let mut batch = WriteBatch::default();
+    if utxos.is_empty() {
+        batch.delete_cf(cf_addr, &key_address);
+    } else {
+        batch.put_cf(cf_addr, &key_address, db::codec::encode("addr_index", &serialize_utxos(&utxos)));
     }
-}
+    rich_list::rerank(_db, &mut batch, address, stored.balance, balance);
+    let entry = AddressBalance { balance, received, sent };
+    let data = bincode::serialize(&entry)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    batch.put_cf(cf_balances, &key, &data);
+    _db.write(batch).map_err(from_rocksdb_error)?;
 
-fn deserialize_out_point(cursor: &mut Cursor<&[u8]>) -> COutPoint {
-    let mut hash_bytes = [0u8; 32];
-    cursor.read_exact(&mut hash_bytes).unwrap();
-    let hash = hex::encode(hash_bytes);
-    let n = cursor.read_u32::<LittleEndian>().unwrap();
-
-    COutPoint {
-        hash: hash,
-        n: n,
-    }
+    Ok(diff)
 }
 
-fn remove_utxo_addr(_db: &DB, address_type: &AddressType, txid: &str, index: u32) -> Result<(), io::Error> {
-    let address_keys = match address_type {
-        AddressType::P2PKH(address) | AddressType::P2SH(address) => vec![address.clone()],
-        AddressType::P2PK(pubkey) => vec![pubkey.clone()],
-        AddressType::Staking(staker, owner) => vec![staker.clone(), owner.clone()],
-        _ => return Ok(()),
-    };
+// Balance and UTXO summary of one address from the address and UTXO indexes
+fn address_summary(_db: &DB, address: &str, dust_relay_fee: i64) -> Result<AddressSummary, io::Error> {
+    let cf_utxo = _db.cf_handle("utxo").expect("UTXO column family not found");
+    let cf_addr = _db.cf_handle("addr_index").expect("Address_index column family not found");
+    let cf_balances = _db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let snapshot = _db.snapshot();
 
-    for address_key in &address_keys {
-        let cf_addr = _db.cf_handle("addr_index").expect("Address_index column family not found");
-        let mut key_address = vec![b'a']; 
-        key_address.extend_from_slice(address_key.as_bytes());
+    let totals: AddressBalance = match snapshot.get_cf(cf_balances, balance_key(address)).map_err(from_rocksdb_error)? {
+        Some(data) => bincode::deserialize(&data).unwrap_or_default(),
+        None => AddressBalance::default(),
+    };
+    let mut summary = AddressSummary {
+        address: address.to_string(),
+        balance: totals.balance,
+        received: totals.received,
+        sent: totals.sent,
+        utxo_count: 0,
+        dust_utxo_count: 0,
+        largest_utxo: 0,
+    };
 
-        // Fetch existing UTXOs associated with this address
-        let existing_data = _db.get_cf(cf_addr, &key_address).map_err(from_rocksdb_error)?;
-        let mut existing_utxos = existing_data.as_deref().map_or(Vec::new(), deserialize_utxos);
+    let mut key_address = vec![b'a'];
+    key_address.extend_from_slice(address.as_bytes());
+    let utxos = snapshot.get_cf(cf_addr, &key_address).map_err(from_rocksdb_error)?
+        .map(db::codec::decode)
+        .as_deref()
+        .map_or(Vec::new(), deserialize_utxos);
+    for (txid, index) in utxos {
+        let data = match snapshot.get_cf(cf_utxo, utxo_key(&txid, index as u32)).map_err(from_rocksdb_error)? {
+            Some(data) => data,
+            None => continue,
+        };
+        let entry = match deserialize_utxo_entry(&data) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let tx_out = CTxOut {
+            value: entry.value,
+            script_length: entry.script_pubkey.len() as i32,
+            script_pubkey: CScript { script: entry.script_pubkey },
+            index,
+            address: Vec::new(),
+        };
+        summary.utxo_count += 1;
+        if tx_out.value < dust_threshold(&tx_out, dust_relay_fee) {
+            summary.dust_utxo_count += 1;
+        }
+        summary.largest_utxo = summary.largest_utxo.max(tx_out.value);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    // Every field set, so a value read from the wrong place shows up
+    fn sample_stats() -> BlockStats {
+        BlockStats {
+            size: 1_000_001,
+            p2pkh_outputs: 2,
+            p2sh_outputs: 3,
+            p2pk_outputs: 4,
+            staking_outputs: 5,
+            zerocoin_outputs: 6,
+            nonstandard_outputs: 7,
+            empty_outputs: 8,
+            dust_outputs: 9,
+            unspendable_outputs: 10,
+            unspendable_value: 11_000_000_011,
+            shield_net_flow: -12_000_000_012,
+            shield_txs: 13,
+            shield_spends: 14,
+            shield_outputs: 15,
+            total_fees: 16_000_016,
+            fee_txs: 17,
+            min_feerate: 18,
+            median_feerate: 19,
+            max_feerate: 20,
+        }
+    }
 
-        // Find the UTXO to remove
-        if let Some(pos) = existing_utxos.iter().position(|(stored_txid, stored_index)| stored_txid.as_slice() == txid.as_bytes() && *stored_index == index as u64) {
-            existing_utxos.remove(pos);
+    // A row as written by the releases before the version byte, and the stats
+    // it holds
+    fn legacy_row(len: usize) -> (Vec<u8>, BlockStats) {
+        let all = sample_stats();
+        let mut expected = BlockStats::default();
+        let mut row = Vec::new();
+        if len >= 36 {
+            row.write_u32::<LittleEndian>(all.size).unwrap();
+            expected.size = all.size;
+        }
+        for count in [all.p2pkh_outputs, all.p2sh_outputs, all.p2pk_outputs, all.staking_outputs,
+                      all.zerocoin_outputs, all.nonstandard_outputs, all.empty_outputs, all.dust_outputs] {
+            row.write_u32::<LittleEndian>(count).unwrap();
+        }
+        expected.p2pkh_outputs = all.p2pkh_outputs;
+        expected.p2sh_outputs = all.p2sh_outputs;
+        expected.p2pk_outputs = all.p2pk_outputs;
+        expected.staking_outputs = all.staking_outputs;
+        expected.zerocoin_outputs = all.zerocoin_outputs;
+        expected.nonstandard_outputs = all.nonstandard_outputs;
+        expected.empty_outputs = all.empty_outputs;
+        expected.dust_outputs = all.dust_outputs;
+        if len >= 104 {
+            row.write_u32::<LittleEndian>(all.unspendable_outputs).unwrap();
+            row.write_i64::<LittleEndian>(all.unspendable_value).unwrap();
+            expected.unspendable_outputs = all.unspendable_outputs;
+            expected.unspendable_value = all.unspendable_value;
+        }
+        if len >= 44 {
+            row.write_i64::<LittleEndian>(all.shield_net_flow).unwrap();
+            expected.shield_net_flow = all.shield_net_flow;
         }
+        if len >= 56 {
+            row.write_u32::<LittleEndian>(all.shield_txs).unwrap();
+            row.write_u32::<LittleEndian>(all.shield_spends).unwrap();
+            row.write_u32::<LittleEndian>(all.shield_outputs).unwrap();
+            expected.shield_txs = all.shield_txs;
+            expected.shield_spends = all.shield_spends;
+            expected.shield_outputs = all.shield_outputs;
+        }
+        if len >= 92 {
+            row.write_i64::<LittleEndian>(all.total_fees).unwrap();
+            row.write_u32::<LittleEndian>(all.fee_txs).unwrap();
+            row.write_i64::<LittleEndian>(all.min_feerate).unwrap();
+            row.write_i64::<LittleEndian>(all.median_feerate).unwrap();
+            row.write_i64::<LittleEndian>(all.max_feerate).unwrap();
+            expected.total_fees = all.total_fees;
+            expected.fee_txs = all.fee_txs;
+            expected.min_feerate = all.min_feerate;
+            expected.median_feerate = all.median_feerate;
+            expected.max_feerate = all.max_feerate;
+        }
+        assert_eq!(row.len(), len);
+        (row, expected)
+    }
 
-        // Update or delete the UTXO entry for this address
-        if !existing_utxos.is_empty() {
-            _db.put_cf(cf_addr, &key_address, &serialize_utxos(&existing_utxos)).map_err(from_rocksdb_error)?;
-        } else {
-            _db.delete_cf(cf_addr, &key_address).map_err(from_rocksdb_error)?;
+    #[test]
+    fn block_stats_legacy_layouts() {
+        for len in [32, 36, 44, 56, 92, 104] {
+            let (row, expected) = legacy_row(len);
+            let decoded = BlockStats::decode(&row).unwrap_or_else(|| panic!("{} byte row not decoded", len));
+            assert_eq!(decoded, expected, "{} byte row", len);
+            // What migrate_block_stats writes back decodes to the same stats
+            let migrated = decoded.encode().unwrap();
+            assert_eq!(migrated[0], BLOCK_STATS_VERSION);
+            assert_eq!(BlockStats::decode(&migrated), Some(expected), "{} byte row after migration", len);
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn block_stats_versioned_round_trip() {
+        let encoded = sample_stats().encode().unwrap();
+        assert_eq!(encoded.len(), 105);
+        assert_eq!(encoded[0], BLOCK_STATS_VERSION);
+        assert_eq!(BlockStats::decode(&encoded), Some(sample_stats()));
+        // The current fields in the legacy 104 byte order, without the version byte
+        assert_eq!(BlockStats::decode(&encoded[1..]), Some(sample_stats()));
+    }
+
+    #[test]
+    fn block_stats_unknown_layouts_rejected() {
+        let encoded = sample_stats().encode().unwrap();
+        assert_eq!(BlockStats::decode(&encoded[..60]), None);
+        let mut future = encoded.clone();
+        future[0] = BLOCK_STATS_VERSION + 1;
+        assert_eq!(BlockStats::decode(&future), None);
+        assert_eq!(BlockStats::decode(&[]), None);
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+
+use crate::chain_params::{self, ChainParams};
+use crate::parser::stream::BlockStream;
+use crate::parser::types::CBlockHeader;
+
+// The network a database was indexed for, in chain_state "network_identity"
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkIdentity {
+    pub network: String,
+    pub magic: String,
+    pub genesis_hash: String,
+}
+
+impl NetworkIdentity {
+    fn of(params: &ChainParams) -> NetworkIdentity {
+        NetworkIdentity {
+            network: format!("{:?}", params.network).to_lowercase(),
+            magic: hex::encode(params.magic),
+            genesis_hash: params.genesis_hash.to_string(),
+        }
+    }
+}
+
+// Header of the first block in the first blk file, which a node always writes
+// first; None without blk files
+fn genesis_in(blk_dir: &Path) -> io::Result<Option<CBlockHeader>> {
+    let mut blk_files: Vec<_> = match fs::read_dir(blk_dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+            .filter(|path| path.file_name().and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat")))
+            .collect(),
+        // No blk files, e.g. with sync-peer
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    blk_files.sort();
+    let first = match blk_files.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    let file = io::BufReader::new(File::open(first)?);
+    match BlockStream::starting_at(file, chain_params::params().magic, 0).next() {
+        Some(block) => Ok(Some(block?.header)),
+        None => Ok(None),
+    }
+}
+
+// Check the configured network against the one recorded in chain_state
+// "network_identity", so a database is never mixed with another network's
+// blocks. Until one is recorded, the first block of the blk files is checked
+// against the configured genesis block, and an indexing command (`record`)
+// records the network once that check passed. Without a genesis block to check,
+// e.g. with sync-peer or blk files of another network's magic, nothing is
+// recorded; sync-peer records it when the peer's first block links to genesis.
+pub fn verify(db: &DB, blk_dir: &Path, record: bool) -> io::Result<()> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let params = chain_params::params();
+    let configured = NetworkIdentity::of(params);
+
+    if let Some(value) = db.get_cf(cf_state, b"network_identity").map_err(to_io)? {
+        let recorded: NetworkIdentity = serde_json::from_slice(&value)?;
+        if recorded != configured {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Database at {} was indexed for {} (magic {}, genesis {}) but the config selects {} (magic {}, genesis {}); use another db_path",
+                db.path().display(), recorded.network, recorded.magic, recorded.genesis_hash,
+                configured.network, configured.magic, configured.genesis_hash)));
+        }
+        return Ok(());
+    }
+
+    match genesis_in(blk_dir)? {
+        Some(genesis) if !params.is_genesis(&genesis) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "First block in {} (merkle root {}) is not the {} genesis block {}",
+                blk_dir.display(), hex::encode(genesis.hash_merkle_root.iter().rev().cloned().collect::<Vec<u8>>()),
+                configured.network, params.genesis_hash)))
+        }
+        Some(_) if record => {
+            println!("Genesis block {} verified", params.genesis_hash);
+            db.put_cf(cf_state, b"network_identity", serde_json::to_vec(&configured)?).map_err(to_io)
+        }
+        Some(_) => Ok(()),
+        None => {
+            if record {
+                println!("No {} genesis block in {}, the network is not recorded yet", configured.network, blk_dir.display());
+            }
+            Ok(())
+        }
+    }
+}
+
+// Record the configured network after the genesis block was checked another
+// way, e.g. against a peer's chain
+pub fn record(db: &DB) -> io::Result<()> {
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let configured = NetworkIdentity::of(chain_params::params());
+    db.put_cf(cf_state, b"network_identity", serde_json::to_vec(&configured)?)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
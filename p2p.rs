@@ -0,0 +1,329 @@
+use std::io::{self, Cursor, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::parser::stream::{decode_header, header_size};
+use crate::parser::tx::{read_varint2, sha256d};
+
+// Protocol version announced in `version`; recent enough for 112 byte headers
+const PROTOCOL_VERSION: i32 = 70926;
+const USER_AGENT: &str = concat!("/rusty-blox:", env!("CARGO_PKG_VERSION"), "/");
+// Upper bound on a message payload, a little above the largest PIVX block
+const MAX_PAYLOAD_SIZE: u32 = 4 * 1024 * 1024;
+// Inventory type for a full block in getdata
+const MSG_BLOCK: u32 = 2;
+// Peers answer getheaders with at most this many headers
+const MAX_HEADERS_RESULTS: usize = 2000;
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A connection to one PIVX node that has completed the version handshake
+pub struct Peer {
+    stream: TcpStream,
+    magic: [u8; 4],
+    pub start_height: i32,
+    pub user_agent: String,
+}
+
+impl Peer {
+    // Connect to `addr` (host:port) and exchange version and verack
+    pub fn connect(addr: &str, magic: [u8; 4]) -> io::Result<Peer> {
+        let socket_addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", addr)))?;
+        let stream = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(30))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut peer = Peer { stream, magic, start_height: 0, user_agent: String::new() };
+
+        peer.send("version", &version_payload(&socket_addr))?;
+        let (mut got_version, mut got_verack) = (false, false);
+        while !(got_version && got_verack) {
+            let (command, payload) = peer.receive()?;
+            match command.as_str() {
+                "version" => {
+                    let (user_agent, start_height) = parse_version(&payload)?;
+                    peer.user_agent = user_agent;
+                    peer.start_height = start_height;
+                    peer.send("verack", &[])?;
+                    got_version = true;
+                }
+                "verack" => got_verack = true,
+                _ => {}
+            }
+        }
+        Ok(peer)
+    }
+
+    // Hashes of the headers following the first locator hash the peer knows,
+    // in chain order, and whether the peer sent a full batch
+    pub fn get_headers(&mut self, locator: &[[u8; 32]]) -> io::Result<(Vec<[u8; 32]>, bool)> {
+        let mut payload = Vec::new();
+        payload.write_u32::<LittleEndian>(PROTOCOL_VERSION as u32)?;
+        write_compact_size(&mut payload, locator.len() as u64);
+        for hash in locator {
+            payload.extend_from_slice(hash);
+        }
+        payload.extend_from_slice(&[0u8; 32]); // No stop hash, as many as allowed
+        self.send("getheaders", &payload)?;
+
+        let payload = self.wait_for("headers")?;
+        parse_headers(&payload)
+    }
+
+    // Request blocks by hash and return their serialized data in the same order
+    pub fn get_blocks(&mut self, hashes: &[[u8; 32]]) -> io::Result<Vec<Vec<u8>>> {
+        let mut payload = Vec::new();
+        write_compact_size(&mut payload, hashes.len() as u64);
+        for hash in hashes {
+            payload.write_u32::<LittleEndian>(MSG_BLOCK)?;
+            payload.extend_from_slice(hash);
+        }
+        self.send("getdata", &payload)?;
+
+        let mut blocks = Vec::with_capacity(hashes.len());
+        for (i, expected) in hashes.iter().enumerate() {
+            let block = self.wait_for("block")?;
+            let (header, size) = decode_header(&block)?;
+            // A Quark-hashed block can only be checked by its link to the one before
+            let in_order = match header_hash(&block[..size]) {
+                Some(hash) => hash == *expected,
+                None => i == 0 || header.hash_prev_block == hashes[i - 1],
+            };
+            if !in_order {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Peer sent block {} out of order", hex::encode(header.block_hash.iter().rev().cloned().collect::<Vec<u8>>()))));
+            }
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    // Read messages until one with `command` arrives, answering pings meanwhile
+    fn wait_for(&mut self, command: &str) -> io::Result<Vec<u8>> {
+        loop {
+            let (received, payload) = self.receive()?;
+            match received.as_str() {
+                c if c == command => return Ok(payload),
+                "ping" => self.send("pong", &payload)?,
+                "notfound" => return Err(io::Error::new(io::ErrorKind::NotFound, "Peer does not have a requested block")),
+                _ => {} // inv, addr, sendheaders and friends are not needed here
+            }
+        }
+    }
+
+    // magic, command padded to 12 bytes, u32 LE length, checksum, payload
+    fn send(&mut self, command: &str, payload: &[u8]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(24 + payload.len());
+        message.extend_from_slice(&self.magic);
+        let mut name = [0u8; 12];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+        message.extend_from_slice(&name);
+        message.write_u32::<LittleEndian>(payload.len() as u32)?;
+        message.extend_from_slice(&sha256d(payload)[..4]);
+        message.extend_from_slice(payload);
+        self.stream.write_all(&message)
+    }
+
+    fn receive(&mut self) -> io::Result<(String, Vec<u8>)> {
+        let mut header = [0u8; 24];
+        self.stream.read_exact(&mut header)?;
+        if header[..4] != self.magic {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer is on a different network"));
+        }
+        let command: String = header[4..16].iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| b as char)
+            .collect();
+        let length = (&header[16..20]).read_u32::<LittleEndian>()?;
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Oversized {} message of {} bytes", command, length)));
+        }
+
+        let mut payload = vec![0u8; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        if sha256d(&payload)[..4] != header[20..24] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad checksum on {} message", command)));
+        }
+        Ok((command, payload))
+    }
+}
+
+// PIVX hashes version 1-3 headers with Quark, which isn't implemented here, and
+// later ones with double SHA-256 over the serialized header
+pub fn header_hash(header: &[u8]) -> Option<[u8; 32]> {
+    let version = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if version < 4 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&sha256d(&header[..header_size(version)]));
+    Some(hash)
+}
+
+// Hashes of the headers in a headers message. A Quark-hashed header takes its
+// hash from the previous-block link of the header after it, so one that ends
+// the batch is left for the next getheaders, which starts right after the
+// last hash returned.
+fn parse_headers(payload: &[u8]) -> io::Result<(Vec<[u8; 32]>, bool)> {
+    let mut reader = Cursor::new(payload);
+    let count = read_varint2(&mut reader)? as usize;
+    let mut hashes: Vec<Option<[u8; 32]>> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = reader.position() as usize;
+        let version = reader.read_u32::<LittleEndian>()?;
+        let end = start + header_size(version);
+        if end > payload.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated headers message"));
+        }
+        if let Some(last) = hashes.last_mut() {
+            if last.is_none() {
+                let mut prev = [0u8; 32];
+                prev.copy_from_slice(&payload[start + 4..start + 36]);
+                *last = Some(prev);
+            }
+        }
+        hashes.push(header_hash(&payload[start..end]));
+        reader.set_position(end as u64);
+        read_varint2(&mut reader)?; // Transaction count, always 0
+    }
+    if let Some(None) = hashes.last() {
+        hashes.pop();
+    }
+    Ok((hashes.into_iter().flatten().collect(), count == MAX_HEADERS_RESULTS))
+}
+
+fn version_payload(remote: &std::net::SocketAddr) -> Vec<u8> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut payload = Vec::new();
+    payload.write_i32::<LittleEndian>(PROTOCOL_VERSION).unwrap();
+    payload.write_u64::<LittleEndian>(0).unwrap(); // No services offered
+    payload.write_i64::<LittleEndian>(now as i64).unwrap();
+    write_net_addr(&mut payload, Some(remote));
+    write_net_addr(&mut payload, None);
+    payload.write_u64::<LittleEndian>(now ^ (u64::from(std::process::id()) << 32)).unwrap(); // Nonce
+    write_compact_size(&mut payload, USER_AGENT.len() as u64);
+    payload.extend_from_slice(USER_AGENT.as_bytes());
+    payload.write_i32::<LittleEndian>(0).unwrap(); // Start height
+    payload.push(0); // Don't relay transactions
+    payload
+}
+
+// services, IPv6 (or IPv4-mapped) address, big endian port
+fn write_net_addr(buf: &mut Vec<u8>, addr: Option<&std::net::SocketAddr>) {
+    buf.write_u64::<LittleEndian>(0).unwrap();
+    let (ip, port) = match addr {
+        Some(std::net::SocketAddr::V4(v4)) => (v4.ip().to_ipv6_mapped().octets(), v4.port()),
+        Some(std::net::SocketAddr::V6(v6)) => (v6.ip().octets(), v6.port()),
+        None => ([0u8; 16], 0),
+    };
+    buf.extend_from_slice(&ip);
+    buf.write_u16::<BigEndian>(port).unwrap();
+}
+
+// User agent and start height of the peer
+fn parse_version(payload: &[u8]) -> io::Result<(String, i32)> {
+    let mut reader = Cursor::new(payload);
+    // version, services, timestamp, two network addresses, nonce
+    reader.set_position(4 + 8 + 8 + 26 + 26 + 8);
+    let len = read_varint2(&mut reader)? as usize;
+    let mut user_agent = vec![0u8; len];
+    reader.read_exact(&mut user_agent)?;
+    let start_height = reader.read_i32::<LittleEndian>()?;
+    Ok((String::from_utf8_lossy(&user_agent).into_owned(), start_height))
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.write_u16::<LittleEndian>(n as u16).unwrap();
+        }
+        0x10000..=0xffffffff => {
+            buf.push(0xfe);
+            buf.write_u32::<LittleEndian>(n as u32).unwrap();
+        }
+        _ => {
+            buf.push(0xff);
+            buf.write_u64::<LittleEndian>(n).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PIVX genesis header (version 1) and its Quark hash, both in wire order
+    fn genesis_header() -> (Vec<u8>, [u8; 32]) {
+        let mut header = Vec::new();
+        header.write_u32::<LittleEndian>(1).unwrap();
+        header.extend_from_slice(&[0u8; 32]);
+        let mut merkle = hex::decode("1b2ef6e2f28be914103a277377ae7729dcd125dfeb8bf97bd5964ba72b6dc39b").unwrap();
+        merkle.reverse();
+        header.extend_from_slice(&merkle);
+        header.write_u32::<LittleEndian>(1454124731).unwrap();
+        header.write_u32::<LittleEndian>(0x1e0ffff0).unwrap();
+        header.write_u32::<LittleEndian>(2402015).unwrap();
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&hex::decode("0000041e482b9b9691d98eefb48473405c0b8ec31b76df3797c74a78680ef818").unwrap());
+        hash.reverse();
+        (header, hash)
+    }
+
+    fn child_header(version: u32, prev: &[u8; 32]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.write_u32::<LittleEndian>(version).unwrap();
+        header.extend_from_slice(prev);
+        header.resize(header_size(version), 7);
+        header
+    }
+
+    fn headers_message(headers: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        write_compact_size(&mut payload, headers.len() as u64);
+        for header in headers {
+            payload.extend_from_slice(header);
+            payload.push(0);
+        }
+        payload
+    }
+
+    #[test]
+    fn header_hash_by_version() {
+        let (genesis, _) = genesis_header();
+        assert_eq!(header_hash(&genesis), None);
+
+        let v4 = child_header(4, &[1u8; 32]);
+        assert_eq!(v4.len(), 112);
+        assert_eq!(header_hash(&v4).unwrap()[..], sha256d(&v4)[..]);
+        // Trailing block data is not part of the hash
+        let mut block = v4.clone();
+        block.extend_from_slice(&[0u8; 100]);
+        assert_eq!(header_hash(&block), header_hash(&v4));
+    }
+
+    #[test]
+    fn quark_headers_hashed_from_their_successor() {
+        let (genesis, genesis_hash) = genesis_header();
+        let v3 = child_header(3, &genesis_hash);
+        let v3_hash = [3u8; 32];
+        let v4 = child_header(4, &v3_hash);
+        let v4_hash = header_hash(&v4).unwrap();
+
+        let (hashes, full) = parse_headers(&headers_message(&[genesis.clone(), v3.clone(), v4])).unwrap();
+        assert_eq!(hashes, vec![genesis_hash, v3_hash, v4_hash]);
+        assert!(!full);
+
+        // A Quark-hashed header that ends the batch waits for the next one
+        let (hashes, _) = parse_headers(&headers_message(&[genesis, v3])).unwrap();
+        assert_eq!(hashes, vec![genesis_hash]);
+    }
+
+    #[test]
+    fn truncated_headers_rejected() {
+        let mut payload = headers_message(&[child_header(4, &[0u8; 32])]);
+        payload.truncate(60);
+        assert!(parse_headers(&payload).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+// Consensus parsing of blk file data that does not touch the database
+pub mod address;
+pub mod stream;
+pub mod tx;
+pub mod types;
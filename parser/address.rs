@@ -0,0 +1,99 @@
+use ripemd160::Ripemd160;
+use sha2::{Digest, Sha256};
+
+use crate::chain_params;
+use crate::parser::tx::sha256d;
+use crate::parser::types::{AddressType, CScript};
+use crate::scripts;
+
+pub fn compute_address_hash(data: &[u8]) -> Vec<u8> {
+    let sha = Sha256::digest(data);
+    Ripemd160::digest(&sha).to_vec()
+}
+
+// Function to convert hash to a base58check address (prefix comes from chain_params)
+pub fn hash_address(hash: &[u8], prefix: u8) -> String {
+    let mut extended_hash = vec![prefix];
+    extended_hash.extend_from_slice(hash);
+
+    let checksum = sha256d(&extended_hash);
+    extended_hash.extend_from_slice(&checksum[0..4]);
+
+    bs58::encode(extended_hash).into_string()
+}
+
+// Function to parse script_pubkey to a P2PKH address
+pub fn scriptpubkey_to_p2pkh_address(script: &CScript) -> Option<String> {
+    let address_hash = scripts::pubkey_hash(&script.script)?;
+    Some(hash_address(address_hash, chain_params::params().pubkey_address_prefix))
+}
+
+pub fn scriptpubkey_to_p2sh_address(script: &CScript) -> Option<String> {
+    let address_hash = scripts::script_hash(&script.script)?;
+    Some(hash_address(address_hash, chain_params::params().script_address_prefix))
+}
+
+pub fn compress_pubkey(pub_key_bytes: &[u8]) -> Option<Vec<u8>> {
+    match pub_key_bytes.len() {
+        65 if pub_key_bytes[0] == 0x04 => {
+            let x = &pub_key_bytes[1..33];
+            let y = &pub_key_bytes[33..65];
+            let parity = if y[31].is_multiple_of(2) { 2 } else { 3 };
+            let mut compressed_key: Vec<u8> = vec![parity];
+            compressed_key.extend_from_slice(x);
+            Some(compressed_key)
+        },
+        33 if pub_key_bytes[0] == 0x02 || pub_key_bytes[0] == 0x03 => {
+            // Already compressed, just return as is
+            Some(pub_key_bytes.to_vec())
+        },
+        _ => None
+    }
+}
+
+pub fn scriptpubkey_to_p2pk(script: &CScript) -> Option<String> {
+    let pubkey = scripts::pubkey(&script.script)?;
+
+    let pubkey_compressed = compress_pubkey(pubkey)?;
+    let pubkey_hash = compute_address_hash(&pubkey_compressed);
+    let pubkey_addr = hash_address(&pubkey_hash, chain_params::params().pubkey_address_prefix);
+
+    Some(pubkey_addr)
+}
+
+pub fn scriptpubkey_to_staking_address(script: &CScript) -> Option<(String, String)> {
+    let (staker_key_hash, owner_key_hash) = scripts::cold_stake_hashes(&script.script)?;
+
+    let params = chain_params::params();
+    let staker_address = hash_address(staker_key_hash, params.staking_address_prefix);
+    let owner_address = hash_address(owner_key_hash, params.pubkey_address_prefix);
+
+    Some((staker_address, owner_address))
+}
+
+pub fn scriptpubkey_to_address(script: &CScript) -> Option<AddressType> {
+    // Verify non-empty script
+    if script.script.is_empty() {
+        return Some(AddressType::Nonstandard);
+    }
+
+    // Matched templates that fail to decode are treated as non-standard
+    let address_type = scripts::classify(&script.script).and_then(|template| template.extract(script));
+    Some(address_type.unwrap_or(AddressType::Nonstandard))
+}
+
+pub fn address_type_to_string(address: Option<AddressType>) -> Vec<String> {
+    match address {
+        Some(AddressType::CoinStakeTx) => vec!["CoinStakeTx".to_string()],
+        Some(AddressType::CoinBaseTx) => vec!["CoinBaseTx".to_string()],
+        Some(AddressType::Nonstandard) => vec!["Nonstandard".to_string()],
+        Some(AddressType::P2PKH(addr)) => vec![addr],
+        Some(AddressType::P2PK(pubkey)) => vec![pubkey],
+        Some(AddressType::P2SH(addr)) => vec![addr],
+        Some(AddressType::ZerocoinMint) => vec!["ZerocoinMint".to_string()],
+        Some(AddressType::ZerocoinSpend) => vec!["ZerocoinSpend".to_string()],
+        Some(AddressType::ZerocoinPublicSpend) => vec!["ZerocoinPublicSpend".to_string()],
+        Some(AddressType::Staking(staker, owner)) => vec![format!("Staking({}, {})", staker, owner)],
+        None => Vec::new(),
+    }
+}
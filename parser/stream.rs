@@ -0,0 +1,374 @@
+use std::future::poll_fn;
+use std::io::{self, Cursor, Read, SeekFrom};
+use std::pin::Pin;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use futures_io::{AsyncRead, AsyncSeek};
+use sha2::{Digest, Sha256};
+
+use crate::parser::types::CBlockHeader;
+
+// A block as framed in a blk file: magic, u32 LE size, then the block data
+pub struct RawBlock {
+    // Offset of the magic bytes in the source
+    pub offset: u64,
+    // Height is left unset, it is not part of the serialized header
+    pub header: CBlockHeader,
+    // Header followed by the serialized transactions
+    pub data: Vec<u8>,
+    header_size: usize,
+}
+
+impl RawBlock {
+    // A block received whole, e.g. from a peer; `offset` is where it came from in its source
+    pub fn from_data(offset: u64, data: Vec<u8>) -> io::Result<RawBlock> {
+        let (header, header_size) = decode_header(&data)?;
+        Ok(RawBlock { offset, header, data, header_size })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.data[..self.header_size]
+    }
+
+    // Starts with the transaction count varint
+    pub fn transactions(&self) -> &[u8] {
+        &self.data[self.header_size..]
+    }
+}
+
+// Serialized header length for a block version
+pub fn header_size(version: u32) -> usize {
+    match version {
+        4 | 5 | 6 | 8 | 9 | 10 | 11 => 112, // Accumulator checkpoint or final sapling root
+        _ => 80, // Version 1 to 3 and 7
+    }
+}
+
+// Decode the header at the start of a block's data
+pub fn decode_header(data: &[u8]) -> io::Result<(CBlockHeader, usize)> {
+    let mut reader = Cursor::new(data);
+    let n_version = reader.read_u32::<LittleEndian>()?;
+    let size = header_size(n_version);
+    if data.len() < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Block data shorter than its header"));
+    }
+
+    let block_hash = Sha256::digest(&Sha256::digest(&data[..size]));
+
+    let mut hash_prev_block = [0u8; 32];
+    reader.read_exact(&mut hash_prev_block)?;
+    let mut hash_merkle_root = [0u8; 32];
+    reader.read_exact(&mut hash_merkle_root)?;
+    let n_time = reader.read_u32::<LittleEndian>()?;
+    let n_bits = reader.read_u32::<LittleEndian>()?;
+    let n_nonce = reader.read_u32::<LittleEndian>()?;
+
+    // The extra 32 bytes of a 112 byte header depend on the version
+    let mut extra = [0u8; 32];
+    let (hash_final_sapling_root, n_accumulator_checkpoint) = match n_version {
+        8..=11 => {
+            reader.read_exact(&mut extra)?;
+            (Some(extra), None)
+        }
+        4..=6 => {
+            reader.read_exact(&mut extra)?;
+            (None, Some(extra))
+        }
+        _ => (None, None),
+    };
+
+    let header = CBlockHeader {
+        n_version,
+        block_hash: block_hash.into(),
+        block_height: None,
+        hash_prev_block,
+        hash_merkle_root,
+        n_time,
+        n_bits,
+        n_nonce,
+        n_accumulator_checkpoint,
+        hash_final_sapling_root,
+    };
+    Ok((header, size))
+}
+
+// Yields the blocks framed in any byte source (a blk file, a raw buffer, a network stream, ...)
+pub struct BlockStream<R> {
+    reader: R,
+    magic: [u8; 4],
+    position: u64,
+}
+
+impl<R: Read> BlockStream<R> {
+    // `position` is how far into its source the reader already is, so block
+    // offsets stay relative to the start of the source
+    pub fn starting_at(reader: R, magic: [u8; 4], position: u64) -> Self {
+        BlockStream { reader, magic, position }
+    }
+
+    // Advance past the next magic; false once the source is exhausted
+    // (blk files are preallocated, so they usually end in zero padding)
+    fn find_magic(&mut self) -> io::Result<bool> {
+        let mut window = [0u8; 4];
+        let mut filled = 0;
+        let mut byte = [0u8; 1];
+        loop {
+            match self.reader.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            self.position += 1;
+
+            window.rotate_left(1);
+            window[3] = byte[0];
+            filled += 1;
+            if filled >= 4 && window == self.magic {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn read_block(&mut self) -> io::Result<Option<RawBlock>> {
+        if !self.find_magic()? {
+            return Ok(None);
+        }
+        let offset = self.position - 4;
+
+        let size = match self.reader.read_u32::<LittleEndian>() {
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.position += 4;
+
+        // A truncated final block is one the node is still writing
+        let mut data = vec![0u8; size as usize];
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.position += size as u64;
+
+        RawBlock::from_data(offset, data).map(Some)
+    }
+}
+
+impl<R: Read> Iterator for BlockStream<R> {
+    type Item = io::Result<RawBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_block().transpose()
+    }
+}
+
+// BlockStream for async sources. It works with any executor: tokio types go
+// through tokio-util's compat layer, and a source should be buffered since
+// magic bytes are searched for one byte at a time.
+pub struct AsyncBlockStream<R> {
+    reader: R,
+    magic: [u8; 4],
+    position: u64,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncBlockStream<R> {
+    // Seek the source to `offset`, e.g. a block offset recorded by an earlier
+    // run, and yield the blocks from there
+    pub async fn open_at(mut reader: R, magic: [u8; 4], offset: u64) -> io::Result<Self> {
+        let position = poll_fn(|cx| Pin::new(&mut reader).poll_seek(cx, SeekFrom::Start(offset))).await?;
+        Ok(AsyncBlockStream { reader, magic, position })
+    }
+
+    // The next block, None once the source is exhausted
+    pub async fn next_block(&mut self) -> Option<io::Result<RawBlock>> {
+        self.read_block().await.transpose()
+    }
+
+    // Fill `buf`; false when the source ends first
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let reader = &mut self.reader;
+            match poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf[filled..])).await {
+                Ok(0) => return Ok(false),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.position += buf.len() as u64;
+        Ok(true)
+    }
+
+    async fn find_magic(&mut self) -> io::Result<bool> {
+        let mut window = [0u8; 4];
+        let mut filled = 0;
+        let mut byte = [0u8; 1];
+        loop {
+            if !self.read_exact(&mut byte).await? {
+                return Ok(false);
+            }
+            window.rotate_left(1);
+            window[3] = byte[0];
+            filled += 1;
+            if filled >= 4 && window == self.magic {
+                return Ok(true);
+            }
+        }
+    }
+
+    async fn read_block(&mut self) -> io::Result<Option<RawBlock>> {
+        if !self.find_magic().await? {
+            return Ok(None);
+        }
+        let offset = self.position - 4;
+
+        let mut size = [0u8; 4];
+        if !self.read_exact(&mut size).await? {
+            return Ok(None);
+        }
+        // A truncated final block is one the node is still writing
+        let mut data = vec![0u8; u32::from_le_bytes(size) as usize];
+        if !self.read_exact(&mut data).await? {
+            return Ok(None);
+        }
+
+        RawBlock::from_data(offset, data).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    const MAGIC: [u8; 4] = [0x90, 0xc4, 0xfd, 0xe9];
+
+    // A version 1 header (80 bytes) with a distinguishing nonce and no transactions
+    fn block(nonce: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 80];
+        data[0] = 1;
+        data[76..80].copy_from_slice(&nonce.to_le_bytes());
+        data.push(0);
+        data
+    }
+
+    fn framed(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for data in blocks {
+            file.extend_from_slice(&MAGIC);
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    // Two blocks, preallocation padding, then a block cut short by the writer
+    fn blk_file() -> Vec<u8> {
+        let mut file = framed(&[block(1), block(2)]);
+        file.extend_from_slice(&[0u8; 16]);
+        let mut partial = framed(&[block(3)]);
+        partial.truncate(40);
+        file.extend_from_slice(&partial);
+        file
+    }
+
+    // An in-memory source that is not ready on every other poll
+    struct SlowSource {
+        data: Cursor<Vec<u8>>,
+        ready: bool,
+    }
+
+    impl AsyncRead for SlowSource {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.data.read(buf))
+        }
+    }
+
+    impl AsyncSeek for SlowSource {
+        fn poll_seek(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, pos: SeekFrom) -> Poll<io::Result<u64>> {
+            Poll::Ready(io::Seek::seek(&mut self.data, pos))
+        }
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn summary(block: RawBlock) -> (u64, u32, u32) {
+        (block.offset, block.header.n_nonce, block.size())
+    }
+
+    #[test]
+    fn stream_yields_complete_blocks() {
+        let blocks: Vec<_> = BlockStream::starting_at(Cursor::new(blk_file()), MAGIC, 0)
+            .map(|block| summary(block.unwrap()))
+            .collect();
+        assert_eq!(blocks, vec![(0, 1, 81), (89, 2, 81)]);
+    }
+
+    #[test]
+    fn stream_keeps_offsets_relative_to_the_source() {
+        let file = blk_file();
+        let blocks: Vec<_> = BlockStream::starting_at(Cursor::new(&file[89..]), MAGIC, 89)
+            .map(|block| summary(block.unwrap()))
+            .collect();
+        assert_eq!(blocks, vec![(89, 2, 81)]);
+    }
+
+    #[test]
+    fn async_stream_matches_the_sync_one() {
+        let source = SlowSource { data: Cursor::new(blk_file()), ready: false };
+        let blocks = block_on(async {
+            let mut stream = AsyncBlockStream::open_at(source, MAGIC, 0).await.unwrap();
+            let mut blocks = Vec::new();
+            while let Some(block) = stream.next_block().await {
+                blocks.push(summary(block.unwrap()));
+            }
+            blocks
+        });
+        assert_eq!(blocks, vec![(0, 1, 81), (89, 2, 81)]);
+    }
+
+    #[test]
+    fn async_stream_resumes_at_an_offset() {
+        let source = SlowSource { data: Cursor::new(blk_file()), ready: false };
+        let block = block_on(async {
+            let mut stream = AsyncBlockStream::open_at(source, MAGIC, 89).await.unwrap();
+            stream.next_block().await.unwrap().unwrap()
+        });
+        assert_eq!(summary(block), (89, 2, 81));
+    }
+
+    #[test]
+    fn short_block_data_is_an_error() {
+        let file = framed(&[vec![1, 0, 0, 0, 0]]);
+        let mut stream = BlockStream::starting_at(Cursor::new(file), MAGIC, 0);
+        assert!(stream.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use bitcoin::consensus::encode::{Decodable, VarInt};
+use byteorder::{LittleEndian, ReadBytesExt};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::parser::address::{address_type_to_string, scriptpubkey_to_address};
+use crate::parser::types::{
+    AddressType, COutPoint, CScript, CTransaction, CTxIn, CTxOut, SaplingTxData, VShieldOutput, VShieldSpend,
+};
+
+// A transaction read from block bytes, before anything is written to the DB
+pub struct DecodedTx {
+    pub transaction: CTransaction,
+    pub general_address_type: AddressType,
+    pub tx_bytes: Vec<u8>,
+    pub sapling: Option<SaplingTxData>,
+    // Set by resolve
+    pub txid: Vec<u8>,
+    pub address_types: Vec<AddressType>,
+}
+
+impl DecodedTx {
+    pub fn new(transaction: CTransaction, general_address_type: AddressType, tx_bytes: Vec<u8>, sapling: Option<SaplingTxData>) -> Self {
+        DecodedTx {
+            transaction,
+            general_address_type,
+            tx_bytes,
+            sapling,
+            txid: Vec::new(),
+            address_types: Vec::new(),
+        }
+    }
+
+    // Work that only needs this transaction's bytes, safe to run in parallel
+    pub fn resolve(&mut self) -> Result<(), io::Error> {
+        self.txid = hash_txid(&self.tx_bytes)?;
+        for tx_out in &mut self.transaction.outputs {
+            let address_type = get_address_type(tx_out, &self.general_address_type);
+            tx_out.address = address_type_to_string(Some(address_type.clone()));
+            self.address_types.push(address_type);
+        }
+        Ok(())
+    }
+}
+
+// Decode a block's transactions, the reader positioned at the transaction
+// count. Transactions this parser doesn't support for the block version come
+// back as None, and every other one is resolved.
+pub fn decode_transactions<R: BufRead + Seek>(reader: &mut R, block_version: u32) -> io::Result<Vec<Option<DecodedTx>>> {
+    let tx_amt = read_varint(reader)?;
+
+    // Transactions have to be walked in order to find where each one ends
+    let mut decoded = Vec::with_capacity(tx_amt as usize);
+    for _ in 0..tx_amt {
+        let start_pos = reader.stream_position()?;
+
+        let tx_ver_out = reader.read_u16::<LittleEndian>()?;
+        reader.read_u16::<LittleEndian>()?; // Transaction type
+
+        let supported = block_version == 11
+            || (tx_ver_out <= 2 && block_version < 11)
+            || (tx_ver_out > 1 && block_version > 7);
+        if !supported {
+            decoded.push(None);
+        } else if tx_ver_out <= 2 {
+            decoded.push(Some(parse_transaction_v1(reader, tx_ver_out.try_into().unwrap(), block_version, start_pos)?));
+        } else {
+            decoded.push(Some(parse_sapling_tx_data(reader, tx_ver_out.try_into().unwrap(), start_pos)?));
+        }
+    }
+
+    // Hashing and address encoding don't depend on other transactions
+    decoded.par_iter_mut().flatten().try_for_each(DecodedTx::resolve)?;
+    Ok(decoded)
+}
+
+pub fn parse_transaction_v1<R: BufRead + Seek>(reader: &mut R, tx_ver_out: i16, block_version: u32, start_pos: u64) -> Result<DecodedTx, io::Error> {
+    let input_count = read_varint(reader)?;
+
+    let inputs = (0..input_count)
+    .map(|i| {
+        let mut coinbase = None;
+        let mut prev_output = None;
+        let mut script = None;
+
+        match (block_version, tx_ver_out) {
+            (ver, 2) if ver < 3 => {
+                let mut buffer = [0; 26];
+                reader.read_exact(&mut buffer)?;
+                coinbase = Some(buffer.to_vec());
+            }
+            _ => {
+                prev_output = Some(read_outpoint(reader)?);
+                script = Some(read_script(reader)?);
+            }
+        }
+
+        let sequence = reader.read_u32::<LittleEndian>()?;
+        Ok(CTxIn {
+            prevout: prev_output,
+            script_sig: CScript { script: script.unwrap_or_default() }, 
+            sequence,
+            index: i,
+            coinbase,
+        })
+    })
+    .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    let output_count = read_varint(reader)?;
+    let general_address_type = if input_count == 1 && output_count == 1 {
+        AddressType::CoinBaseTx
+    } else if output_count > 1 {
+        AddressType::CoinStakeTx
+    } else {
+        AddressType::Nonstandard
+    };
+    let outputs = (0..output_count)
+        .map(|i| {
+            let value = reader.read_i64::<LittleEndian>()?;
+            let script = read_script(reader)?;
+            Ok(CTxOut {
+                value,
+                script_length: script.len().try_into().unwrap(),
+                script_pubkey: CScript { script },
+                index: i,
+                address: Vec::new(), // Filled in by DecodedTx::resolve
+            })
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    let lock_time_buff = reader.read_u32::<LittleEndian>()?;
+
+    let transaction = CTransaction {
+        version: tx_ver_out, 
+        inputs,
+        outputs,
+        lock_time: lock_time_buff, 
+    };
+
+    let end_pos: u64 = set_end_pos(reader, start_pos)?;
+    let tx_bytes: Vec<u8> = get_txid_bytes(reader, start_pos, end_pos)?;
+    //println!("Tx Bytes: {:?}", hex::encode(&tx_bytes));
+    reader.seek(SeekFrom::Start(end_pos))?;
+
+    Ok(DecodedTx::new(transaction, general_address_type, tx_bytes, None))
+}
+
+pub fn get_address_type(tx_out: &CTxOut, general_address_type: &AddressType) -> AddressType {
+    if !tx_out.script_pubkey.script.is_empty() {
+        scriptpubkey_to_address(&tx_out.script_pubkey).unwrap_or_else(|| general_address_type.clone())
+    } else {
+        general_address_type.clone()
+    }
+}
+
+pub fn get_txid_bytes<R: Read + Seek>(reader: &mut R, start_pos: u64, end_pos: u64) -> Result<Vec<u8>, io::Error> {
+    // Calculate tx_size
+    let tx_size = (end_pos - start_pos) as usize;
+    let mut tx_bytes = vec![0u8; tx_size];
+    // Read the transaction bytes
+    reader.seek(SeekFrom::Start(start_pos))?;
+    reader.read_exact(&mut tx_bytes)?;
+
+    Ok(tx_bytes)
+}
+
+pub fn set_end_pos<R: Read + Seek>(reader: &mut R, start_pos: u64) -> Result<u64, io::Error> {
+    let end_pos = reader.stream_position()?;
+    reader.seek(SeekFrom::Start(start_pos))?;
+    Ok(end_pos)
+}
+
+pub fn hash_txid(tx_bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+    //Create TXID by hashing twice and reversing result
+    let first_hash = Sha256::digest(tx_bytes);
+    let txid = Sha256::digest(&first_hash);
+    let reversed_txid: Vec<_> = txid.iter().rev().cloned().collect();
+
+    Ok(reversed_txid)
+}
+
+pub fn read_outpoint(reader: &mut dyn Read) -> io::Result<COutPoint> {
+    // Set size for hash
+    let mut hash = [0u8; 32];
+    // Read hash
+    reader.read_exact(&mut hash)?;
+    // Read output index
+    let n = reader.read_u32::<LittleEndian>()?;
+    let reversed_bytes = reverse_bytes(&hash);
+    let hex_hash = hex::encode(&reversed_bytes);
+
+    Ok(COutPoint { hash: hex_hash, n })
+}
+
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let varint = VarInt::consensus_decode(reader)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(varint.0)
+}
+
+pub fn parse_sapling_tx_data<R: BufRead + Seek>(reader: &mut R, tx_ver_out: i16, start_pos: u64) -> Result<DecodedTx, io::Error> {
+
+    // Set empty vectors for later access
+    let mut inputs: Vec<CTxIn> = Vec::new();
+    let mut outputs: Vec<CTxOut> = Vec::new();
+    // Potential Vin Vector
+    let input_count = read_varint2(reader)? as u64;
+
+    if input_count > 0 {
+        inputs = (0..input_count)
+            .map(|i| {
+                let coinbase = None;
+                let prev_output = read_outpoint(reader)?;
+                let script = read_script(reader)?;
+                let sequence = reader.read_u32::<LittleEndian>()?;
+                Ok(CTxIn {
+                    prevout: Some(prev_output),
+                    script_sig: CScript { script },
+                    sequence,
+                    index: i,
+                    coinbase,
+                })
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+    }
+
+    let output_count = read_varint(reader)?;
+    let general_address_type = if input_count == 1 && output_count == 1 {
+        AddressType::CoinBaseTx
+    } else if output_count > 1 {
+        AddressType::CoinStakeTx
+    } else {
+        AddressType::Nonstandard
+    };
+
+    if output_count > 0 {
+        outputs = (0..output_count)
+            .map(|i| {
+                let value = reader.read_i64::<LittleEndian>()?;
+                let script = read_script(reader)?;
+                Ok(CTxOut {
+                    value,
+                    script_length: script.len().try_into().unwrap(),
+                    script_pubkey: CScript { script },
+                    index: i,
+                    address: Vec::new(), // Filled in by DecodedTx::resolve
+                })
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+    }
+
+    let lock_time_buff = reader.read_u32::<LittleEndian>()?;
+    // Hacky fix for getting proper values/spends/outputs for Sapling
+    read_varint(reader)?; // Value count
+    let value = reader.read_i64::<LittleEndian>()?;
+    // Read the SaplingTxData
+    let vshield_spend = parse_vshield_spends(reader)?;
+    let vshield_output = parse_vshield_outputs(reader)?;
+    // Read the binding_sig as an array of unsigned chars max size 64
+    let mut binding_sig = [0u8; 64];
+    reader.read_exact(&mut binding_sig)?;
+
+    // Create and return the SaplingTxData struct
+    let sapling_tx_data = SaplingTxData {
+        value,
+        vshield_spend,
+        vshield_output,
+        binding_sig: binding_sig.to_vec(),
+    };
+
+    let end_pos: u64 = set_end_pos(reader, start_pos)?;
+    let tx_bytes: Vec<u8> = get_txid_bytes(reader, start_pos, end_pos)?;
+    //println!("Tx Bytes: {:?}", hex::encode(&tx_bytes));
+
+    let transaction = CTransaction {
+        version: tx_ver_out,
+        inputs,
+        outputs,
+        lock_time: lock_time_buff,
+    };
+    Ok(DecodedTx::new(transaction, general_address_type, tx_bytes, Some(sapling_tx_data)))
+}
+
+pub fn parse_vshield_spends<R: Read>(reader: &mut R) -> Result<Vec<VShieldSpend>, io::Error> {
+    // Read the number of vShieldSpend entries
+    let count = read_varint(reader)? as usize;
+    //println!("vShieldSpend Count: {}", count);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Define buffer sizes for respective fields
+    let buff_32 = [0u8; 32];
+    let buff_64 = [0u8; 64];
+    let buff_192 = [0u8; 192];
+
+    // Read each vShieldSpend entry
+    let mut vshield_spends = Vec::with_capacity(count);
+    for _ in 0..count {
+        // Read each field
+        let mut cv = buff_32;
+        reader.read_exact(&mut cv)?;
+        let mut anchor = buff_32;
+        reader.read_exact(&mut anchor)?;
+        let mut nullifier = buff_32;
+        reader.read_exact(&mut nullifier)?;
+        let mut rk = buff_32;
+        reader.read_exact(&mut rk)?;
+        let mut proof = buff_192;
+        reader.read_exact(&mut proof)?;
+        let mut spend_auth_sig = buff_64;
+        reader.read_exact(&mut spend_auth_sig)?;
+
+        // Create and return the VShieldSpend struct
+        let vshield_spend = VShieldSpend {
+            cv: reverse_bytes(&cv),
+            anchor: reverse_bytes(&anchor),
+            nullifier: reverse_bytes(&nullifier),
+            rk: reverse_bytes(&rk),
+            proof: proof.to_vec(),
+            spend_auth_sig: spend_auth_sig.to_vec(),
+        };
+        vshield_spends.push(vshield_spend);
+        //println!("{:?}", vshield_spends);
+    }
+    Ok(vshield_spends)
+}
+
+pub fn parse_vshield_outputs<R: Read>(reader: &mut R) -> Result<Vec<VShieldOutput>, io::Error> {
+    // Read the number of vShieldOutput entries
+    let count = read_varint(reader)? as usize;
+    //println!("vShieldOutput Count: {}", count);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Define buffer sizes for respective fields
+    let buff_32 = [0u8; 32];
+    let buff_80 = [0u8; 80];
+    let buff_192 = [0u8; 192];
+    let buff_580 = [0u8; 580];
+
+    // Read each vShieldOutput entry
+    let mut vshield_outputs = Vec::with_capacity(count);
+    for _ in 0..count {
+        // Read each field
+        let mut cv = buff_32;
+        reader.read_exact(&mut cv)?;
+        let mut cmu = buff_32;
+        reader.read_exact(&mut cmu)?;
+        let mut ephemeral_key = buff_32;
+        reader.read_exact(&mut ephemeral_key)?;
+        let mut enc_ciphertext = buff_580;
+        reader.read_exact(&mut enc_ciphertext)?;
+        let mut out_ciphertext = buff_80;
+        reader.read_exact(&mut out_ciphertext)?;
+        let mut proof = buff_192;
+        reader.read_exact(&mut proof)?;
+
+        // Create and return the VShieldOutput struct
+        let vshield_output = VShieldOutput {
+            cv: reverse_bytes(&cv),
+            cmu: reverse_bytes(&cmu),
+            ephemeral_key: reverse_bytes(&ephemeral_key),
+            enc_ciphertext: enc_ciphertext.to_vec(),
+            out_ciphertext: out_ciphertext.to_vec(),
+            proof: proof.to_vec(),
+        };
+        vshield_outputs.push(vshield_output);
+        //println!("{:?}", vshield_outputs);
+    }
+
+    Ok(vshield_outputs)
+}
+
+pub fn read_script<R: io::Read>(reader: &mut R) -> Result<Vec<u8>, io::Error> {
+    let script_length = read_varint(reader)?;
+    let mut script = vec![0u8; script_length as usize];
+    reader.read_exact(&mut script)?;
+    Ok(script)
+}
+
+pub fn reverse_bytes(array: &[u8]) -> Vec<u8> {
+    let mut vec = Vec::from(array);
+    vec.reverse();
+    vec
+}
+
+// Bitcoin normal varint
+pub fn read_varint2<R: Read + ?Sized>(reader: &mut R) -> io::Result<u64> {
+    let first = reader.read_u8()?; // read first length byte
+    let value = match first {
+        0x00..=0xfc => u64::from(first),
+        0xfd => u64::from(reader.read_u16::<LittleEndian>()?),
+        0xfe => u64::from(reader.read_u32::<LittleEndian>()?),
+        0xff => reader.read_u64::<LittleEndian>()?,
+    };
+    Ok(value)
+}
+
+pub fn sha256d(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    let first = hasher.finalize();
+
+    let mut hasher = Sha256::new();
+    hasher.update(first);
+    hasher.finalize().to_vec()
+}
+
+// Merkle root of txids in internal byte order, duplicating the last hash of odd levels
+pub fn merkle_root(txids: &[Vec<u8>]) -> Vec<u8> {
+    let mut level = txids.to_vec();
+    if level.is_empty() {
+        return vec![0u8; 32];
+    }
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1].clone());
+        }
+        level = level.chunks(2).map(|pair| sha256d(&[&pair[0][..], &pair[1][..]].concat())).collect();
+    }
+    level.remove(0)
+}
@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+
+// Progress through one blk file, reported after every indexed block
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    pub file: PathBuf,
+    pub file_size: u64,
+    // Bytes consumed up to the end of the last indexed block
+    pub bytes_processed: u64,
+    pub blocks_indexed: u64,
+    pub height: i32,
+}
+
+impl FileProgress {
+    pub fn new(file: &Path, file_size: u64) -> Self {
+        FileProgress {
+            file: file.to_path_buf(),
+            file_size,
+            bytes_processed: 0,
+            blocks_indexed: 0,
+            height: 0,
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.file_size == 0 {
+            return 100.0;
+        }
+        self.bytes_processed as f64 * 100.0 / self.file_size as f64
+    }
+}
+
+// Receives progress updates from the blk file indexer
+pub trait ProgressSink {
+    fn block_indexed(&mut self, progress: &FileProgress);
+    fn file_finished(&mut self, progress: &FileProgress);
+}
+
+// Prints a line every `every` blocks and when a file is done
+pub struct ConsoleProgress {
+    every: u64,
+}
+
+impl ConsoleProgress {
+    pub fn new(every: u64) -> Self {
+        ConsoleProgress { every: every.max(1) }
+    }
+}
+
+impl ProgressSink for ConsoleProgress {
+    fn block_indexed(&mut self, progress: &FileProgress) {
+        if progress.blocks_indexed.is_multiple_of(self.every) {
+            println!("{}: {} blocks, height {}, {:.1}%",
+                progress.file.display(), progress.blocks_indexed, progress.height, progress.percent());
+        }
+    }
+
+    fn file_finished(&mut self, progress: &FileProgress) {
+        println!("{}: done, {} blocks, {} bytes, last height {}",
+            progress.file.display(), progress.blocks_indexed, progress.bytes_processed, progress.height);
+    }
+}
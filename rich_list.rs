@@ -0,0 +1,135 @@
+use std::io;
+
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::Serialize;
+
+use crate::block_commit::BlockCommit;
+use crate::AddressBalance;
+
+// Rank keys written per batch when building the index
+const BUILD_BATCH_KEYS: usize = 100_000;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RichListEntry {
+    pub rank: usize,
+    pub address: String,
+    pub balance: i64,
+    // Heights of the first and last balance change, unknown for addresses
+    // untouched since they were tracked
+    pub first_seen: Option<i32>,
+    pub last_active: Option<i32>,
+}
+
+// 'r' + inverted balance (big endian, so the largest balances sort first)
+// + address -> (), in addr_balances. Only positive balances are ranked.
+fn rank_key(balance: i64, address: &str) -> Vec<u8> {
+    let mut key = vec![b'r'];
+    key.extend_from_slice(&(!(balance as u64)).to_be_bytes());
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+// 'f' + address -> first seen height (i32 LE) + last active height (i32 LE), in addr_balances
+fn activity_key(address: &str) -> Vec<u8> {
+    let mut key = vec![b'f'];
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+// Move an address to its new place in the ranking and note the activity
+pub fn update(commit: &mut BlockCommit, address: &str, height: i32, old_balance: i64, new_balance: i64) -> io::Result<()> {
+    if old_balance != new_balance {
+        if old_balance > 0 {
+            commit.delete_cf("addr_balances", &rank_key(old_balance, address));
+        }
+        if new_balance > 0 {
+            commit.put_cf("addr_balances", &rank_key(new_balance, address), &[]);
+        }
+    }
+
+    let key = activity_key(address);
+    let first_seen = match commit.get_cf("addr_balances", &key)? {
+        Some(value) if value.len() == 8 => i32::from_le_bytes(value[..4].try_into().unwrap()),
+        _ => height,
+    };
+    let mut value = first_seen.to_le_bytes().to_vec();
+    value.extend_from_slice(&height.to_le_bytes());
+    commit.put_cf("addr_balances", &key, &value);
+    Ok(())
+}
+
+// Fix an address's rank in a batch that rewrites its balance outside a block commit
+pub fn rerank(db: &DB, batch: &mut WriteBatch, address: &str, old_balance: i64, new_balance: i64) {
+    let cf_balances = db.cf_handle("addr_balances").expect("Address balances column family not found");
+    if old_balance > 0 {
+        batch.delete_cf(cf_balances, rank_key(old_balance, address));
+    }
+    if new_balance > 0 {
+        batch.put_cf(cf_balances, rank_key(new_balance, address), []);
+    }
+}
+
+// Rebuild the ranking from the stored balances, for databases indexed before
+// it was kept and after balances are rebuilt
+pub fn build(db: &DB) -> io::Result<()> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_balances = db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let snapshot = db.snapshot();
+
+    let mut batch = WriteBatch::default();
+    for item in snapshot.iterator_cf(cf_balances, IteratorMode::From(b"r", Direction::Forward)) {
+        let (key, _) = item.map_err(to_io)?;
+        if key[0] != b'r' {
+            break;
+        }
+        batch.delete_cf(cf_balances, &key);
+        if batch.len() >= BUILD_BATCH_KEYS {
+            db.write(std::mem::take(&mut batch)).map_err(to_io)?;
+        }
+    }
+
+    for item in snapshot.iterator_cf(cf_balances, IteratorMode::From(b"b", Direction::Forward)) {
+        let (key, value) = item.map_err(to_io)?;
+        if key[0] != b'b' {
+            break;
+        }
+        let entry: AddressBalance = bincode::deserialize(&value).unwrap_or_default();
+        if entry.balance > 0 {
+            batch.put_cf(cf_balances, rank_key(entry.balance, &String::from_utf8_lossy(&key[1..])), []);
+        }
+        if batch.len() >= BUILD_BATCH_KEYS {
+            db.write(std::mem::take(&mut batch)).map_err(to_io)?;
+        }
+    }
+
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    batch.put_cf(cf_state, b"rich_list_built", [1u8]);
+    db.write(batch).map_err(to_io)
+}
+
+// The `limit` addresses with the largest balances, largest first
+pub fn top(db: &DB, limit: usize) -> io::Result<Vec<RichListEntry>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_balances = db.cf_handle("addr_balances").expect("Address balances column family not found");
+    let snapshot = db.snapshot();
+
+    let mut entries = Vec::new();
+    for item in snapshot.iterator_cf(cf_balances, IteratorMode::From(b"r", Direction::Forward)) {
+        let (key, _) = item.map_err(to_io)?;
+        if key[0] != b'r' || key.len() < 9 || entries.len() >= limit {
+            break;
+        }
+        let address = String::from_utf8_lossy(&key[9..]).into_owned();
+        let activity = snapshot.get_cf(cf_balances, activity_key(&address)).map_err(to_io)?
+            .filter(|value| value.len() == 8);
+        entries.push(RichListEntry {
+            rank: entries.len() + 1,
+            balance: !u64::from_be_bytes(key[1..9].try_into().unwrap()) as i64,
+            first_seen: activity.as_ref().map(|value| i32::from_le_bytes(value[..4].try_into().unwrap())),
+            last_active: activity.as_ref().map(|value| i32::from_le_bytes(value[4..].try_into().unwrap())),
+            address,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+
+use crate::shutdown;
+
+// Tasks that can be given a schedule under [schedule]
+pub const TASKS: [&str; 2] = ["sync", "compact"];
+
+// A five field cron expression (minute hour day-of-month month day-of-week),
+// evaluated in UTC. Each field is `*`, a number, a range `a-b`, a step `*/n`
+// or `a-b/n`, or a comma separated list of those.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in cron, when both day fields are restricted either one may match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("'{}' must have 5 fields (minute hour day month weekday)", s));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Schedule {
+    // Whether the minute containing `unix_secs` is a scheduled one
+    pub fn matches(&self, unix_secs: u64) -> bool {
+        let days_since_epoch = (unix_secs / 86400) as i64;
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        let minute = unix_secs / 60 % 60;
+        let hour = unix_secs / 3600 % 24;
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4).rem_euclid(7);
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => bit(self.days, day) || bit(self.weekdays, weekday as u32),
+            _ => bit(self.days, day) && bit(self.weekdays, weekday as u32),
+        };
+        bit(self.minutes, minute as u32) && bit(self.hours, hour as u32) && bit(self.months, month) && day_matches
+    }
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Bad step in '{}'", part))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Step must be positive in '{}'", part));
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, part)?, parse_value(end, part)?)
+        } else {
+            let value = parse_value(range, part)?;
+            // `a/n` runs from a to the end of the field
+            (value, if part.contains('/') { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{}' is outside {}-{}", part, min, max));
+        }
+        for n in (start..=end).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_value(value: &str, part: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("Bad value in '{}'", part))
+}
+
+// (year, month, day) of a day count since 1970-01-01, from Howard Hinnant's
+// civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Outcome of a task's most recent run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskStatus {
+    pub last_run: u64,
+    pub duration_secs: u64,
+    pub ok: bool,
+    // Error message of a failed run
+    pub error: Option<String>,
+}
+
+// chain_state "task_status" -> JSON map of task name to its last run
+pub fn load_statuses(db: &DB) -> io::Result<BTreeMap<String, TaskStatus>> {
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    match db.get_cf(cf_state, b"task_status").map_err(|e| io::Error::other(e.to_string()))? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn record_status(db: &DB, task: &str, status: TaskStatus) -> io::Result<()> {
+    let mut statuses = load_statuses(db)?;
+    statuses.insert(task.to_string(), status);
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    db.put_cf(cf_state, b"task_status", serde_json::to_vec(&statuses)?)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Run tasks whenever their schedule matches, until a shutdown is requested.
+// Tasks run one at a time in the order given; a minute that passes while a
+// task runs is not caught up.
+pub fn run<F>(db: &DB, tasks: &[(String, Schedule)], mut run_task: F) -> io::Result<()>
+where
+    F: FnMut(&str) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let mut last_minute = 0;
+    loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let minute = now / 60;
+        if minute != last_minute {
+            last_minute = minute;
+            for (name, schedule) in tasks {
+                if shutdown::requested() {
+                    return Ok(());
+                }
+                if !schedule.matches(now) {
+                    continue;
+                }
+                println!("Running scheduled task {}", name);
+                let started = Instant::now();
+                let result = run_task(name);
+                if let Err(e) = &result {
+                    eprintln!("Scheduled task {} failed: {}", name, e);
+                }
+                record_status(db, name, TaskStatus {
+                    last_run: now,
+                    duration_secs: started.elapsed().as_secs(),
+                    ok: result.is_ok(),
+                    error: result.err().map(|e| e.to_string()),
+                })?;
+            }
+        }
+
+        // Wake every second to notice a shutdown request
+        if shutdown::requested() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-03-13 08:30 UTC, a Friday
+    const FRIDAY_13TH: u64 = 1773390600;
+    // Midnight UTC of other days in March 2026
+    const SATURDAY_14TH: u64 = 1773446400;
+    const SUNDAY_15TH: u64 = 1773532800;
+    const FRIDAY_20TH: u64 = 1773964800;
+    // 2026-10-13 00:00 UTC, a Tuesday
+    const TUESDAY_13TH: u64 = 1791849600;
+
+    fn schedule(expression: &str) -> Schedule {
+        expression.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn at_minute(minute: u64) -> u64 {
+        SATURDAY_14TH + minute * 60
+    }
+
+    #[test]
+    fn known_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+        assert_eq!(civil_from_days(20818), (2026, 12, 31));
+    }
+
+    #[test]
+    fn known_timestamps() {
+        assert!(schedule("30 8 13 3 5").matches(FRIDAY_13TH));
+        // Seconds within the minute don't matter
+        assert!(schedule("30 8 13 3 5").matches(FRIDAY_13TH + 59));
+        assert!(!schedule("30 8 13 3 5").matches(FRIDAY_13TH + 60));
+        assert!(schedule("59 23 31 12 *").matches(1798761540));
+        assert!(schedule("0 0 1 1 4").matches(0)); // The epoch was a Thursday
+        assert!(!schedule("0 0 * 2 *").matches(SATURDAY_14TH));
+    }
+
+    #[test]
+    fn steps() {
+        let every_quarter = schedule("*/15 * * * *");
+        for minute in 0..60 {
+            assert_eq!(every_quarter.matches(at_minute(minute)), minute % 15 == 0, "minute {}", minute);
+        }
+        let ranged = schedule("10-40/10 * * * *");
+        let minutes: Vec<u64> = (0..60).filter(|m| ranged.matches(at_minute(*m))).collect();
+        assert_eq!(minutes, vec![10, 20, 30, 40]);
+        // a/n runs to the end of the field
+        let from_five = schedule("5/20 * * * *");
+        let minutes: Vec<u64> = (0..60).filter(|m| from_five.matches(at_minute(*m))).collect();
+        assert_eq!(minutes, vec![5, 25, 45]);
+    }
+
+    #[test]
+    fn ranges_and_lists() {
+        let office_hours = schedule("0 9-17 * * 1-5");
+        assert!(office_hours.matches(FRIDAY_20TH + 9 * 3600));
+        assert!(office_hours.matches(FRIDAY_20TH + 17 * 3600));
+        assert!(!office_hours.matches(FRIDAY_20TH + 18 * 3600));
+        assert!(!office_hours.matches(SATURDAY_14TH + 9 * 3600));
+
+        let list = schedule("0,30 0,12 * * *");
+        assert!(list.matches(at_minute(30)));
+        assert!(list.matches(at_minute(12 * 60)));
+        assert!(!list.matches(at_minute(15)));
+        assert!(!list.matches(at_minute(6 * 60)));
+
+        let mixed = schedule("1-3,50,*/29 * * * *");
+        let minutes: Vec<u64> = (0..60).filter(|m| mixed.matches(at_minute(*m))).collect();
+        assert_eq!(minutes, vec![0, 1, 2, 3, 29, 50, 58]);
+    }
+
+    #[test]
+    fn sunday_as_0_or_7() {
+        for expression in ["0 0 * * 0", "0 0 * * 7", "0 0 * * 6-7", "0 0 * * 5,7"] {
+            assert!(schedule(expression).matches(SUNDAY_15TH), "{}", expression);
+        }
+        assert!(!schedule("0 0 * * 7").matches(SATURDAY_14TH));
+        assert!(schedule("0 0 * * 6-7").matches(SATURDAY_14TH));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // Both restricted: either one matching is enough
+        let either = schedule("0 0 13 * 5");
+        assert!(either.matches(FRIDAY_20TH));
+        assert!(either.matches(TUESDAY_13TH));
+        assert!(!either.matches(SATURDAY_14TH));
+
+        // Only one restricted: that one has to match
+        let thirteenth = schedule("0 0 13 * *");
+        assert!(thirteenth.matches(TUESDAY_13TH));
+        assert!(!thirteenth.matches(FRIDAY_20TH));
+        let fridays = schedule("0 0 * * 5");
+        assert!(fridays.matches(FRIDAY_20TH));
+        assert!(!fridays.matches(TUESDAY_13TH));
+    }
+
+    #[test]
+    fn bad_expressions() {
+        for expression in [
+            "* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *",
+            "* * * * 8", "*/0 * * * *", "30-10 * * * *", "x * * * *", "*/x * * * *", "1,,2 * * * *",
+        ] {
+            assert!(expression.parse::<Schedule>().is_err(), "{} accepted", expression);
+        }
+    }
+}
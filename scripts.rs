@@ -0,0 +1,397 @@
+use crate::parser::address::{
+    scriptpubkey_to_p2pk, scriptpubkey_to_p2pkh_address, scriptpubkey_to_p2sh_address,
+    scriptpubkey_to_staking_address,
+};
+use crate::parser::types::{AddressType, CScript};
+
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_IF: u8 = 0x63;
+const OP_ELSE: u8 = 0x67;
+const OP_ENDIF: u8 = 0x68;
+const OP_RETURN: u8 = 0x6a;
+const OP_DUP: u8 = 0x76;
+const OP_ROT: u8 = 0x7b;
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKCOLDSTAKEVERIFY_LOF: u8 = 0xd1;
+const OP_CHECKCOLDSTAKEVERIFY: u8 = 0xd2;
+const OP_ZEROCOINMINT: u8 = 0xc1;
+const OP_ZEROCOINSPEND: u8 = 0xc2;
+const OP_ZEROCOINPUBLICSPEND: u8 = 0xc3;
+
+// An output script template; new script types are added by implementing this
+// and listing the template in TEMPLATES
+pub trait ScriptTemplate: Sync {
+    // Type string reported for outputs of this template
+    fn type_name(&self) -> &'static str;
+
+    fn matches(&self, script: &[u8]) -> bool;
+
+    // Address(es) paid by a matching script, None if they cannot be decoded
+    fn extract(&self, script: &CScript) -> Option<AddressType>;
+}
+
+struct PubKeyHash;
+
+impl ScriptTemplate for PubKeyHash {
+    fn type_name(&self) -> &'static str {
+        "pubkeyhash"
+    }
+
+    fn matches(&self, script: &[u8]) -> bool {
+        matches!(script, [OP_DUP, OP_HASH160, 0x14, .., OP_EQUALVERIFY, OP_CHECKSIG] if script.len() == 25)
+    }
+
+    fn extract(&self, script: &CScript) -> Option<AddressType> {
+        scriptpubkey_to_p2pkh_address(script).map(AddressType::P2PKH)
+    }
+}
+
+struct ScriptHash;
+
+impl ScriptTemplate for ScriptHash {
+    fn type_name(&self) -> &'static str {
+        "scripthash"
+    }
+
+    fn matches(&self, script: &[u8]) -> bool {
+        matches!(script, [OP_HASH160, 0x14, .., OP_EQUAL] if script.len() == 23)
+    }
+
+    fn extract(&self, script: &CScript) -> Option<AddressType> {
+        scriptpubkey_to_p2sh_address(script).map(AddressType::P2SH)
+    }
+}
+
+// Zerocoin scripts are identified by their leading opcode and carry no address
+struct Zerocoin {
+    opcode: u8,
+    type_name: &'static str,
+    address_type: fn() -> AddressType,
+}
+
+impl ScriptTemplate for Zerocoin {
+    fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    fn matches(&self, script: &[u8]) -> bool {
+        script.first() == Some(&self.opcode)
+    }
+
+    fn extract(&self, _script: &CScript) -> Option<AddressType> {
+        Some((self.address_type)())
+    }
+}
+
+struct PubKey;
+
+impl ScriptTemplate for PubKey {
+    fn type_name(&self) -> &'static str {
+        "pubkey"
+    }
+
+    fn matches(&self, script: &[u8]) -> bool {
+        script.len() > 1
+            && script.last() == Some(&OP_CHECKSIG)
+            && !script.contains(&OP_DUP)
+            && !script.contains(&OP_CHECKCOLDSTAKEVERIFY)
+            && !script.contains(&OP_CHECKCOLDSTAKEVERIFY_LOF)
+    }
+
+    fn extract(&self, script: &CScript) -> Option<AddressType> {
+        scriptpubkey_to_p2pk(script).map(AddressType::P2PK)
+    }
+}
+
+struct ColdStake;
+
+impl ScriptTemplate for ColdStake {
+    fn type_name(&self) -> &'static str {
+        "coldstake"
+    }
+
+    fn matches(&self, script: &[u8]) -> bool {
+        script.contains(&OP_CHECKCOLDSTAKEVERIFY) || script.contains(&OP_CHECKCOLDSTAKEVERIFY_LOF)
+    }
+
+    fn extract(&self, script: &CScript) -> Option<AddressType> {
+        scriptpubkey_to_staking_address(script).map(|(staker, owner)| AddressType::Staking(staker, owner))
+    }
+}
+
+// Checked in order, the first matching template wins
+static TEMPLATES: &[&dyn ScriptTemplate] = &[
+    &PubKeyHash,
+    &ScriptHash,
+    &Zerocoin { opcode: OP_ZEROCOINMINT, type_name: "zerocoinmint", address_type: || AddressType::ZerocoinMint },
+    &Zerocoin { opcode: OP_ZEROCOINSPEND, type_name: "zerocoinspend", address_type: || AddressType::ZerocoinSpend },
+    &Zerocoin { opcode: OP_ZEROCOINPUBLICSPEND, type_name: "zerocoinpublicspend", address_type: || AddressType::ZerocoinPublicSpend },
+    &PubKey,
+    &ColdStake,
+];
+
+pub fn classify(script: &[u8]) -> Option<&'static dyn ScriptTemplate> {
+    TEMPLATES.iter().copied().find(|template| template.matches(script))
+}
+
+// Key hash paid by a pay-to-pubkey-hash script
+pub fn pubkey_hash(script: &[u8]) -> Option<&[u8]> {
+    PubKeyHash.matches(script).then(|| &script[3..23])
+}
+
+// Script hash paid by a pay-to-script-hash script
+pub fn script_hash(script: &[u8]) -> Option<&[u8]> {
+    ScriptHash.matches(script).then(|| &script[2..22])
+}
+
+// Public key, compressed or not, paid by a pay-to-pubkey script
+pub fn pubkey(script: &[u8]) -> Option<&[u8]> {
+    if script.last() != Some(&OP_CHECKSIG) || script.contains(&OP_DUP) {
+        return None;
+    }
+    match script.len() {
+        67 => Some(&script[1..66]), // skip the push opcode, then take uncompressed pubkey
+        35 => Some(&script[1..34]), // skip the push opcode, then take compressed pubkey
+        _ => None,
+    }
+}
+
+// Staker and owner key hashes of a cold staking script, laid out as
+// OP_DUP OP_HASH160 OP_ROT OP_IF OP_CHECKCOLDSTAKEVERIFY(_LOF) <staker hash>
+// OP_ELSE <owner hash> OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG
+pub fn cold_stake_hashes(script: &[u8]) -> Option<(&[u8], &[u8])> {
+    match script {
+        [OP_DUP, OP_HASH160, OP_ROT, OP_IF, OP_CHECKCOLDSTAKEVERIFY | OP_CHECKCOLDSTAKEVERIFY_LOF, 0x14, ..]
+            if script.len() == 51
+                && script[26] == OP_ELSE
+                && script[27] == 0x14
+                && script[48..] == [OP_ENDIF, OP_EQUALVERIFY, OP_CHECKSIG] =>
+        {
+            Some((&script[6..26], &script[28..48]))
+        }
+        _ => None,
+    }
+}
+
+// Scripts longer than this always fail to execute
+const MAX_SCRIPT_SIZE: usize = 10000;
+
+// Whether an output script can never be satisfied: it starts with OP_RETURN,
+// is over the size limit, or has a push running past its end. Anything that
+// needs real evaluation to decide is treated as spendable.
+pub fn is_unspendable(script: &[u8]) -> bool {
+    if script.is_empty() {
+        return false;
+    }
+    script[0] == OP_RETURN || script.len() > MAX_SCRIPT_SIZE || !pushes_fit(script)
+}
+
+// Data pushed after a leading OP_RETURN, concatenated; None for other scripts
+pub fn op_return_data(script: &[u8]) -> Option<Vec<u8>> {
+    if script.first() != Some(&OP_RETURN) {
+        return None;
+    }
+    let mut data = Vec::new();
+    let mut pos = 1;
+    while pos < script.len() {
+        let opcode = script[pos];
+        pos += 1;
+        let (len_bytes, len) = match opcode {
+            0x01..=0x4b => (0, opcode as usize),
+            OP_PUSHDATA1 => (1, *script.get(pos)? as usize),
+            OP_PUSHDATA2 => (2, script.get(pos..pos + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)?),
+            OP_PUSHDATA4 => (4, script.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)?),
+            _ => continue,
+        };
+        let start = pos + len_bytes;
+        data.extend_from_slice(script.get(start..start.checked_add(len)?)?);
+        pos = start + len;
+    }
+    Some(data)
+}
+
+fn pushes_fit(script: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < script.len() {
+        let opcode = script[pos];
+        pos += 1;
+        let (len_bytes, len) = match opcode {
+            0x01..=0x4b => (0, opcode as usize),
+            OP_PUSHDATA1 => (1, script.get(pos).map_or(usize::MAX, |b| *b as usize)),
+            OP_PUSHDATA2 => (2, script.get(pos..pos + 2).map_or(usize::MAX, |b| u16::from_le_bytes([b[0], b[1]]) as usize)),
+            OP_PUSHDATA4 => (4, script.get(pos..pos + 4).map_or(usize::MAX, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)),
+            _ => continue,
+        };
+        if len == usize::MAX {
+            return false;
+        }
+        pos += len_bytes + len;
+        if pos > script.len() {
+            return false;
+        }
+    }
+    true
+}
+
+// Type string for an output script, "nonstandard" when no template matches
+pub fn script_type(script: &[u8]) -> &'static str {
+    classify(script).map_or("nonstandard", |template| template.type_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params;
+    use crate::parser::address::{address_type_to_string, compute_address_hash, hash_address};
+
+    const STAKER: [u8; 20] = [0x51; 20];
+    const OWNER: [u8; 20] = [0x0a; 20];
+
+    fn p2pkh(hash: &[u8; 20]) -> Vec<u8> {
+        [&[OP_DUP, OP_HASH160, 0x14][..], hash, &[OP_EQUALVERIFY, OP_CHECKSIG]].concat()
+    }
+
+    fn p2sh(hash: &[u8; 20]) -> Vec<u8> {
+        [&[OP_HASH160, 0x14][..], hash, &[OP_EQUAL]].concat()
+    }
+
+    fn p2pk(pubkey: &[u8]) -> Vec<u8> {
+        [&[pubkey.len() as u8][..], pubkey, &[OP_CHECKSIG]].concat()
+    }
+
+    fn cold_stake(opcode: u8) -> Vec<u8> {
+        [&[OP_DUP, OP_HASH160, OP_ROT, OP_IF, opcode, 0x14][..], &STAKER, &[OP_ELSE, 0x14], &OWNER,
+         &[OP_ENDIF, OP_EQUALVERIFY, OP_CHECKSIG]].concat()
+    }
+
+    fn compressed_pubkey() -> Vec<u8> {
+        [&[0x02][..], &[0x79; 32]].concat()
+    }
+
+    fn uncompressed_pubkey() -> Vec<u8> {
+        [&[0x04][..], &[0x79; 32], &[0x48; 31], &[0x17]].concat()
+    }
+
+    #[test]
+    fn script_types() {
+        let mut short_cold_stake = cold_stake(OP_CHECKCOLDSTAKEVERIFY);
+        short_cold_stake.remove(30);
+        let cases: Vec<(&str, Vec<u8>, &str)> = vec![
+            ("p2pkh", p2pkh(&OWNER), "pubkeyhash"),
+            ("p2sh", p2sh(&OWNER), "scripthash"),
+            ("p2pk compressed", p2pk(&compressed_pubkey()), "pubkey"),
+            ("p2pk uncompressed", p2pk(&uncompressed_pubkey()), "pubkey"),
+            ("cold stake", cold_stake(OP_CHECKCOLDSTAKEVERIFY), "coldstake"),
+            ("cold stake lof", cold_stake(OP_CHECKCOLDSTAKEVERIFY_LOF), "coldstake"),
+            ("cold stake off layout", short_cold_stake, "coldstake"),
+            ("zerocoin mint", vec![OP_ZEROCOINMINT, 0x01, 0x00], "zerocoinmint"),
+            ("zerocoin spend", vec![OP_ZEROCOINSPEND], "zerocoinspend"),
+            ("zerocoin public spend", vec![OP_ZEROCOINPUBLICSPEND], "zerocoinpublicspend"),
+            ("op_return", vec![OP_RETURN, 0x02, 0xbe, 0xef], "nonstandard"),
+            ("p2pkh one byte short", p2pkh(&OWNER)[..24].to_vec(), "nonstandard"),
+            ("p2sh with a 21 byte push", [&[OP_HASH160, 0x15][..], &[0u8; 21], &[OP_EQUAL]].concat(), "nonstandard"),
+            ("op_true", vec![0x51], "nonstandard"),
+            ("empty", Vec::new(), "nonstandard"),
+        ];
+        for (name, script, expected) in &cases {
+            assert_eq!(script_type(script), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn extracted_hashes_and_keys() {
+        assert_eq!(pubkey_hash(&p2pkh(&OWNER)), Some(&OWNER[..]));
+        assert_eq!(pubkey_hash(&p2sh(&OWNER)), None);
+        assert_eq!(script_hash(&p2sh(&OWNER)), Some(&OWNER[..]));
+        assert_eq!(script_hash(&p2pkh(&OWNER)), None);
+        assert_eq!(pubkey(&p2pk(&compressed_pubkey())), Some(&compressed_pubkey()[..]));
+        assert_eq!(pubkey(&p2pk(&uncompressed_pubkey())), Some(&uncompressed_pubkey()[..]));
+        assert_eq!(pubkey(&p2pk(&[0x02; 20])), None);
+    }
+
+    #[test]
+    fn cold_stake_layout_is_strict() {
+        for opcode in [OP_CHECKCOLDSTAKEVERIFY, OP_CHECKCOLDSTAKEVERIFY_LOF] {
+            let script = cold_stake(opcode);
+            assert_eq!(script.len(), 51);
+            assert_eq!(cold_stake_hashes(&script), Some((&STAKER[..], &OWNER[..])));
+        }
+
+        let valid = cold_stake(OP_CHECKCOLDSTAKEVERIFY);
+        let mut missing_else = valid.clone();
+        missing_else[26] = OP_IF;
+        let mut short_owner_push = valid.clone();
+        short_owner_push[27] = 0x13;
+        let mut wrong_tail = valid.clone();
+        wrong_tail[49] = OP_EQUAL;
+        let mut trailing = valid.clone();
+        trailing.push(OP_CHECKSIG);
+        let not_cold_stake = [&[OP_DUP, OP_HASH160, OP_ROT, OP_IF, OP_CHECKSIG, 0x14][..], &valid[6..]].concat();
+        for script in [missing_else, short_owner_push, wrong_tail, trailing, not_cold_stake, valid[..50].to_vec()] {
+            assert_eq!(cold_stake_hashes(&script), None, "{}", hex::encode(&script));
+        }
+    }
+
+    #[test]
+    fn templates_extract_addresses() {
+        let params = chain_params::params();
+        let extract = |script: Vec<u8>| {
+            let template = classify(&script).unwrap();
+            address_type_to_string(template.extract(&CScript { script }))
+        };
+        let owner = hash_address(&OWNER, params.pubkey_address_prefix);
+        let staker = hash_address(&STAKER, params.staking_address_prefix);
+
+        assert_eq!(extract(p2pkh(&OWNER)), vec![owner.clone()]);
+        assert_eq!(extract(p2sh(&OWNER)), vec![hash_address(&OWNER, params.script_address_prefix)]);
+        assert_eq!(extract(cold_stake(OP_CHECKCOLDSTAKEVERIFY)), vec![format!("Staking({}, {})", staker, owner)]);
+        // P2PK outputs are reported under the P2PKH address of the compressed key
+        let compressed = compute_address_hash(&compressed_pubkey());
+        let p2pk_address = vec![hash_address(&compressed, params.pubkey_address_prefix)];
+        assert_eq!(extract(p2pk(&compressed_pubkey())), p2pk_address);
+        // The uncompressed key has an odd y, so it compresses to 0x03 + x
+        let odd = [&[0x03][..], &[0x79; 32]].concat();
+        let odd_address = vec![hash_address(&compute_address_hash(&odd), params.pubkey_address_prefix)];
+        assert_eq!(extract(p2pk(&uncompressed_pubkey())), odd_address);
+        assert_eq!(extract(vec![OP_ZEROCOINMINT]), vec!["ZerocoinMint".to_string()]);
+    }
+
+    #[test]
+    fn unspendable_scripts() {
+        let cases: Vec<(&str, Vec<u8>, bool)> = vec![
+            ("empty", Vec::new(), false),
+            ("p2pkh", p2pkh(&OWNER), false),
+            ("op_true", vec![0x51], false),
+            ("op_return", vec![OP_RETURN], true),
+            ("op_return with data", vec![OP_RETURN, 0x01, 0xff], true),
+            ("push past the end", vec![0x05, 0x01, 0x02], true),
+            ("pushdata1 without its length", vec![OP_PUSHDATA1], true),
+            ("pushdata2 past the end", vec![OP_PUSHDATA2, 0x10, 0x00, 0x01], true),
+            ("pushdata4 exactly to the end", vec![OP_PUSHDATA4, 0x02, 0x00, 0x00, 0x00, 0xaa, 0xbb], false),
+            ("over the size limit", vec![0x61; MAX_SCRIPT_SIZE + 1], true),
+            ("at the size limit", vec![0x61; MAX_SCRIPT_SIZE], false),
+        ];
+        for (name, script, expected) in &cases {
+            assert_eq!(is_unspendable(script), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn op_return_payloads() {
+        assert_eq!(op_return_data(&p2pkh(&OWNER)), None);
+        assert_eq!(op_return_data(&[OP_RETURN]), Some(Vec::new()));
+        assert_eq!(op_return_data(&[OP_RETURN, 0x02, 0xbe, 0xef]), Some(vec![0xbe, 0xef]));
+        // Pushes of every size are concatenated and other opcodes skipped
+        let script = [&[OP_RETURN, 0x01, 0x61, 0x51, OP_PUSHDATA1, 0x02, 0x62, 0x63, OP_PUSHDATA2, 0x01, 0x00, 0x64][..],
+                      &[OP_PUSHDATA4, 0x01, 0x00, 0x00, 0x00, 0x65]].concat();
+        assert_eq!(op_return_data(&script), Some(b"abcde".to_vec()));
+        // A push running past the end
+        assert_eq!(op_return_data(&[OP_RETURN, 0x03, 0x61]), None);
+        assert_eq!(op_return_data(&[OP_RETURN, OP_PUSHDATA2, 0x01]), None);
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+static REQUESTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// Where a sync stopped on shutdown, in chain_state "shutdown_checkpoint"
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    // blk file path or peer address
+    pub source: String,
+    // Bytes of the blk file indexed, 0 for peers
+    pub offset: u64,
+    pub height: i32,
+    pub time: u64,
+}
+
+// Turn SIGINT and SIGTERM into a shutdown request that long running commands
+// check between blocks. A second signal exits at once.
+pub fn install() -> io::Result<()> {
+    let flag = REQUESTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first so it only sees the flag set by an earlier signal
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(flag))?;
+        signal_hook::flag::register(signal, Arc::clone(flag))?;
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.get().is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+// Record where the sync stopped and sync the WAL, so nothing committed is
+// lost when the process exits right after
+pub fn write_checkpoint(db: &DB, source: &str, offset: u64, height: i32) -> io::Result<()> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let checkpoint = Checkpoint {
+        source: source.to_string(),
+        offset,
+        height,
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+    };
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    db.put_cf(cf_state, b"shutdown_checkpoint", serde_json::to_vec(&checkpoint)?).map_err(to_io)?;
+    db.flush_wal(true).map_err(to_io)?;
+    println!("Stopped at height {} in {} ({} bytes), checkpoint saved", height, checkpoint.source, offset);
+    Ok(())
+}
+
+pub fn last_checkpoint(db: &DB) -> io::Result<Option<Checkpoint>> {
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+    let value = db.get_cf(cf_state, b"shutdown_checkpoint").map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(value.and_then(|value| serde_json::from_slice(&value).ok()))
+}
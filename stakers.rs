@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::block_commit::BlockCommit;
+use crate::parser::address::scriptpubkey_to_address;
+use crate::parser::types::AddressType;
+use crate::IndexedTx;
+
+const DAY: u32 = 86400;
+
+// Blocks produced and rewards earned by one staker, all time or on one day
+#[derive(Default, Serialize, Deserialize)]
+struct StakerTotals {
+    blocks: u32,
+    rewards: i64,
+    first_height: i32,
+    last_height: i32,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StakerRow {
+    pub address: String,
+    pub blocks: u32,
+    pub rewards: i64,
+    // Percentage of the PoS blocks in the period
+    pub share: f64,
+}
+
+// Address credited with a coinstake: the staker of a cold staking output,
+// otherwise the owner of the first paying output
+pub fn staker_address(coinstake: &IndexedTx) -> Option<String> {
+    let output = coinstake.outputs.iter().find(|output| !output.script_pubkey.script.is_empty())?;
+    match scriptpubkey_to_address(&output.script_pubkey)? {
+        AddressType::Staking(staker, _) => Some(staker),
+        AddressType::P2PKH(address) | AddressType::P2SH(address) | AddressType::P2PK(address) => Some(address),
+        _ => None,
+    }
+}
+
+fn totals_key(prefix: &[u8], address: &str) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.push(address.len() as u8);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+// Address in an 'S' or 'D' key, None when the key's length doesn't match
+// the address length it records
+fn totals_address(key: &[u8]) -> Option<&[u8]> {
+    let length_at = match key.first() {
+        Some(b'S') => 1,
+        Some(b'D') => 5,
+        _ => return None,
+    };
+    let length = *key.get(length_at)? as usize;
+    key.get(length_at + 1..).filter(|address| address.len() == length)
+}
+
+fn add_block(commit: &mut BlockCommit, key: &[u8], height: i32, reward: i64) -> io::Result<()> {
+    let mut totals: StakerTotals = match commit.get_cf("stakers", key)? {
+        Some(data) => bincode::deserialize(&data).unwrap_or_default(),
+        None => StakerTotals { first_height: height, ..StakerTotals::default() },
+    };
+    totals.blocks += 1;
+    totals.rewards += reward;
+    totals.first_height = totals.first_height.min(height);
+    totals.last_height = totals.last_height.max(height);
+    let data = bincode::serialize(&totals)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    commit.put_cf("stakers", key, &data);
+    Ok(())
+}
+
+// Credit a PoS block to its staker:
+// 'y' + block_height -> staker address, in blocks
+// 'S' + address length + address -> all time StakerTotals, in stakers
+// 'D' + day (big endian) + address length + address -> StakerTotals for that UTC day, in stakers
+pub fn record_block(commit: &mut BlockCommit, height: i32, block_time: u32, coinstake: &IndexedTx) -> io::Result<()> {
+    let address = match staker_address(coinstake) {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let reward = coinstake.value_out() - coinstake.value_in;
+
+    let mut key = vec![b'y'];
+    key.extend_from_slice(&height.to_le_bytes());
+    commit.put_cf("blocks", &key, address.as_bytes());
+
+    add_block(commit, &totals_key(b"S", &address), height, reward)?;
+    let mut day_prefix = vec![b'D'];
+    day_prefix.extend_from_slice(&(block_time / DAY).to_be_bytes());
+    add_block(commit, &totals_key(&day_prefix, &address), height, reward)
+}
+
+// Staker of the PoS block at `height`
+pub fn staked_by(db: &DB, height: i32) -> io::Result<Option<String>> {
+    let cf_blocks = db.cf_handle("blocks").expect("Blocks column family not found");
+    let mut key = vec![b'y'];
+    key.extend_from_slice(&height.to_le_bytes());
+    let value = db.get_cf(cf_blocks, &key).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(value.map(|address| String::from_utf8_lossy(&address).into_owned()))
+}
+
+// Stakers with the most blocks over the last `days` UTC days (including
+// today), or all time when None
+pub fn top(db: &DB, days: Option<u32>, limit: usize) -> io::Result<Vec<StakerRow>> {
+    let cf_stakers = db.cf_handle("stakers").expect("Stakers column family not found");
+    let (prefix, start) = match days {
+        Some(days) => {
+            let today = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32 / DAY;
+            let mut start = vec![b'D'];
+            start.extend_from_slice(&today.saturating_sub(days.saturating_sub(1)).to_be_bytes());
+            (vec![b'D'], start)
+        }
+        None => (vec![b'S'], vec![b'S']),
+    };
+
+    let mut by_staker: HashMap<String, (u32, i64)> = HashMap::new();
+    for item in db.iterator_cf(cf_stakers, IteratorMode::From(&start, Direction::Forward)) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        let address = match totals_address(&key) {
+            Some(address) => String::from_utf8_lossy(address).into_owned(),
+            None => continue,
+        };
+        if let Ok(totals) = bincode::deserialize::<StakerTotals>(&value) {
+            let entry = by_staker.entry(address).or_default();
+            entry.0 += totals.blocks;
+            entry.1 += totals.rewards;
+        }
+    }
+
+    let total_blocks: u32 = by_staker.values().map(|(blocks, _)| blocks).sum();
+    let mut rows: Vec<StakerRow> = by_staker.into_iter()
+        .map(|(address, (blocks, rewards))| StakerRow {
+            address,
+            blocks,
+            rewards,
+            share: blocks as f64 * 100.0 / total_blocks.max(1) as f64,
+        })
+        .collect();
+    rows.sort_by(|a, b| b.blocks.cmp(&a.blocks).then_with(|| a.address.cmp(&b.address)));
+    rows.truncate(limit);
+    Ok(rows)
+}
+
+// Move staker totals out of stats, where databases indexed before the stakers
+// column family kept them next to the block stats. Block stats keys are 4 byte
+// heights, some of which start with 'S' or 'D', so only keys that parse as
+// totals keys are moved.
+pub fn migrate_from_stats(db: &DB) -> io::Result<usize> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_stats = db.cf_handle("stats").expect("Stats column family not found");
+    let cf_stakers = db.cf_handle("stakers").expect("Stakers column family not found");
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+
+    let mut batch = WriteBatch::default();
+    let mut moved = 0;
+    for prefix in [b"S", b"D"] {
+        for item in db.iterator_cf(cf_stats, IteratorMode::From(prefix, Direction::Forward)) {
+            let (key, value) = item.map_err(to_io)?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if key.len() == 4 || totals_address(&key).is_none() {
+                continue;
+            }
+            batch.put_cf(cf_stakers, &key, &value);
+            batch.delete_cf(cf_stats, &key);
+            moved += 1;
+        }
+    }
+    batch.put_cf(cf_state, b"stakers_moved", [1u8]);
+    db.write(batch).map_err(to_io)?;
+    Ok(moved)
+}
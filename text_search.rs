@@ -0,0 +1,82 @@
+use std::io;
+
+use rocksdb::{Direction, IteratorMode, DB};
+
+use crate::block_commit::BlockCommit;
+
+// Tokens shorter than this are too common to be useful, longer ones are
+// usually encoded data rather than words
+const MIN_TOKEN_LEN: usize = 3;
+const MAX_TOKEN_LEN: usize = 32;
+// Distinct tokens indexed per transaction
+const MAX_TOKENS_PER_TX: usize = 16;
+// Transactions read for the first query token before giving up
+const MAX_CANDIDATES: usize = 10_000;
+
+// Lowercased runs of ASCII letters and digits within the printable text of `data`
+pub fn tokens(data: &[u8]) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in data.split(|b| !b.is_ascii_alphanumeric()) {
+        if word.len() < MIN_TOKEN_LEN || word.len() > MAX_TOKEN_LEN {
+            continue;
+        }
+        let token = String::from_utf8_lossy(word).to_ascii_lowercase();
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+fn token_prefix(token: &str) -> Vec<u8> {
+    let mut key = vec![b'w', token.len() as u8];
+    key.extend_from_slice(token.as_bytes());
+    key
+}
+
+// 'w' + token length + token + txid -> empty, in the transactions column family
+pub fn index(commit: &mut BlockCommit, txid: &[u8], data: &[u8]) {
+    for token in tokens(data).iter().take(MAX_TOKENS_PER_TX) {
+        let mut key = token_prefix(token);
+        key.extend_from_slice(txid);
+        commit.put_cf("transactions", &key, &[]);
+    }
+}
+
+// Txids (hex) whose indexed text contains every token of `query`, at most `limit`
+pub fn search(db: &DB, query: &str, limit: usize) -> io::Result<Vec<String>> {
+    let cf_transactions = db.cf_handle("transactions").expect("Transactions column family not found");
+    let query_tokens = tokens(query.as_bytes());
+    let (first, rest) = match query_tokens.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+
+    let prefix = token_prefix(first);
+    let mut txids = Vec::new();
+    let iter = db.iterator_cf(cf_transactions, IteratorMode::From(&prefix, Direction::Forward));
+    for item in iter.take(MAX_CANDIDATES) {
+        let (key, _) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        let txid = &key[prefix.len()..];
+
+        let mut matches_all = true;
+        for token in rest {
+            let mut key = token_prefix(token);
+            key.extend_from_slice(txid);
+            if db.get_cf(cf_transactions, &key).map_err(|e| io::Error::other(e.to_string()))?.is_none() {
+                matches_all = false;
+                break;
+            }
+        }
+        if matches_all {
+            txids.push(hex::encode(txid));
+            if txids.len() >= limit {
+                break;
+            }
+        }
+    }
+    Ok(txids)
+}
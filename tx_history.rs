@@ -0,0 +1,269 @@
+use std::io;
+
+use rocksdb::{Direction, IteratorMode, DB};
+use serde::Serialize;
+
+use crate::block_commit::BlockCommit;
+use crate::db::codec;
+use crate::{deserialize_utxo_entry, deserialize_utxos, utxo_key};
+
+// One transaction in an address's history
+#[derive(Serialize, Debug)]
+pub struct HistoryTx {
+    pub txid: String,
+    pub height: i32,
+}
+
+// One page of an address's history, newest first
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage {
+    pub page: usize,
+    pub page_size: usize,
+    pub transactions: Vec<HistoryTx>,
+}
+
+// 'h' + address length + address, shared by all of an address's entries
+fn address_prefix(address: &str) -> Vec<u8> {
+    let mut key = vec![b'h', address.len() as u8];
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+fn history_key(address: &str, height: i32, tx_index: u32) -> Vec<u8> {
+    let mut key = address_prefix(address);
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(&tx_index.to_be_bytes());
+    key
+}
+
+// 'h' + address length + address + height + tx index (both big endian, so an
+// address's transactions sort in chain order) -> txid, in addr_index
+pub fn record(commit: &mut BlockCommit, address: &str, height: i32, tx_index: u32, txid: &[u8]) {
+    commit.put_cf("addr_index", &history_key(address, height, tx_index), txid);
+}
+
+// Every txid recorded for `address`, oldest first
+pub fn txids(db: &DB, address: &str) -> io::Result<Vec<Vec<u8>>> {
+    let cf_addr = db.cf_handle("addr_index").expect("Address_index column family not found");
+    let prefix = address_prefix(address);
+    let mut txids = Vec::new();
+    for item in db.iterator_cf(cf_addr, IteratorMode::From(&prefix, Direction::Forward)) {
+        let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 8 {
+            break;
+        }
+        txids.push(codec::decode(value.to_vec()));
+    }
+    Ok(txids)
+}
+
+// Page `page` (from 1) of the transactions touching `address` between two
+// heights (inclusive), newest first. Only the requested page is read.
+pub fn page(db: &DB, address: &str, from: i32, to: i32, page: usize, page_size: usize) -> io::Result<HistoryPage> {
+    let cf_addr = db.cf_handle("addr_index").expect("Address_index column family not found");
+    let start = history_key(address, to, u32::MAX);
+    let entries = db.iterator_cf(cf_addr, IteratorMode::From(&start, Direction::Reverse))
+        .map(|item| item.map_err(|e| io::Error::other(e.to_string())));
+    let transactions = page_entries(entries, address, from, page.saturating_sub(1) * page_size, page_size)?;
+    Ok(HistoryPage { page, page_size, transactions })
+}
+
+// Take a page from history entries read backwards from the end of the height
+// range. Entries of other addresses or before `from` end the history before
+// any are skipped, so the offset only counts the address's own entries.
+fn page_entries<I>(entries: I, address: &str, from: i32, skip: usize, page_size: usize) -> io::Result<Vec<HistoryTx>>
+where
+    I: Iterator<Item = io::Result<(Box<[u8]>, Box<[u8]>)>>,
+{
+    let prefix = address_prefix(address);
+    let mut transactions = Vec::new();
+    let mut skipped = 0;
+    for item in entries {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 8 {
+            break;
+        }
+        let height = i32::from_be_bytes(key[prefix.len()..prefix.len() + 4].try_into().unwrap());
+        if height < from || transactions.len() >= page_size {
+            break;
+        }
+        if skipped < skip {
+            skipped += 1;
+            continue;
+        }
+        transactions.push(HistoryTx { txid: hex::encode(codec::decode(value.to_vec())), height });
+    }
+    Ok(transactions)
+}
+
+// Spent outputs read for one utxo-at query before giving up; bounds how far
+// back a reconstruction can go for busy addresses
+const MAX_SPENT_SCANNED: usize = 100_000;
+
+// An output of an address that was unspent at some height
+#[derive(Serialize, Debug)]
+pub struct UtxoAt {
+    pub txid: String,
+    pub vout: u32,
+    pub value: i64,
+    pub height: i32,
+}
+
+fn spent_prefix(address: &str) -> Vec<u8> {
+    let mut key = vec![b'o', address.len() as u8];
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+// 'o' + address length + address + spend height (big endian) + txid + vout
+// -> value (i64 LE) + creation height (i32 LE), in addr_index. Keeps what the
+// address owned before each spend so older UTXO sets can be rebuilt.
+pub fn record_spent(commit: &mut BlockCommit, address: &str, spend_height: i32, txid: &[u8], vout: u32, value: i64, height: i32) {
+    let mut key = spent_prefix(address);
+    key.extend_from_slice(&spend_height.to_be_bytes());
+    key.extend_from_slice(txid);
+    key.extend_from_slice(&vout.to_be_bytes());
+    let mut entry = value.to_le_bytes().to_vec();
+    entry.extend_from_slice(&height.to_le_bytes());
+    commit.put_cf("addr_index", &key, &entry);
+}
+
+// The address's UTXOs as of the end of block `height`: outputs created by
+// then that are unspent now or were spent in a later block
+pub fn utxos_at(db: &DB, address: &str, height: i32) -> io::Result<Vec<UtxoAt>> {
+    let to_io = |e: rocksdb::Error| io::Error::other(e.to_string());
+    let cf_addr = db.cf_handle("addr_index").expect("Address_index column family not found");
+    let cf_utxo = db.cf_handle("utxo").expect("UTXO column family not found");
+    let cf_state = db.cf_handle("chain_state").expect("Chain state column family not found");
+
+    let recorded_since = db.get_cf(cf_state, b"spent_outputs_since").map_err(to_io)?
+        .map(|value| i32::from_le_bytes(value[..4].try_into().unwrap()));
+    match recorded_since {
+        Some(since) if height >= since => {}
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Spent outputs are only recorded from height {}", recorded_since.map_or("(none yet)".to_string(), |h| h.to_string())))),
+    }
+
+    let snapshot = db.snapshot();
+    let mut utxos = Vec::new();
+
+    let mut key_address = vec![b'a'];
+    key_address.extend_from_slice(address.as_bytes());
+    let unspent = snapshot.get_cf(cf_addr, &key_address).map_err(to_io)?
+        .map(codec::decode)
+        .as_deref()
+        .map_or(Vec::new(), deserialize_utxos);
+    for (txid, vout) in unspent {
+        let entry = match snapshot.get_cf(cf_utxo, utxo_key(&txid, vout as u32)).map_err(to_io)?.as_deref().and_then(deserialize_utxo_entry) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if entry.height <= height {
+            utxos.push(UtxoAt { txid: hex::encode(&txid), vout: vout as u32, value: entry.value, height: entry.height });
+        }
+    }
+
+    let prefix = spent_prefix(address);
+    let mut start = prefix.clone();
+    start.extend_from_slice(&height.saturating_add(1).to_be_bytes());
+    for (scanned, item) in snapshot.iterator_cf(cf_addr, IteratorMode::From(&start, Direction::Forward)).enumerate() {
+        let (key, value) = item.map_err(to_io)?;
+        if !key.starts_with(&prefix) || key.len() != prefix.len() + 4 + 32 + 4 || value.len() != 12 {
+            break;
+        }
+        if scanned >= MAX_SPENT_SCANNED {
+            return Err(io::Error::other(format!(
+                "More than {} outputs spent since height {}, pick a more recent height", MAX_SPENT_SCANNED, height)));
+        }
+        let created = i32::from_le_bytes(value[8..12].try_into().unwrap());
+        if created > height {
+            continue;
+        }
+        let txid = &key[prefix.len() + 4..prefix.len() + 36];
+        utxos.push(UtxoAt {
+            txid: hex::encode(txid),
+            vout: u32::from_be_bytes(key[prefix.len() + 36..].try_into().unwrap()),
+            value: i64::from_le_bytes(value[..8].try_into().unwrap()),
+            height: created,
+        });
+    }
+
+    utxos.sort_by_key(|utxo| (utxo.height, utxo.txid.clone(), utxo.vout));
+    Ok(utxos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const ADDRESS: &str = "DAddrB";
+    // Sorts right before ADDRESS, so a backwards read runs into its entries
+    const NEIGHBOUR: &str = "DAddrA";
+
+    fn txid(height: i32, tx_index: u32) -> Vec<u8> {
+        let mut txid = height.to_be_bytes().to_vec();
+        txid.extend_from_slice(&tx_index.to_be_bytes());
+        txid
+    }
+
+    fn addr_index() -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut entries = BTreeMap::new();
+        for (height, tx_index) in [(100, 0), (100, 1), (200, 3), (300, 0), (400, 2)] {
+            entries.insert(history_key(ADDRESS, height, tx_index), txid(height, tx_index));
+        }
+        for (height, tx_index) in [(50, 0), (150, 1), (250, 0)] {
+            entries.insert(history_key(NEIGHBOUR, height, tx_index), txid(height, tx_index));
+        }
+        // Other kinds of addr_index entries share the column family
+        entries.insert(b"aDAddrB".to_vec(), vec![0]);
+        entries
+    }
+
+    // What page() reads, over an in-memory addr_index
+    fn read(address: &str, from: i32, to: i32, page: usize, page_size: usize) -> Vec<(i32, String)> {
+        let entries = addr_index();
+        let start = history_key(address, to, u32::MAX);
+        let iter = entries.range(..=start).rev()
+            .map(|(key, value)| Ok((key.clone().into_boxed_slice(), value.clone().into_boxed_slice())));
+        page_entries(iter, address, from, page.saturating_sub(1) * page_size, page_size).unwrap()
+            .into_iter()
+            .map(|tx| (tx.height, tx.txid))
+            .collect()
+    }
+
+    fn expected(txs: &[(i32, u32)]) -> Vec<(i32, String)> {
+        txs.iter().map(|(height, tx_index)| (*height, hex::encode(txid(*height, *tx_index)))).collect()
+    }
+
+    #[test]
+    fn pages_newest_first() {
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 1, 2), expected(&[(400, 2), (300, 0)]));
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 2, 2), expected(&[(200, 3), (100, 1)]));
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 3, 2), expected(&[(100, 0)]));
+        // Past the last page, not the neighbour's entries
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 4, 2), expected(&[]));
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 1, 10).len(), 5);
+        // Page 0 reads like page 1
+        assert_eq!(read(ADDRESS, 0, i32::MAX, 0, 2), read(ADDRESS, 0, i32::MAX, 1, 2));
+    }
+
+    #[test]
+    fn height_range_is_inclusive() {
+        assert_eq!(read(ADDRESS, 200, 300, 1, 10), expected(&[(300, 0), (200, 3)]));
+        assert_eq!(read(ADDRESS, 150, 350, 2, 1), expected(&[(200, 3)]));
+        assert_eq!(read(ADDRESS, 150, 350, 3, 1), expected(&[]));
+        assert_eq!(read(ADDRESS, 100, 100, 1, 10), expected(&[(100, 1), (100, 0)]));
+        assert_eq!(read(ADDRESS, 0, 99, 1, 10), expected(&[]));
+    }
+
+    #[test]
+    fn neighbouring_addresses_stay_apart() {
+        assert_eq!(read(NEIGHBOUR, 0, i32::MAX, 1, 10), expected(&[(250, 0), (150, 1), (50, 0)]));
+        assert_eq!(read(NEIGHBOUR, 0, i32::MAX, 2, 2), expected(&[(50, 0)]));
+        // A longer address with the same start has its own length byte
+        assert_eq!(read("DAddrB1", 0, i32::MAX, 1, 10), expected(&[]));
+        assert_eq!(read("DAddr", 0, i32::MAX, 1, 10), expected(&[]));
+    }
+}
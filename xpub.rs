@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::io;
+
+use bitcoin::secp256k1::{PublicKey, Secp256k1, VerifyOnly};
+use rocksdb::DB;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+
+use crate::parser::address::{compute_address_hash, hash_address};
+use crate::parser::tx::sha256d;
+use crate::{address_summary, chain_params, tx_history};
+
+// Receive (external) and change (internal) chains below the account key
+const CHAINS: [u32; 2] = [0, 1];
+// Addresses derived per chain regardless of the gap limit
+const MAX_ADDRESSES_PER_CHAIN: u32 = 10_000;
+
+// A BIP32 extended public key: version, depth, parent fingerprint, child
+// number, chain code and compressed public key
+pub struct ExtendedPubKey {
+    chain_code: [u8; 32],
+    public_key: PublicKey,
+}
+
+impl ExtendedPubKey {
+    pub fn parse(xpub: &str) -> io::Result<ExtendedPubKey> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid xpub: {}", msg));
+        let data = bs58::decode(xpub).into_vec().map_err(|_| invalid("not base58"))?;
+        if data.len() != 82 {
+            return Err(invalid("wrong length"));
+        }
+        let (payload, checksum) = data.split_at(78);
+        if sha256d(payload)[..4] != *checksum {
+            return Err(invalid("bad checksum"));
+        }
+        if payload[..4] != chain_params::params().ext_public_key_prefix {
+            return Err(invalid("not an extended public key for this network"));
+        }
+        ExtendedPubKey::from_payload(payload).map_err(|_| invalid("bad public key"))
+    }
+
+    // The 78 byte serialization, whatever its version bytes
+    fn from_payload(payload: &[u8]) -> Result<ExtendedPubKey, bitcoin::secp256k1::Error> {
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&payload[13..45]);
+        let public_key = PublicKey::from_slice(&payload[45..78])?;
+        Ok(ExtendedPubKey { chain_code, public_key })
+    }
+
+    // Non-hardened child key `index` (CKDpub)
+    pub fn derive(&self, secp: &Secp256k1<VerifyOnly>, index: u32) -> io::Result<ExtendedPubKey> {
+        let mut data = self.public_key.serialize().to_vec();
+        data.extend_from_slice(&index.to_be_bytes());
+        let i = hmac_sha512(&self.chain_code, &data);
+
+        let mut public_key = self.public_key;
+        public_key.add_exp_assign(secp, &i[..32])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Child key {} is invalid", index)))?;
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&i[32..]);
+        Ok(ExtendedPubKey { chain_code, public_key })
+    }
+
+    // P2PKH address of the key
+    pub fn address(&self) -> String {
+        hash_address(&compute_address_hash(&self.public_key.serialize()), chain_params::params().pubkey_address_prefix)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    const BLOCK_SIZE: usize = 128;
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..key.len()].copy_from_slice(key); // Chain codes are shorter than a block
+
+    let mut inner = Sha512::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(data);
+    let mut outer = Sha512::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+
+    let mut mac = [0u8; 64];
+    mac.copy_from_slice(&outer.finalize());
+    mac
+}
+
+// One derived address that has been used, in Blockbook's `tokens` format
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct XpubToken {
+    #[serde(rename = "type")]
+    pub token_type: &'static str,
+    pub name: String,
+    // Relative to the xpub: chain/index
+    pub path: String,
+    pub transfers: usize,
+    pub decimals: u32,
+    pub balance: String,
+    pub total_received: String,
+    pub total_sent: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct XpubSummary {
+    pub address: String,
+    pub balance: String,
+    pub total_received: String,
+    pub total_sent: String,
+    pub utxo_count: u32,
+    pub txs: usize,
+    pub used_tokens: usize,
+    pub tokens: Vec<XpubToken>,
+}
+
+// Walk the receive and change chains of `xpub` until `gap_limit` consecutive
+// addresses have no history, and sum up the used ones
+pub fn discover(db: &DB, xpub: &str, gap_limit: u32, dust_relay_fee: i64) -> io::Result<XpubSummary> {
+    let account = ExtendedPubKey::parse(xpub)?;
+    let secp = Secp256k1::verification_only();
+
+    let (mut balance, mut received, mut sent, mut utxo_count) = (0, 0, 0, 0);
+    let mut txids = HashSet::new();
+    let mut tokens = Vec::new();
+    for chain in CHAINS {
+        let chain_key = account.derive(&secp, chain)?;
+        let mut unused = 0;
+        for index in 0..MAX_ADDRESSES_PER_CHAIN {
+            if unused >= gap_limit {
+                break;
+            }
+            let address = match chain_key.derive(&secp, index) {
+                Ok(key) => key.address(),
+                Err(_) => continue,
+            };
+            let history = tx_history::txids(db, &address)?;
+            if history.is_empty() {
+                unused += 1;
+                continue;
+            }
+            unused = 0;
+
+            let summary = address_summary(db, &address, dust_relay_fee)?;
+            balance += summary.balance;
+            received += summary.received;
+            sent += summary.sent;
+            utxo_count += summary.utxo_count;
+            tokens.push(XpubToken {
+                token_type: "XPUBAddress",
+                name: address,
+                path: format!("{}/{}", chain, index),
+                transfers: history.len(),
+                decimals: 8,
+                balance: summary.balance.to_string(),
+                total_received: summary.received.to_string(),
+                total_sent: summary.sent.to_string(),
+            });
+            txids.extend(history);
+        }
+    }
+
+    Ok(XpubSummary {
+        address: xpub.to_string(),
+        balance: balance.to_string(),
+        total_received: received.to_string(),
+        total_sent: sent.to_string(),
+        utxo_count,
+        txs: txids.len(),
+        used_tokens: tokens.len(),
+        tokens,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(xpub: &str) -> Vec<u8> {
+        let mut data = bs58::decode(xpub).into_vec().unwrap();
+        data.truncate(78);
+        data
+    }
+
+    // BIP32 test vector 1, the steps below m/0H that CKDpub can take
+    #[test]
+    fn bip32_vector_1_public_derivation() {
+        let secp = Secp256k1::verification_only();
+        let steps = [
+            ("xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+             1, "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ"),
+            ("xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+             2, "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV"),
+            ("xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+             1_000_000_000, "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy"),
+        ];
+        for (parent, index, child) in steps.iter() {
+            let derived = ExtendedPubKey::from_payload(&payload(parent)).unwrap().derive(&secp, *index).unwrap();
+            let expected = payload(child);
+            assert_eq!(derived.chain_code[..], expected[13..45], "chain code of child {}", index);
+            assert_eq!(derived.public_key.serialize()[..], expected[45..78], "public key of child {}", index);
+        }
+    }
+
+    // RFC 4231 test case 1
+    #[test]
+    fn hmac_sha512_rfc_4231() {
+        assert_eq!(hex::encode(&hmac_sha512(&[0x0b; 20], b"Hi There")[..]),
+            "87aa7cdea5ef619d4ff0b4241a1d6cb02379f4e2ce4ec2787ad0b30545e17cde\
+             daa833b7d6b8a702038b274eaea3f4e4be9d914eeb61f1702e696c203a126854");
+    }
+}